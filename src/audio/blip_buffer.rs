@@ -0,0 +1,110 @@
+use std::f64::consts::PI;
+
+/// Number of fractional sample positions the step kernel is precomputed for.
+const PHASES: usize = 32;
+
+/// Width of the step kernel in output samples. Wider kernels cut off aliasing more sharply.
+const KERNEL_WIDTH: usize = 16;
+
+/// Fraction of the output Nyquist frequency kept by the kernel's low-pass filter.
+const CUTOFF: f64 = 0.9;
+
+/// Converts a waveform made of instantaneous steps at the input clock rate into band-limited
+/// samples at the output rate. Instead of point sampling the waveform, which aliases, each step
+/// adds a windowed-sinc impulse to a buffer of differences which is integrated on output. This is
+/// the technique used by Blargg's blip_buf.
+#[derive(Clone)]
+pub struct BlipBuffer {
+    /// Output samples per input clock.
+    ratio: f64,
+
+    /// Position of the current frame's start in output samples, relative to `deltas[0]`.
+    offset: f64,
+
+    /// Differences between consecutive output samples which haven't been read yet.
+    deltas: Vec<f32>,
+
+    /// Sum of all the differences read so far, which is the current output level.
+    integrator: f32,
+
+    /// The impulse for each phase, for `PHASES + 1` evenly spaced fractions from 0 to 1.
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(ratio: f64) -> Self {
+        BlipBuffer {
+            ratio,
+            offset: 0.0,
+            deltas: Vec::new(),
+            integrator: 0.0,
+            kernel: (0..=PHASES).map(|phase| impulse(phase as f64 / PHASES as f64)).collect(),
+        }
+    }
+
+    /// Set the number of output samples per input clock.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    /// Add a step of `delta` to the waveform, `time` input clocks after the start of the current
+    /// frame.
+    pub fn add_delta(&mut self, time: usize, delta: f32) {
+        let position = self.offset + time as f64 * self.ratio;
+        let index = position as usize;
+        let phase = ((position - index as f64) * PHASES as f64).round() as usize;
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (d, k) in self.deltas[index..].iter_mut().zip(&self.kernel[phase]) {
+            *d += delta * k;
+        }
+    }
+
+    /// End the current frame after the given number of input clocks. Steps added from now on are
+    /// timed from the end of this frame.
+    pub fn end_frame(&mut self, clocks: usize) {
+        self.offset += clocks as f64 * self.ratio;
+    }
+
+    /// Number of output samples which no later step can change, so they are ready to be read.
+    pub fn samples_available(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Append all the samples which are ready to `out`.
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_available();
+        if self.deltas.len() < count {
+            self.deltas.resize(count, 0.0);
+        }
+        for delta in self.deltas.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset -= count as f64;
+    }
+}
+
+/// A windowed-sinc impulse for a step `fraction` of the way between two output samples, scaled
+/// so the step it adds has exactly the right height.
+fn impulse(fraction: f64) -> [f32; KERNEL_WIDTH] {
+    let half_width = KERNEL_WIDTH as f64 / 2.0;
+    let mut taps = [0.0; KERNEL_WIDTH];
+    for (k, tap) in taps.iter_mut().enumerate() {
+        // Distance from the step, with the kernel centered in its width.
+        let x = k as f64 + 1.0 - half_width - fraction;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x * CUTOFF).sin() / (PI * x * CUTOFF) };
+        // Blackman window
+        let window = 0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos();
+        *tap = sinc * window.max(0.0);
+    }
+
+    let sum: f64 = taps.iter().sum();
+    let mut kernel = [0.0; KERNEL_WIDTH];
+    for (k, tap) in kernel.iter_mut().zip(&taps) {
+        *k = (tap / sum) as f32;
+    }
+    kernel
+}
@@ -1,33 +1,45 @@
-use log::warn;
-use super::{LENGTH_COUNTER_RATE_CYCLES, EnvelopeDirection};
+use super::{CLOCK_RATE, DUTY_NAMES};
+use super::length_counter::LengthCounter;
+use super::envelope::Envelope;
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
+
+/// The highest frequency value. The sweep disables the channel if it would go above this.
+const MAX_FREQUENCY: u16 = 2047;
 
 #[derive(Clone)]
 pub struct Channel1 {
-    // TODO(wcarlson): Sweep register
+    /// Number of sweep updates between frequency changes. Bits 4-6 of 0xFF10
+    sweep_period: u8,
 
-    /// Wave pattern duty. Bits 6-7 of 0xFF11
-    wave_pattern: u8,
+    /// True if the sweep decreases the frequency. Bit 3 of 0xFF10
+    sweep_negate: bool,
 
-    /// Length of sound data. Bits 0-5 of 0xFF11
-    length: u8,
+    /// How far the frequency is shifted to get the amount it changes by. Bits 0-2 of 0xFF10
+    sweep_shift: u8,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
+    /// True if the sweep is running. Set on restart if the period or shift is non-zero.
+    sweep_enabled: bool,
 
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// Counts down sweep updates until the next frequency change.
+    sweep_timer: u8,
 
-    /// Volume. Bits 4-7 of 0xFF12
-    volume: u8,
+    /// The frequency the sweep calculates from, copied from `frequency` on restart.
+    shadow_frequency: u16,
+
+    /// True if a sweep calculation has decreased the frequency since the last restart. Clearing
+    /// the negate bit after that disables the channel.
+    sweep_negate_used: bool,
+
+    /// Wave pattern duty. Bits 6-7 of 0xFF11
+    wave_pattern: u8,
 
-    /// Envelope direction. Bit 3 of 0xFF12.
-    envelope_direction: EnvelopeDirection,
+    /// Length counter. The length is bits 0-5 of 0xFF11, and bit 6 of 0xFF14 enables the counter
+    length: LengthCounter,
 
-    /// Number of envelope sweeps. Bits 0-2 of 0xFF12
-    envelope_sweeps: u8,
+    /// Volume envelope. Register 0xFF12
+    envelope: Envelope,
 
     /// Channel frequency. Lower bits are bits 0-7 of 0xFF13. Higher bits are 0-2 of 0xFF14
     /// Actual frequency is given by `(2048 - frequency) * 4`. http://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
@@ -36,15 +48,9 @@ pub struct Channel1 {
     /// True if we are going to restart sound. Bit 7 of 0xFF14
     restart: bool,
 
-    /// True if we should stop after the current sound length. Bit 6 of 0xFF14
-    stop_after_sound_length: bool,
-
     /// Track current cycles for audio output
     curr_cycles: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
-
     /// Track the wave pattern position
     curr_index: u8,
 
@@ -58,57 +64,81 @@ pub struct Channel1 {
 impl Channel1 {
     pub fn new() -> Channel1 {
         Channel1 {
-            wave_pattern: 0b10,
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
-            volume: 0b11111,
-            envelope_direction: EnvelopeDirection::Decrease,
-            envelope_sweeps: 0b11,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+            sweep_negate_used: false,
+            wave_pattern: 0,
+            length: LengthCounter::new(MAX_SOUND_LENGTH),
+            envelope: Envelope::new(),
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             curr_cycles: 0,
-            curr_length_counter_cycles: 0,
             curr_index: 0,
             curr_output: 0,
             enabled: false,
         }
     }
 
+    /// True if the channel is playing, as shown in NR52.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Clear the registers when the APU is powered off. On the DMG the length counter isn't
+    /// affected.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let mut length = self.length.clone();
+        *self = Channel1::new();
+        if keep_length {
+            length.enabled = false;
+            self.length = length;
+        }
+    }
+
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
             0x10 => {
-                warn!("unimplemented read from channel 1 audio sweep register");
-                0x80
+                0b1000_0000 // Unused bit
+                    | self.sweep_period << 4
+                    | (self.sweep_negate as u8) << 3
+                    | self.sweep_shift
             }
             0x11 => (self.wave_pattern << 6) | 0b0011_1111, // Low bits are write-only
-            0x12 => {
-                self.volume << 4
-                    | (self.envelope_direction as u8) << 3
-                    | self.envelope_sweeps
-            },
+            0x12 => self.envelope.read_reg(),
             0x13 => 0xFF, // This register is entirely write-only
             0x14 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length.enabled as u8) << 6
             }
             _ => panic!("Invalid read address for audio channel 1"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    pub fn write_reg(&mut self, addr: u8, val: u8, next_step_clocks_length: bool) {
         match addr {
-            0x10 => warn!("unimplemented write to channel 1 audio sweep register"),
+            0x10 => {
+                self.sweep_period = (val >> 4) & 0b111;
+                self.sweep_negate = (val >> 3) & 1 == 1;
+                self.sweep_shift = val & 0b111;
+                // Switching from subtraction to addition after the sweep has subtracted disables
+                // the channel.
+                if !self.sweep_negate && self.sweep_negate_used {
+                    self.enabled = false;
+                }
+            }
             0x11 => {
                 self.wave_pattern = val >> 6;
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
+                self.length.write_length((val & 0b0011_1111) as u16);
             },
             0x12 => {
-                self.envelope_sweeps = val & 0b0111;
-                self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
-                self.volume = val >> 4;
+                self.envelope.write_reg(val, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
             },
             0x13 => {
                 self.frequency &= !0 << 8;
@@ -116,23 +146,18 @@ impl Channel1 {
             },
             0x14 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                let enable_length = (val >> 6) & 1 == 1;
+                if self.length.write_control(enable_length, self.restart, next_step_clocks_length) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
-                    }
+                    self.enabled = self.envelope.dac_enabled();
+                    self.envelope.restart();
+                    self.restart_sweep();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 1"),
         }
@@ -151,9 +176,72 @@ impl Channel1 {
             self.curr_index = (self.curr_index + 1) % 8;
         }
 
-        self.update_length_counter(cycles);
+        self.curr_output * self.envelope.volume
+    }
+
+    /// Clock the length counter. Called at 256Hz by the frame sequencer.
+    pub fn step_length(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
+
+    fn restart_sweep(&mut self) {
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = self.sweep_reload_value();
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        self.sweep_negate_used = false;
+        // With a non-zero shift, the overflow check happens immediately.
+        if self.sweep_shift != 0 {
+            self.calculate_sweep_frequency();
+        }
+    }
 
-        self.curr_output * self.volume
+    /// A period of 0 is treated as 8.
+    fn sweep_reload_value(&self) -> u8 {
+        if self.sweep_period == 0 { 8 } else { self.sweep_period }
+    }
+
+    /// Clock the frequency sweep. Called at 128Hz by the frame sequencer.
+    pub fn step_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = self.sweep_reload_value();
+
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let new_frequency = self.calculate_sweep_frequency();
+            if new_frequency <= MAX_FREQUENCY && self.sweep_shift != 0 {
+                self.shadow_frequency = new_frequency;
+                self.frequency = new_frequency;
+                // The new frequency is checked for overflow again, but isn't used.
+                self.calculate_sweep_frequency();
+            }
+        }
+    }
+
+    /// Calculate the next sweep frequency from the shadow frequency, disabling the channel if it
+    /// overflows.
+    fn calculate_sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let new_frequency = if self.sweep_negate {
+            self.sweep_negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        if new_frequency > MAX_FREQUENCY {
+            self.enabled = false;
+        }
+        new_frequency
+    }
+
+    /// Clock the volume envelope. Called at 64Hz by the frame sequencer.
+    pub fn step_envelope(&mut self) {
+        self.envelope.step();
     }
 
     fn get_wave_duty(&self) -> u8 {
@@ -165,17 +253,20 @@ impl Channel1 {
             _ => panic!("Invalid channel 1 waveform value")
         }
     }
+}
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+impl std::fmt::Display for Channel1 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Each of the 8 steps of the duty cycle lasts `(2048 - frequency) * 4` cycles.
+        let hz = CLOCK_RATE / ((2048 - self.frequency as usize) * 32) as f64;
+        write!(f, "{}, {:.1}Hz, duty {}, envelope {}, length {}, ",
+            if self.enabled { "on" } else { "off" }, hz, DUTY_NAMES[self.wave_pattern as usize],
+            self.envelope, self.length)?;
+        if self.sweep_period == 0 {
+            write!(f, "sweep off")
+        } else {
+            write!(f, "sweep {} by >>{} every {}/128s",
+                if self.sweep_negate { "down" } else { "up" }, self.sweep_shift, self.sweep_period)
         }
     }
 }
@@ -1,30 +1,20 @@
-use super::{LENGTH_COUNTER_RATE_CYCLES, EnvelopeDirection};
+use super::{CLOCK_RATE, DUTY_NAMES};
+use super::length_counter::LengthCounter;
+use super::envelope::Envelope;
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
 
 #[derive(Clone)]
 pub struct Channel2 {
     /// Wave pattern. Bits 6-7 of 0xFF16
     wave_pattern: u8,
 
-    /// Length of sound data. Bits 0-5 of 0xFF16
-    length: u8,
+    /// Length counter. The length is bits 0-5 of 0xFF16, and bit 6 of 0xFF19 enables the counter
+    length: LengthCounter,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
-
-    /// Volume. Bits 4-7 of 0xFF17
-    volume: u8,
-
-    /// Envelope direction. Bit 3 of 0xFF17.
-    envelope_direction: EnvelopeDirection,
-
-    /// Number of envelope sweeps. Bits 0-2 of 0xFF17
-    envelope_sweeps: u8,
+    /// Volume envelope. Register 0xFF17
+    envelope: Envelope,
 
     /// Channel frequency. Lower bits are bits 0-7 of 0xFF18. Higher bits are 0-2 of 0xFF19
     /// Actual frequency is given by `(2048 - frequency) * 4`. http://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
@@ -33,15 +23,9 @@ pub struct Channel2 {
     /// True if we are going to restart sound. Bit 7 of 0xFF19
     restart: bool,
 
-    /// True if we should stop after the current sound length. Bit 6 of 0xFF19
-    stop_after_sound_length: bool,
-
     /// Track current cycles for audio output
     curr_cycles: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
-
     /// Track the wave pattern position
     curr_index: u8,
 
@@ -56,51 +40,57 @@ impl Channel2 {
     pub fn new() -> Channel2 {
         Channel2 {
             wave_pattern: 0,
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
-            volume: 0,
-            envelope_direction: EnvelopeDirection::Decrease,
-            envelope_sweeps: 0,
+            length: LengthCounter::new(MAX_SOUND_LENGTH),
+            envelope: Envelope::new(),
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             curr_cycles: 0,
-            curr_length_counter_cycles: 0,
             curr_index: 0,
             curr_output: 0,
             enabled: false,
         }
     }
 
+    /// True if the channel is playing, as shown in NR52.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Clear the registers when the APU is powered off. On the DMG the length counter isn't
+    /// affected.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let mut length = self.length.clone();
+        *self = Channel2::new();
+        if keep_length {
+            length.enabled = false;
+            self.length = length;
+        }
+    }
+
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
             0x16 => (self.wave_pattern << 6) | 0b0011_1111, // Low bits are write-only
-            0x17 => {
-                self.volume << 4
-                    | (self.envelope_direction as u8) << 3
-                    | self.envelope_sweeps
-            },
+            0x17 => self.envelope.read_reg(),
             0x18 => 0xFF, // This register is entirely write-only
             0x19 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length.enabled as u8) << 6
             },
             _ => panic!("Invalid read address for audio channel 2"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    pub fn write_reg(&mut self, addr: u8, val: u8, next_step_clocks_length: bool) {
         match addr {
             0x16 => {
                 self.wave_pattern = val >> 6;
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
+                self.length.write_length((val & 0b0011_1111) as u16);
             },
             0x17 => {
-                self.envelope_sweeps = val & 0b0111;
-                self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
-                self.volume = val >> 4;
+                self.envelope.write_reg(val, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
             },
             0x18 => {
                 self.frequency &= !0 << 8;
@@ -108,23 +98,17 @@ impl Channel2 {
             },
             0x19 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                let enable_length = (val >> 6) & 1 == 1;
+                if self.length.write_control(enable_length, self.restart, next_step_clocks_length) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
-                    }
+                    self.enabled = self.envelope.dac_enabled();
+                    self.envelope.restart();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 2"),
         }
@@ -143,9 +127,19 @@ impl Channel2 {
             self.curr_index = (self.curr_index + 1) % 8;
         }
 
-        self.update_length_counter(cycles);
+        self.curr_output * self.envelope.volume
+    }
+
+    /// Clock the length counter. Called at 256Hz by the frame sequencer.
+    pub fn step_length(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
 
-        self.curr_output * self.volume
+    /// Clock the volume envelope. Called at 64Hz by the frame sequencer.
+    pub fn step_envelope(&mut self) {
+        self.envelope.step();
     }
 
     fn get_wave_duty(&self) -> u8 {
@@ -157,17 +151,14 @@ impl Channel2 {
             _ => panic!("Invalid channel 2 waveform value")
         }
     }
+}
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
-        }
+impl std::fmt::Display for Channel2 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Each of the 8 steps of the duty cycle lasts `(2048 - frequency) * 4` cycles.
+        let hz = CLOCK_RATE / ((2048 - self.frequency as usize) * 32) as f64;
+        write!(f, "{}, {:.1}Hz, duty {}, envelope {}, length {}",
+            if self.enabled { "on" } else { "off" }, hz, DUTY_NAMES[self.wave_pattern as usize],
+            self.envelope, self.length)
     }
 }
@@ -1,21 +1,29 @@
-use super::LENGTH_COUNTER_RATE_CYCLES;
+use super::CLOCK_RATE;
+use super::length_counter::LengthCounter;
 
 /// Wave RAM can fit 32 4-bit samples
 const WAVE_RAM_LENGTH: usize = 16;
 
+/// Number of 4-bit samples in wave RAM
+const WAVE_SAMPLES: usize = WAVE_RAM_LENGTH * 2;
+
 /// Max length for sound data
 const MAX_SOUND_LENGTH: u16 = 256;
 
+/// Extra cycles before the first sample is read after a restart.
+const RESTART_DELAY_CYCLES: usize = 6;
+
+/// On the DMG, the CPU can only access wave RAM while the channel is playing if it does so within
+/// this many cycles of the channel reading a sample.
+const DMG_WAVE_ACCESS_CYCLES: usize = 2;
+
 #[derive(Clone)]
 pub struct Channel3 {
-    /// Sound Length. Register FF1B
-    length: u8,
+    /// Length counter. The length is register FF1B, and bit 6 of FF1E enables the counter
+    length: LengthCounter,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 256 - length.
-    length_counter: u16,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// True if the DAC is powered. Bit 7 of FF1A
+    dac_enabled: bool,
 
     /// Volume. Register FF1C
     pub volume: Volume,
@@ -27,70 +35,94 @@ pub struct Channel3 {
     /// True if we are going to restart sound.
     restart: bool,
 
-    /// True if we should stop after the current sound length
-    stop_after_sound_length: bool,
-
     /// Wave pattern RAM. Registers FF30-FF3F
     pub wave_ram: Box<[u8]>,
 
-    /// Track current cycles for audio output
-    curr_cycles: usize,
+    /// Cycles left until the next sample is read.
+    timer: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
+    /// Index of the sample being played (0-31). Each byte of wave RAM holds two samples, high
+    /// nibble first.
+    position: usize,
 
-    /// Track the current nibble index in wave ram
-    curr_index: usize,
+    /// The wave RAM byte holding the sample being played. This is only updated when the timer
+    /// reads the next sample, so right after a restart the old byte keeps playing.
+    sample_buffer: u8,
 
-    /// Track the current audio output value
-    curr_output: u8,
+    /// Cycles since the last sample was read from wave RAM.
+    cycles_since_read: usize,
 
     /// True if the channel is enabled
     enabled: bool,
+
+    /// True on the CGB, where the CPU can always access wave RAM and restarting doesn't corrupt it.
+    cgb_mode: bool,
 }
 
 impl Channel3 {
-    pub fn new() -> Channel3 {
+    pub fn new(cgb_mode: bool) -> Channel3 {
         Channel3 {
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
+            length: LengthCounter::new(MAX_SOUND_LENGTH),
+            dac_enabled: false,
             volume: Volume::Zero,
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             wave_ram: vec![0; WAVE_RAM_LENGTH].into_boxed_slice(),
-            curr_cycles: 0,
-            curr_length_counter_cycles: 0,
-            curr_index: 0,
-            curr_output: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            cycles_since_read: 0,
             enabled: false,
+            cgb_mode,
+        }
+    }
+
+    /// True if the channel is playing, as shown in NR52.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Clear the registers when the APU is powered off. Wave RAM is kept, and on the DMG the
+    /// length counter isn't affected.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let mut length = self.length.clone();
+        let wave_ram = self.wave_ram.clone();
+        *self = Channel3::new(self.cgb_mode);
+        self.wave_ram = wave_ram;
+        if keep_length {
+            length.enabled = false;
+            self.length = length;
         }
     }
 
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x1A => ((self.enabled as u8) << 7) | 0b0111_1111, // Lower 7 bits unused
+            0x1A => ((self.dac_enabled as u8) << 7) | 0b0111_1111, // Lower 7 bits unused
             0x1B => 0xFF, // This entire register is write-only
             0x1C => ((self.volume as u8) << 5) | 0b1001_1111, // All other bits unused
             0x1D => 0xFF, // This entire register is write-only
             0x1E => {
                 0b1011_1111 // These bits are unused or write-only
-                | (self.restart as u8) << 7
-                | (self.stop_after_sound_length as u8) << 6
-                | ((self.frequency >> 8) as u8) & 0b111
+                | (self.length.enabled as u8) << 6
+            },
+            0x30..=0x3F => match self.wave_ram_index(addr) {
+                Some(i) => self.wave_ram[i],
+                None => 0xFF,
             },
-            0x30..=0x3F => self.wave_ram[(addr - 0x30) as usize],
             _ => panic!("Invalid read address for audio channel 3"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    pub fn write_reg(&mut self, addr: u8, val: u8, next_step_clocks_length: bool) {
         match addr {
-            0x1A => self.enabled = (val >> 7) == 1,
+            0x1A => {
+                self.dac_enabled = (val >> 7) == 1;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
             0x1B => {
-                self.length = val;
-                self.length_counter = MAX_SOUND_LENGTH - self.length as u16;
+                self.length.write_length(val as u16);
             },
             0x1C => self.volume = Volume::from((val >> 5) & 0b11),
             0x1D => {
@@ -99,71 +131,96 @@ impl Channel3 {
             },
             0x1E => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                let enable_length = (val >> 6) & 1 == 1;
+                if self.length.write_control(enable_length, self.restart, next_step_clocks_length) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
+                    // Restarting on the DMG just as a sample is read corrupts wave RAM.
+                    if self.enabled && !self.cgb_mode && self.timer <= DMG_WAVE_ACCESS_CYCLES {
+                        self.corrupt_wave_ram();
                     }
+                    self.enabled = self.dac_enabled;
+                    self.position = 0;
+                    self.timer = self.period() + RESTART_DELAY_CYCLES;
+                }
+            },
+            0x30..=0x3F => {
+                if let Some(i) = self.wave_ram_index(addr) {
+                    self.wave_ram[i] = val;
                 }
-                self.length_counter_enabled = self.restart;
             },
-            0x30..=0x3F => self.wave_ram[(addr - 0x30) as usize] = val,
             _ => panic!("Invalid write address for audio channel 3"),
         }
     }
 
     pub fn step(&mut self, cycles: usize) -> u8 {
+        self.cycles_since_read += cycles;
         if !self.enabled {
             return 0;
         }
 
-        self.curr_cycles += cycles;
-        let freq = (2048 - self.frequency as usize) * 2;
-        if self.curr_cycles > freq && freq > 0 {
-            self.curr_cycles %= freq;
-            let mut b = self.wave_ram[self.curr_index / 2];
-            if self.curr_index % 2 == 0 {
-                b = (b >> 4) & 0b1111;
-            }
-            else {
-                b &= 0b1111;
-            }
-            self.curr_index = (self.curr_index + 1) % 32;
-            match self.volume {
-                Volume::Zero => self.curr_output = 0,
-                Volume::Full => self.curr_output = b,
-                Volume::Half => self.curr_output = b >> 1,
-                Volume::Quarter => self.curr_output = b >> 2,
-            }
+        let mut elapsed = cycles;
+        while self.timer <= elapsed {
+            elapsed -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % WAVE_SAMPLES;
+            self.sample_buffer = self.wave_ram[self.position / 2];
+            self.cycles_since_read = elapsed;
         }
+        self.timer -= elapsed;
 
-        self.update_length_counter(cycles);
+        let sample = if self.position % 2 == 0 { self.sample_buffer >> 4 } else { self.sample_buffer & 0b1111 };
+        // The volume is applied as the sample is output, so changing it takes effect immediately.
+        match self.volume {
+            Volume::Zero => 0,
+            Volume::Full => sample,
+            Volume::Half => sample >> 1,
+            Volume::Quarter => sample >> 2,
+        }
+    }
 
-        self.curr_output
+    /// Number of cycles between samples.
+    fn period(&self) -> usize {
+        (2048 - self.frequency as usize) * 2
     }
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
+    /// The index in wave RAM the CPU accesses at the given address. While the channel is playing,
+    /// every address accesses the byte being played instead. On the DMG that only works right
+    /// after the channel reads it, and otherwise there's no access.
+    fn wave_ram_index(&self, addr: u8) -> Option<usize> {
+        if !self.enabled {
+            Some((addr - 0x30) as usize)
+        } else if self.cgb_mode || self.cycles_since_read < DMG_WAVE_ACCESS_CYCLES {
+            Some(self.position / 2)
+        } else {
+            None
+        }
+    }
+
+    /// Copy the byte about to be read over the start of wave RAM, or the 4-byte block holding it
+    /// if it isn't in the first block.
+    fn corrupt_wave_ram(&mut self) {
+        let next_byte = ((self.position + 1) % WAVE_SAMPLES) / 2;
+        if next_byte < 4 {
+            self.wave_ram[0] = self.wave_ram[next_byte];
+        } else {
+            let block = next_byte & !0b11;
+            for i in 0..4 {
+                self.wave_ram[i] = self.wave_ram[block + i];
             }
         }
     }
+
+    /// Clock the length counter. Called at 256Hz by the frame sequencer.
+    pub fn step_length(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -185,3 +242,18 @@ impl std::convert::From<u8> for Volume {
         }
     }
 }
+
+impl std::fmt::Display for Channel3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let hz = CLOCK_RATE / (self.period() * WAVE_SAMPLES) as f64;
+        let volume = match self.volume {
+            Volume::Zero => "0%",
+            Volume::Full => "100%",
+            Volume::Half => "50%",
+            Volume::Quarter => "25%",
+        };
+        write!(f, "{}, {:.1}Hz, DAC {}, volume {}, length {}, sample {}, wave {}",
+            if self.enabled { "on" } else { "off" }, hz, if self.dac_enabled { "on" } else { "off" },
+            volume, self.length, self.position, hex::encode_upper(&self.wave_ram))
+    }
+}
@@ -1,27 +1,17 @@
-use super::{EnvelopeDirection, LENGTH_COUNTER_RATE_CYCLES};
+use super::CLOCK_RATE;
+use super::length_counter::LengthCounter;
+use super::envelope::Envelope;
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
 
 #[derive(Clone)]
 pub struct Channel4 {
-    /// Sound Length. Bits 0-5 of 0xFF20
-    length: u8,
+    /// Length counter. The length is bits 0-5 of 0xFF20, and bit 6 of 0xFF23 enables the counter
+    length: LengthCounter,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
-
-    /// Initial volume of envelope. Bits 4-7 of 0xFF21
-    volume: u8,
-
-    /// Envelope direction. Bit 3 of 0xFF21
-    envelope_direction: EnvelopeDirection,
-
-    /// Number of envelope sweeps. Bits 0-2 of 0xFF21
-    envelope_sweeps: u8,
+    /// Volume envelope. Register 0xFF21
+    envelope: Envelope,
 
     /// Shift clock frequency. Bits 4-7 of 0xFF22
     shift_clock_frequency: u8,
@@ -38,17 +28,8 @@ pub struct Channel4 {
     /// True if we are going to restart sound.
     restart: bool,
 
-    /// True if we should stop after the current sound length
-    stop_after_sound_length: bool,
-
-    /// Track the current step updating audio output
-    curr_index: usize,
-
-    /// Track current cycles for audio output
-    curr_cycles: usize,
-
-    /// Track the current audio output value
-    curr_output: u8,
+    /// Cycles left until the LFSR is next clocked
+    timer: usize,
 
     /// True if the channel is enabled
     enabled: bool,
@@ -57,33 +38,38 @@ pub struct Channel4 {
 impl Channel4 {
     pub fn new() -> Self {
         Self {
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
-            volume: 0,
-            envelope_direction: EnvelopeDirection::Decrease,
-            envelope_sweeps: 0,
+            length: LengthCounter::new(MAX_SOUND_LENGTH),
+            envelope: Envelope::new(),
             shift_clock_frequency: 0,
             counter_step: 0,
             linear_feedback_shift_register: 0b0111_1111_1111_1111,
             dividing_ratio: 0,
             restart: false,
-            stop_after_sound_length: false,
-            curr_index: 0,
-            curr_cycles: 0,
-            curr_output: 0,
+            timer: 0,
             enabled: false,
         }
     }
 
+    /// True if the channel is playing, as shown in NR52.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Clear the registers when the APU is powered off. On the DMG the length counter isn't
+    /// affected.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let mut length = self.length.clone();
+        *self = Channel4::new();
+        if keep_length {
+            length.enabled = false;
+            self.length = length;
+        }
+    }
+
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
             0x20 => 0xFF, // This entire register is write-only
-            0x21 => {
-                self.volume << 4
-                    | (self.envelope_direction as u8) << 3
-                    | self.envelope_sweeps
-            },
+            0x21 => self.envelope.read_reg(),
             0x22 => {
                 self.shift_clock_frequency << 4
                     | self.counter_step << 3
@@ -91,23 +77,22 @@ impl Channel4 {
             },
             0x23 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.restart as u8) << 7
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length.enabled as u8) << 6
             },
             _ => panic!("Invalid read address for audio channel 4"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    pub fn write_reg(&mut self, addr: u8, val: u8, next_step_clocks_length: bool) {
         match addr {
             0x20 => {
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
+                self.length.write_length((val & 0b0011_1111) as u16);
             },
             0x21 => {
-                self.envelope_sweeps = val & 0b0111;
-                self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
-                self.volume = val >> 4;
+                self.envelope.write_reg(val, self.enabled);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
             },
             0x22 => {
                 self.dividing_ratio = val & 0b0111;
@@ -116,22 +101,16 @@ impl Channel4 {
             },
             0x23 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
-
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = 64;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = 64
+                let enable_length = (val >> 6) & 1 == 1;
+                if self.length.write_control(enable_length, self.restart, next_step_clocks_length) {
+                    self.enabled = false;
                 }
                 if self.restart {
                     self.linear_feedback_shift_register = 0b0111_1111_1111_1111;
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = 64
-                    }
+                    self.timer = self.period().unwrap_or(0);
+                    self.enabled = self.envelope.dac_enabled();
+                    self.envelope.restart();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 4"),
         }
@@ -142,16 +121,40 @@ impl Channel4 {
             return 0;
         }
 
-        self.curr_index += 1;
-        let out_freq = self.get_divisor(self.dividing_ratio) << self.shift_clock_frequency as usize;
-        if self.curr_index >= out_freq {
-            self.curr_index %= out_freq;
-            self.curr_output = self.get_next_output();
+        if let Some(period) = self.period() {
+            let mut elapsed = cycles;
+            while self.timer <= elapsed {
+                elapsed -= self.timer;
+                self.timer = period;
+                self.clock_lfsr();
+            }
+            self.timer -= elapsed;
         }
 
-        self.update_length_counter(cycles);
+        // The output is the inverse of bit 0.
+        let output = (!self.linear_feedback_shift_register & 1) as u8;
+        output * self.envelope.volume
+    }
 
-        self.curr_output * self.volume
+    /// Number of cycles between LFSR clocks, or None if the shift is 14 or 15, which stops the
+    /// LFSR.
+    fn period(&self) -> Option<usize> {
+        if self.shift_clock_frequency >= 14 {
+            return None;
+        }
+        Some(self.get_divisor(self.dividing_ratio) << self.shift_clock_frequency)
+    }
+
+    /// Clock the length counter. Called at 256Hz by the frame sequencer.
+    pub fn step_length(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clock the volume envelope. Called at 64Hz by the frame sequencer.
+    pub fn step_envelope(&mut self) {
+        self.envelope.step();
     }
 
     fn get_divisor(&self, dividing_ratio: u8) -> usize {
@@ -168,30 +171,26 @@ impl Channel4 {
         }
     }
 
-    fn get_next_output(&mut self) -> u8 {
-        let bit_0 = self.linear_feedback_shift_register & 1;
-        let bit_1 = (self.linear_feedback_shift_register >> 1) & 1;
-        let new_bit = bit_0 ^ bit_1;
-        self.linear_feedback_shift_register = self.linear_feedback_shift_register >> 1;
-        self.linear_feedback_shift_register &= 0b0011_1111_1111_1111;
-        self.linear_feedback_shift_register |= new_bit << 14;
+    /// Shift the LFSR right, feeding bit 0 XOR bit 1 into bit 14, and also bit 6 in 7-bit mode.
+    fn clock_lfsr(&mut self) {
+        let lfsr = self.linear_feedback_shift_register;
+        let new_bit = (lfsr ^ (lfsr >> 1)) & 1;
+        let mut lfsr = (lfsr >> 1) & 0b0011_1111_1111_1111 | new_bit << 14;
         if self.counter_step == 1 {
-            self.linear_feedback_shift_register &= 0b0111_1111_1011_1111;
-            self.linear_feedback_shift_register |= new_bit << 6;
+            lfsr = lfsr & 0b0111_1111_1011_1111 | new_bit << 6;
         }
-        return (!self.linear_feedback_shift_register & 1) as u8
+        self.linear_feedback_shift_register = lfsr;
     }
+}
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_cycles += cycles;
-        if self.curr_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+impl std::fmt::Display for Channel4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}, ", if self.enabled { "on" } else { "off" })?;
+        match self.period() {
+            Some(period) => write!(f, "LFSR clocked at {:.1}Hz", CLOCK_RATE / period as f64)?,
+            None => write!(f, "LFSR stopped")?,
         }
+        write!(f, ", {} bits, envelope {}, length {}",
+            if self.counter_step == 1 { 7 } else { 15 }, self.envelope, self.length)
     }
 }
@@ -0,0 +1,125 @@
+use super::EnvelopeDirection;
+
+/// Highest volume an envelope can reach
+const MAX_VOLUME: u8 = 15;
+
+/// Volume envelope shared by channels 1, 2 and 4, controlled by their NRx2 register. Clocked at
+/// 64Hz by the frame sequencer.
+#[derive(Clone)]
+pub struct Envelope {
+    /// Volume on restart. Bits 4-7 of NRx2
+    initial_volume: u8,
+
+    /// Envelope direction. Bit 3 of NRx2
+    direction: EnvelopeDirection,
+
+    /// Number of envelope clocks between volume changes, or 0 to keep the volume constant. Bits
+    /// 0-2 of NRx2
+    period: u8,
+
+    /// Current volume (0-15)
+    pub volume: u8,
+
+    /// Counts down envelope clocks until the next volume change
+    timer: u8,
+
+    /// True until the volume reaches 0 or 15 after a restart
+    running: bool,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            direction: EnvelopeDirection::Decrease,
+            period: 0,
+            volume: 0,
+            timer: 0,
+            running: false,
+        }
+    }
+
+    pub fn read_reg(&self) -> u8 {
+        self.initial_volume << 4
+            | (self.direction as u8) << 3
+            | self.period
+    }
+
+    /// Write to NRx2. `channel_enabled` is needed for "zombie mode": writing to the register while
+    /// the channel is playing changes the current volume in odd ways, which some games rely on to
+    /// change the volume without restarting the sound.
+    pub fn write_reg(&mut self, val: u8, channel_enabled: bool) {
+        let direction = EnvelopeDirection::from((val >> 3) & 1);
+        if channel_enabled {
+            if self.period == 0 && self.running {
+                self.volume += 1;
+            } else if self.direction == EnvelopeDirection::Decrease {
+                self.volume += 2;
+            }
+            if direction != self.direction {
+                // The volume can be 16 or 17 here, which wraps around.
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0x0F;
+        }
+
+        self.initial_volume = val >> 4;
+        self.direction = direction;
+        self.period = val & 0b0111;
+    }
+
+    /// The channel's DAC is powered off when the top five bits of NRx2 are all zero, which
+    /// disables the channel.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.direction == EnvelopeDirection::Increase
+    }
+
+    pub fn restart(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.reload_value();
+        self.running = true;
+    }
+
+    /// A period of 0 is treated as 8 for the timer.
+    fn reload_value(&self) -> u8 {
+        if self.period == 0 { 8 } else { self.period }
+    }
+
+    /// Clock the envelope. Called at 64Hz by the frame sequencer.
+    pub fn step(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return;
+        }
+        self.timer = self.reload_value();
+
+        if !self.running {
+            return;
+        }
+        match self.direction {
+            EnvelopeDirection::Increase if self.volume < MAX_VOLUME => self.volume += 1,
+            EnvelopeDirection::Decrease if self.volume > 0 => self.volume -= 1,
+            _ => self.running = false,
+        }
+    }
+}
+
+impl std::fmt::Display for Envelope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let direction = match self.direction {
+            EnvelopeDirection::Increase => "up",
+            EnvelopeDirection::Decrease => "down",
+        };
+        if self.period == 0 {
+            write!(f, "volume {}/15, constant", self.volume)
+        } else {
+            write!(f, "volume {}/15, {} every {}/64s", self.volume, direction, self.period)
+        }
+    }
+}
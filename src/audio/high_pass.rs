@@ -0,0 +1,35 @@
+/// How much charge the capacitor on the DMG's audio output keeps each clock.
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+
+/// The CGB's capacitor discharges faster.
+const CGB_CHARGE_FACTOR: f64 = 0.998943;
+
+/// The capacitor on the audio output, which removes the DC offset the channels' DACs add.
+/// Without this, turning a channel on or off makes a loud pop.
+#[derive(Clone)]
+pub struct HighPass {
+    /// How much charge the capacitor keeps between output samples.
+    charge_factor: f32,
+
+    /// The charge on the capacitor.
+    capacitor: f32,
+}
+
+impl HighPass {
+    pub fn new(cgb_mode: bool, clocks_per_sample: f64) -> Self {
+        let mut high_pass = HighPass { charge_factor: 0.0, capacitor: 0.0 };
+        high_pass.set_clocks_per_sample(cgb_mode, clocks_per_sample);
+        high_pass
+    }
+
+    pub fn set_clocks_per_sample(&mut self, cgb_mode: bool, clocks_per_sample: f64) {
+        let factor = if cgb_mode { CGB_CHARGE_FACTOR } else { DMG_CHARGE_FACTOR };
+        self.charge_factor = factor.powf(clocks_per_sample) as f32;
+    }
+
+    pub fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
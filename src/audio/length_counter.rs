@@ -0,0 +1,69 @@
+/// Length counter shared by all channels, which disables its channel after a set time. Clocked at
+/// 256Hz by the frame sequencer.
+#[derive(Clone)]
+pub struct LengthCounter {
+    /// The longest length. 64 for channels 1, 2 and 4, and 256 for channel 3.
+    max: u16,
+
+    /// Number of clocks left before the channel is disabled. Set to `max - length` by NRx1.
+    counter: u16,
+
+    /// True if the counter is clocked. Bit 6 of NRx4
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter { max, counter: max, enabled: false }
+    }
+
+    /// Write the length from NRx1.
+    pub fn write_length(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    /// Handle a write to NRx4. `next_step_clocks_length` is true if the frame sequencer's next step
+    /// clocks the length counters. Returns true if the channel should be disabled.
+    ///
+    /// If the next step doesn't clock the length counters, enabling the counter clocks it once
+    /// straight away, and restarting with the counter enabled and at zero loads `max - 1`.
+    pub fn write_control(&mut self, enable: bool, restart: bool, next_step_clocks_length: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+
+        let mut expired = false;
+        if !was_enabled && enable && !next_step_clocks_length && self.counter > 0 {
+            self.counter -= 1;
+            // Restarting re-enables the channel below, so it keeps playing.
+            expired = self.counter == 0 && !restart;
+        }
+
+        if restart && self.counter == 0 {
+            self.counter = self.max;
+            if enable && !next_step_clocks_length {
+                self.counter -= 1;
+            }
+        }
+        expired
+    }
+
+    /// Clock the length counter. Returns true if the counter just ran out and the channel should
+    /// be disabled.
+    pub fn step(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+impl std::fmt::Display for LengthCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.enabled {
+            write!(f, "{}/256s left", self.counter)
+        } else {
+            write!(f, "off")
+        }
+    }
+}
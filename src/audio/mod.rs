@@ -1,22 +1,64 @@
+mod blip_buffer;
 mod channel1;
 mod channel2;
 mod channel3;
 mod channel4;
+mod envelope;
+mod high_pass;
+mod length_counter;
+mod scope;
 
+#[cfg(test)]
+mod test;
+
+use blip_buffer::BlipBuffer;
 use channel1::Channel1;
 use channel2::Channel2;
 use channel3::Channel3;
 use channel4::Channel4;
+use crate::model::Model;
+use high_pass::HighPass;
 use log::warn;
 
+pub use scope::{Scope, SCOPE_LENGTH};
+
 /// Number of samples in our audio buffer
 pub const SAMPLE_BUFFER_SIZE: usize = 1024;
 
-/// Number of cycles between samples to achieve at rate of 44100Hz
-const SAMPLE_RATE_CYCLES: usize = 95;
+/// Number of cycles per second the audio hardware runs at
+pub const CLOCK_RATE: f64 = 4_194_304.0;
+
+/// Output sample rate until the frontend sets one
+const DEFAULT_SAMPLE_RATE: f64 = 48000.0;
+
+/// The channels are stepped and mixed this many cycles at a time, which is the rate the hardware
+/// updates its output at.
+const MIX_STEP_CYCLES: usize = 4;
+
+/// Highest output of each channel's DAC input
+const MAX_CHANNEL_OUTPUT: f32 = 15.0;
+
+/// The duty cycle of each wave pattern of channels 1 and 2, for display.
+const DUTY_NAMES: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+
+/// A format for output samples.
+pub trait Sample: Copy {
+    /// Convert a sample in the range -1.0 to 1.0.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl Sample for i16 {
+    fn from_f32(value: f32) -> Self {
+        (value.max(-1.0).min(1.0) * i16::max_value() as f32) as i16
+    }
+}
+
+impl Sample for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
 
-/// Number of cycles between length counter updates to achieve 256Hz
-const LENGTH_COUNTER_RATE_CYCLES: usize = 16383;
 
 #[derive(Clone)]
 pub struct Audio {
@@ -34,18 +76,26 @@ pub struct Audio {
     right_volume: u8,
     /// Sound channel output selection. register 0xFF25
     selection: u8,
-    /// Sound enabled. Bit 7 at 0xFF26. Cannot access any sound registers besides 0xFF26 while disabled.
+    /// Sound enabled. Bit 7 at 0xFF26. Cannot access any sound registers besides 0xFF26 and wave
+    /// RAM while disabled, except for the length registers on the DMG.
     enabled: bool,
-    /// Sound 4 enabled. Bit 3 at 0xFF26. Read only
-    channel_4_enabled: bool,
-    /// Sound 3 enabled. Bit 2 at 0xFF26. Read only
-    channel_3_enabled: bool,
-    /// Sound 2 enabled. Bit 1 at 0xFF26. Read only
-    channel_2_enabled: bool,
-    /// Sound 1 enabled. Bit 0 at 0xFF26. Read only
-    channel_1_enabled: bool,
-    /// Cycle counter for outputting sound data to the audio queue at the proper rate.
-    queue_cycles: usize,
+    /// True on the CGB, where powering off also clears the length counters.
+    cgb_mode: bool,
+    /// Cycles since the output buffers last ended a frame.
+    frame_cycles: usize,
+    /// Band-limited output for each side.
+    left_buffer: BlipBuffer,
+    right_buffer: BlipBuffer,
+    /// The mixer output level for each side at the last step.
+    left_level: f32,
+    right_level: f32,
+    /// The DC-blocking capacitor on each side.
+    left_high_pass: HighPass,
+    right_high_pass: HighPass,
+    /// The next frame sequencer step (0-7). The frame sequencer is clocked at 512Hz by the timer,
+    /// and clocks the length counters on even steps, the sweep on steps 2 and 6, and the
+    /// envelopes on step 7.
+    frame_sequencer_step: u8,
 
     /// Debug features
     /// Whether or not to mute in a specified channel
@@ -53,31 +103,66 @@ pub struct Audio {
     pub channel_2_muted: bool,
     pub channel_3_muted: bool,
     pub channel_4_muted: bool,
+    /// The only channel (1-4) to play, if any
+    pub solo: Option<usize>,
+    /// Records each channel's output while the audio view is open
+    pub scope: Option<Scope>,
 }
 
 impl Audio {
-    pub fn new() -> Audio {
+    /// The APU at power on, before the boot ROM runs.
+    pub fn new(cgb_mode: bool) -> Audio {
         Audio {
             channel1: Channel1::new(),
             channel2: Channel2::new(),
-            channel3: Channel3::new(),
+            channel3: Channel3::new(cgb_mode),
             channel4: Channel4::new(),
-            queue_cycles: 0,
+            frame_cycles: 0,
+            left_buffer: BlipBuffer::new(DEFAULT_SAMPLE_RATE / CLOCK_RATE),
+            right_buffer: BlipBuffer::new(DEFAULT_SAMPLE_RATE / CLOCK_RATE),
+            left_level: 0.0,
+            right_level: 0.0,
+            left_high_pass: HighPass::new(cgb_mode, CLOCK_RATE / DEFAULT_SAMPLE_RATE),
+            right_high_pass: HighPass::new(cgb_mode, CLOCK_RATE / DEFAULT_SAMPLE_RATE),
+            frame_sequencer_step: 0,
             output_vin_left: false,
-            left_volume: 7,
+            left_volume: 0,
             output_vin_right: false,
-            right_volume: 7,
-            selection: 0xF3,
-            enabled: true,
-            channel_4_enabled: true,
-            channel_3_enabled: true,
-            channel_2_enabled: true,
-            channel_1_enabled: true,
+            right_volume: 0,
+            selection: 0,
+            enabled: false,
+            cgb_mode,
             channel_4_muted: false,
             channel_3_muted: false,
             channel_2_muted: false,
             channel_1_muted: false,
+            solo: None,
+            scope: None,
+        }
+    }
+
+    /// The APU when the given model's boot ROM jumps to the cartridge, with the registers the boot
+    /// ROM sets up for its startup sound.
+    pub fn post_boot(model: Model) -> Audio {
+        let mut audio = Audio::new(model == Model::Cgb);
+        audio.write_reg(0x26, 0x80);
+        audio.write_reg(0x11, 0x80);
+        audio.write_reg(0x12, 0xF3);
+        audio.write_reg(0x24, 0x77);
+        audio.write_reg(0x25, 0xF3);
+
+        // The last note of the startup sound leaves channel 1 enabled, so NR52 reads 0xF1. The
+        // SGB boot ROM doesn't play it.
+        if model != Model::Sgb {
+            audio.write_reg(0x13, 0xC1);
+            audio.write_reg(0x14, 0x87);
+            // The note has faded out by the time the boot ROM finishes: 15 volume steps, each 3
+            // envelope clocks long.
+            for _ in 0..15 * 3 {
+                audio.channel1.step_envelope();
+            }
         }
+        audio
     }
 
     pub fn read_reg(&self, addr: u8) -> u8 {
@@ -98,10 +183,10 @@ impl Audio {
             0x26 => {
                 (self.enabled as u8) << 7
                 | 0b0111_0000 // Unused bits
-                | (self.channel_4_enabled as u8) << 3
-                | (self.channel_3_enabled as u8) << 2
-                | (self.channel_2_enabled as u8) << 1
-                | (self.channel_1_enabled as u8)
+                | (self.channel4.is_enabled() as u8) << 3
+                | (self.channel3.is_enabled() as u8) << 2
+                | (self.channel2.is_enabled() as u8) << 1
+                | (self.channel1.is_enabled() as u8)
             }
             0x30..=0x3F => self.channel3.read_reg(addr),
             _ => panic!("Unimplemented audio register read"),
@@ -109,11 +194,26 @@ impl Audio {
     }
 
     pub fn write_reg(&mut self, addr: u8, val: u8) {
+        // Enabling a length counter behaves differently depending on the frame sequencer step.
+        let next_step_clocks_length = self.frame_sequencer_step % 2 == 0;
+        // While powered off only NR52 and wave RAM can be written, plus the length registers on the
+        // DMG. Writing NR11 or NR21 then only sets the length, not the duty.
+        let val = if self.enabled {
+            val
+        } else {
+            match addr {
+                0x26 | 0x30..=0x3F => val,
+                0x11 | 0x16 if !self.cgb_mode => val & 0b0011_1111,
+                0x1B | 0x20 if !self.cgb_mode => val,
+                _ => return,
+            }
+        };
+
         match addr {
-            0x10..=0x14 => self.channel1.write_reg(addr, val),
-            0x16..=0x19 => self.channel2.write_reg(addr, val),
-            0x1A..=0x1E => self.channel3.write_reg(addr, val),
-            0x20..=0x23 => self.channel4.write_reg(addr, val),
+            0x10..=0x14 => self.channel1.write_reg(addr, val, next_step_clocks_length),
+            0x16..=0x19 => self.channel2.write_reg(addr, val, next_step_clocks_length),
+            0x1A..=0x1E => self.channel3.write_reg(addr, val, next_step_clocks_length),
+            0x20..=0x23 => self.channel4.write_reg(addr, val, next_step_clocks_length),
             0x24 => {
                 self.output_vin_left = val & (1 << 7) != 0;
                 self.left_volume = (val >> 4) & 0b111;
@@ -128,90 +228,151 @@ impl Audio {
                 self.selection = val;
             }
             0x26 => {
-                // Should destroy all sound register contents upon disabled.
-                self.enabled = val & (1 << 7) != 0;
+                let enabled = val & (1 << 7) != 0;
+                if self.enabled && !enabled {
+                    self.power_off();
+                } else if !self.enabled && enabled {
+                    // The frame sequencer starts over from the first step.
+                    self.frame_sequencer_step = 0;
+                }
+                self.enabled = enabled;
             }
-            0x30..=0x3F => self.channel3.write_reg(addr, val),
+            0x30..=0x3F => self.channel3.write_reg(addr, val, next_step_clocks_length),
             _ => panic!("Unimplemented audio register write"),
         }
     }
 
-    pub fn step(&mut self, cycles: usize, audio_queue: &mut sdl2::audio::AudioQueue<u8>) {
-        let channel1_val = self.channel1.step(cycles);
-        let channel2_val = self.channel2.step(cycles);
-        let channel3_val = self.channel3.step(cycles);
-        let channel4_val = self.channel4.step(cycles);
-
-        let (mut left, mut right) = self.get_left_and_right_audio(channel1_val, channel2_val, channel3_val, channel4_val);
-        left *= self.left_volume;
-        right *= self.right_volume;
-
-        self.output_to_queue(left, right, audio_queue, cycles);
+    /// Set the output sample rate. The new rate also applies to the cycles stepped since the last
+    /// `drain_samples`, so change it right after draining to avoid a jump in the output.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.left_buffer.set_ratio(sample_rate / CLOCK_RATE);
+        self.right_buffer.set_ratio(sample_rate / CLOCK_RATE);
+        self.left_high_pass.set_clocks_per_sample(self.cgb_mode, CLOCK_RATE / sample_rate);
+        self.right_high_pass.set_clocks_per_sample(self.cgb_mode, CLOCK_RATE / sample_rate);
     }
 
-    fn get_left_and_right_audio(&self, channel1_val: u8, channel2_val: u8, channel3_val: u8, channel4_val: u8) -> (u8, u8) {
-        let mut left: u16 = 0;
-        let mut right: u16 = 0;
-        if !self.enabled {
-            return (0, 0);
-        }
+    pub fn step(&mut self, cycles: usize) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let step_cycles = remaining.min(MIX_STEP_CYCLES);
+            remaining -= step_cycles;
 
-        if self.channel_4_enabled && !self.channel_4_muted {
-            if self.selection & (1 << 7) != 0 {
-                left += channel4_val as u16;
+            let channel1_val = self.channel1.step(step_cycles);
+            let channel2_val = self.channel2.step(step_cycles);
+            let channel3_val = self.channel3.step(step_cycles);
+            let channel4_val = self.channel4.step(step_cycles);
+            self.frame_cycles += step_cycles;
+            if let Some(scope) = &mut self.scope {
+                scope.record(step_cycles, [channel1_val, channel2_val, channel3_val, channel4_val]);
             }
-        }
-        if self.channel_3_enabled && !self.channel_3_muted {
-            if self.selection & (1 << 6) != 0 {
-                left += channel3_val as u16;
+
+            let (left, right) = self.mix(channel1_val, channel2_val, channel3_val, channel4_val);
+            if left != self.left_level {
+                self.left_buffer.add_delta(self.frame_cycles, left - self.left_level);
+                self.left_level = left;
             }
-        }
-        if self.channel_2_enabled && !self.channel_2_muted {
-            if self.selection & (1 << 5) != 0 {
-                left += channel2_val as u16;
+            if right != self.right_level {
+                self.right_buffer.add_delta(self.frame_cycles, right - self.right_level);
+                self.right_level = right;
             }
         }
-        if self.channel_1_enabled && !self.channel_1_muted {
-            if self.selection & (1 << 4) != 0 {
-                left += channel1_val as u16;
-            }
+    }
+
+    /// Append all the samples generated since the last call to `out`, as interleaved left and
+    /// right samples.
+    pub fn drain_samples<S: Sample>(&mut self, out: &mut Vec<S>) {
+        self.left_buffer.end_frame(self.frame_cycles);
+        self.right_buffer.end_frame(self.frame_cycles);
+        self.frame_cycles = 0;
+
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        self.left_buffer.read_samples(&mut left);
+        self.right_buffer.read_samples(&mut right);
+        for (&l, &r) in left.iter().zip(&right) {
+            out.push(S::from_f32(self.left_high_pass.filter(l)));
+            out.push(S::from_f32(self.right_high_pass.filter(r)));
         }
+    }
 
-        if self.channel_4_enabled && !self.channel_4_muted {
-            if self.selection & (1 << 3) != 0 {
-                right += channel4_val as u16;
-            }
+    /// Mix the channel outputs into a level from 0.0 to 1.0 for each side, using the channel
+    /// selection and volume in NR50 and NR51.
+    fn mix(&self, channel1_val: u8, channel2_val: u8, channel3_val: u8, channel4_val: u8) -> (f32, f32) {
+        if !self.enabled {
+            return (0.0, 0.0);
         }
-        if self.channel_3_enabled && !self.channel_3_muted {
-            if self.selection & (1 << 2) != 0 {
-                right += channel3_val as u16;
+
+        let values = [channel1_val, channel2_val, channel3_val, channel4_val];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, &value) in values.iter().enumerate() {
+            if !self.channel_audible(i + 1) {
+                continue;
             }
-        }
-        if self.channel_2_enabled && !self.channel_2_muted {
-            if self.selection & (1 << 1) != 0 {
-                right += channel2_val as u16;
+            // Bits 4-7 of NR51 send channels 1-4 to the left, and bits 0-3 to the right.
+            if self.selection & (1 << (i + 4)) != 0 {
+                left += value as f32;
             }
-        }
-        if self.channel_1_enabled && !self.channel_1_muted {
-            if self.selection & 1 != 0 {
-                right += channel1_val as u16;
+            if self.selection & (1 << i) != 0 {
+                right += value as f32;
             }
         }
 
-        ((left / 4) as u8, (right / 4) as u8)
+        let scale = |sum: f32, volume: u8| sum / (4.0 * MAX_CHANNEL_OUTPUT) * (volume + 1) as f32 / 8.0;
+        (scale(left, self.left_volume), scale(right, self.right_volume))
+    }
+
+    /// True if a channel (1-4) isn't muted or excluded by a solo.
+    pub fn channel_audible(&self, channel: usize) -> bool {
+        let muted = [self.channel_1_muted, self.channel_2_muted, self.channel_3_muted, self.channel_4_muted];
+        !muted[channel - 1] && self.solo.map_or(true, |solo| solo == channel)
+    }
+
+    /// Solo a channel (1-4), or stop soloing it if it's already soloed.
+    pub fn toggle_solo(&mut self, channel: usize) {
+        self.solo = if self.solo == Some(channel) { None } else { Some(channel) };
     }
 
-    fn output_to_queue(&mut self, left: u8, right: u8, queue: &mut sdl2::audio::AudioQueue<u8>, cycles: usize) {
-        self.queue_cycles += cycles;
-        if self.queue_cycles >= SAMPLE_RATE_CYCLES {
-            self.queue_cycles %= SAMPLE_RATE_CYCLES;
-            // Need to verify that this is the right way to do left and right audio
-            queue.queue(&[left, right]);
+    /// Clear all the sound registers except wave RAM.
+    fn power_off(&mut self) {
+        let keep_length = !self.cgb_mode;
+        self.channel1.power_off(keep_length);
+        self.channel2.power_off(keep_length);
+        self.channel3.power_off(keep_length);
+        self.channel4.power_off(keep_length);
+        self.output_vin_left = false;
+        self.left_volume = 0;
+        self.output_vin_right = false;
+        self.right_volume = 0;
+        self.selection = 0;
+    }
+
+    /// Run the next frame sequencer step. Called by the CPU each time the timer's divider clocks
+    /// the frame sequencer.
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let step = self.frame_sequencer_step;
+        if step % 2 == 0 {
+            self.channel1.step_length();
+            self.channel2.step_length();
+            self.channel3.step_length();
+            self.channel4.step_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.step_sweep();
+        }
+        if step == 7 {
+            self.channel1.step_envelope();
+            self.channel2.step_envelope();
+            self.channel4.step_envelope();
         }
+        self.frame_sequencer_step = (step + 1) % 8;
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum EnvelopeDirection {
     Decrease = 0,
     Increase = 1,
@@ -0,0 +1,48 @@
+use std::collections::VecDeque;
+
+/// Cycles between the samples recorded for each channel.
+const SAMPLE_CYCLES: usize = 32;
+
+/// Number of samples kept for each channel, which covers about 8ms.
+pub const SCOPE_LENGTH: usize = 1024;
+
+/// Records the recent output of each channel before mixing, for drawing oscilloscopes.
+#[derive(Clone)]
+pub struct Scope {
+    /// The most recent outputs (0-15) of each channel, oldest first.
+    samples: [VecDeque<u8>; 4],
+
+    /// Cycles since the last sample was recorded.
+    cycles: usize,
+}
+
+impl Scope {
+    pub fn new() -> Self {
+        Scope {
+            samples: [
+                VecDeque::from(vec![0; SCOPE_LENGTH]),
+                VecDeque::from(vec![0; SCOPE_LENGTH]),
+                VecDeque::from(vec![0; SCOPE_LENGTH]),
+                VecDeque::from(vec![0; SCOPE_LENGTH]),
+            ],
+            cycles: 0,
+        }
+    }
+
+    /// Record the channel outputs after `cycles` more cycles.
+    pub fn record(&mut self, cycles: usize, values: [u8; 4]) {
+        self.cycles += cycles;
+        while self.cycles >= SAMPLE_CYCLES {
+            self.cycles -= SAMPLE_CYCLES;
+            for (samples, &value) in self.samples.iter_mut().zip(&values) {
+                samples.pop_front();
+                samples.push_back(value);
+            }
+        }
+    }
+
+    /// The recorded outputs of a channel (0-3), oldest first.
+    pub fn samples(&self, channel: usize) -> &VecDeque<u8> {
+        &self.samples[channel]
+    }
+}
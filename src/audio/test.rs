@@ -0,0 +1,443 @@
+use super::*;
+use super::blip_buffer::BlipBuffer;
+use super::envelope::Envelope;
+
+/// The bits of each register from 0xFF10 to 0xFF2F which always read as 1, as listed by Blargg's
+/// `dmg_sound` register test.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // Unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // Unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // Unused
+];
+
+/// The registers the audio unit handles, as routed by the CPU.
+fn is_audio_reg(addr: u8) -> bool {
+    match addr {
+        0x10..=0x14 | 0x16..=0x19 | 0x1A..=0x1E | 0x20..=0x26 => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_read_masks() {
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    for (i, &mask) in READ_MASKS.iter().enumerate() {
+        let addr = 0x10 + i as u8;
+        // Writing NR52 would power off, and writing NRx4 would restart the channel.
+        if !is_audio_reg(addr) || addr == 0x26 {
+            continue;
+        }
+        audio.write_reg(addr, 0);
+        assert_eq!(audio.read_reg(addr), mask, "after writing 0x00 to FF{:02X}", addr);
+        audio.write_reg(addr, 0xFF);
+        assert_eq!(audio.read_reg(addr), 0xFF, "after writing 0xFF to FF{:02X}", addr);
+    }
+}
+
+#[test]
+fn test_power_off() {
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x12, 0xF0);
+    audio.write_reg(0x14, 0x80);
+    audio.write_reg(0x30, 0x12);
+    assert_eq!(audio.read_reg(0x26), 0xF1);
+
+    audio.write_reg(0x26, 0x00);
+    assert_eq!(audio.read_reg(0x26), 0x70);
+    for addr in (0x10..=0x25).filter(|&addr| is_audio_reg(addr)) {
+        assert_eq!(audio.read_reg(addr), READ_MASKS[(addr - 0x10) as usize], "FF{:02X}", addr);
+    }
+    assert_eq!(audio.read_reg(0x30), 0x12);
+
+    // Writes are ignored while powered off.
+    audio.write_reg(0x12, 0xF0);
+    assert_eq!(audio.read_reg(0x12), 0x00);
+}
+
+#[test]
+fn test_band_limited_step() {
+    let sample_rate = 48000.0;
+    let mut buffer = BlipBuffer::new(sample_rate / CLOCK_RATE);
+    buffer.add_delta(1000, 1.0);
+    buffer.end_frame(CLOCK_RATE as usize);
+
+    let mut samples = Vec::new();
+    buffer.read_samples(&mut samples);
+    assert_eq!(samples.len(), 48000);
+    assert_eq!(samples[0], 0.0);
+    assert!((samples[samples.len() - 1] - 1.0).abs() < 1e-5);
+    // The step is smoothed over a few samples instead of jumping straight to 1.
+    assert!(samples.iter().any(|&s| s > 0.1 && s < 0.9));
+}
+
+#[test]
+fn test_wave_restart() {
+    let mut audio = Audio::new(true);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x30, 0x12);
+    audio.write_reg(0x1A, 0x80);
+    audio.write_reg(0x1C, 0x20); // Full volume
+    audio.write_reg(0x1D, 0x00);
+    audio.write_reg(0x1E, 0x80); // Restart with 4096 cycles per sample
+
+    // The old sample buffer plays until the first sample is read after a short delay, and that is
+    // sample 1, not 0.
+    assert_eq!(audio.channel3.step(4096), 0x0);
+    assert_eq!(audio.channel3.step(8), 0x2);
+
+    // While playing on the CGB, every wave RAM address accesses the byte being played.
+    assert_eq!(audio.read_reg(0x3F), 0x12);
+    audio.write_reg(0x3A, 0xAB);
+    assert_eq!(audio.read_reg(0x30), 0xAB);
+}
+
+/// Fill wave RAM with 0xA0-0xAF and restart channel 3 with 4096 cycles per sample.
+fn start_wave(cgb_mode: bool) -> Audio {
+    let mut audio = Audio::new(cgb_mode);
+    audio.write_reg(0x26, 0x80);
+    for i in 0..0x10 {
+        audio.write_reg(0x30 + i, 0xA0 + i);
+    }
+    audio.write_reg(0x1A, 0x80);
+    audio.write_reg(0x1C, 0x20);
+    audio.write_reg(0x1D, 0x00);
+    audio.write_reg(0x1E, 0x80);
+    audio
+}
+
+#[test]
+fn test_wave_access_dmg() {
+    let mut audio = start_wave(false);
+
+    // Right after the channel reads a sample, every address accesses the byte being played.
+    audio.channel3.step(4096 + 6);
+    assert_eq!(audio.read_reg(0x3F), 0xA0);
+    audio.write_reg(0x35, 0x55);
+    assert_eq!(audio.channel3.wave_ram[0], 0x55);
+
+    // A couple of cycles later, reads return 0xFF and writes are ignored.
+    audio.channel3.step(2);
+    assert_eq!(audio.read_reg(0x30), 0xFF);
+    audio.write_reg(0x30, 0x00);
+    assert_eq!(audio.channel3.wave_ram[0], 0x55);
+}
+
+#[test]
+fn test_wave_restart_corruption_dmg() {
+    // Restarting as sample 2 (in byte 1) is about to be read copies byte 1 over byte 0.
+    let mut audio = start_wave(false);
+    audio.channel3.step(4096 + 6 + 4094);
+    audio.write_reg(0x1E, 0x80);
+    assert_eq!(&audio.channel3.wave_ram[..4], &[0xA1, 0xA1, 0xA2, 0xA3]);
+
+    // Past the first 4 bytes, the whole 4-byte block holding the next byte is copied.
+    let mut audio = start_wave(false);
+    audio.channel3.step(4096 + 6 + 8 * 4096 + 4094);
+    audio.write_reg(0x1E, 0x80);
+    assert_eq!(&audio.channel3.wave_ram[..8], &[0xA4, 0xA5, 0xA6, 0xA7, 0xA4, 0xA5, 0xA6, 0xA7]);
+
+    // Restarting at any other time leaves wave RAM alone, as does restarting on the CGB.
+    let mut audio = start_wave(false);
+    audio.channel3.step(4096 + 6 + 2048);
+    audio.write_reg(0x1E, 0x80);
+    assert_eq!(audio.channel3.wave_ram[0], 0xA0);
+    let mut audio = start_wave(true);
+    audio.channel3.step(4096 + 6 + 4094);
+    audio.write_reg(0x1E, 0x80);
+    assert_eq!(audio.channel3.wave_ram[0], 0xA0);
+}
+
+#[test]
+fn test_envelope_zombie_mode_overflow() {
+    // Full volume, counting down.
+    let mut envelope = Envelope::new();
+    envelope.write_reg(0xF1, false);
+    envelope.restart();
+
+    // Switching the direction while playing adds 2 and then inverts the volume, which wraps.
+    envelope.write_reg(0xF9, true);
+    assert_eq!(envelope.volume, 15);
+    envelope.write_reg(0xF1, true);
+    assert_eq!(envelope.volume, 1);
+}
+
+/// Restart channel 4 at full volume with the given NR43 value, and return its output bits each
+/// time the LFSR is clocked.
+fn noise_bits(nr43: u8, period: usize, count: usize) -> Vec<u8> {
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x21, 0xF0);
+    audio.write_reg(0x22, nr43);
+    audio.write_reg(0x23, 0x80);
+    (0..count).map(|_| audio.channel4.step(period) / 15).collect()
+}
+
+fn parse_bits(bits: &str) -> Vec<u8> {
+    bits.bytes().map(|b| b - b'0').collect()
+}
+
+#[test]
+fn test_noise_lfsr() {
+    // The first outputs of the 15-bit and 7-bit LFSRs after a restart.
+    let wide = parse_bits("00000000000000111111111111110111");
+    let narrow = parse_bits("00000011111101111100111101011100");
+
+    // Divisor code 0 is 8 cycles and code 1 is 16, shifted left by the clock shift.
+    assert_eq!(noise_bits(0x00, 8, 32), wide);
+    assert_eq!(noise_bits(0x08, 8, 32), narrow);
+    assert_eq!(noise_bits(0x21, 16 << 2, 32), wide);
+
+    // The sequences repeat after 2^15 - 1 and 2^7 - 1 clocks.
+    let wide = noise_bits(0x00, 8, 32767 + 32);
+    assert_eq!(wide[..32], wide[32767..]);
+    let narrow = noise_bits(0x08, 8, 127 + 32);
+    assert_eq!(narrow[..32], narrow[127..]);
+
+    // Shifts of 14 and 15 stop the LFSR.
+    assert!(noise_bits(0xE0, 8 << 14, 32).iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_solo() {
+    let mut audio = Audio::post_boot(Model::Dmg);
+    audio.channel_2_muted = true;
+    audio.toggle_solo(3);
+    assert_eq!(audio.solo, Some(3));
+    assert!(audio.channel_audible(3));
+    assert!(!audio.channel_audible(1));
+    // NR51 is 0xF3 after the boot ROM, which sends channel 3 only to the left.
+    let (left, right) = audio.mix(0, 0, 15, 0);
+    assert!(left > 0.0);
+    assert_eq!(right, 0.0);
+    // Soloing channel 1 silences it.
+    audio.toggle_solo(1);
+    assert_eq!(audio.mix(0, 0, 15, 0), (0.0, 0.0));
+    audio.toggle_solo(1);
+    assert_eq!(audio.solo, None);
+    assert!(audio.channel_audible(4));
+    assert!(!audio.channel_audible(2));
+}
+
+/// Start channel 1 at full volume with the given sweep register and frequency.
+fn start_sweep(nr10: u8, frequency: u16) -> Audio {
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x10, nr10);
+    audio.write_reg(0x11, 0x80);
+    audio.write_reg(0x12, 0xF0);
+    audio.write_reg(0x13, frequency as u8);
+    audio.write_reg(0x14, 0x80 | (frequency >> 8) as u8);
+    audio
+}
+
+/// The frequency of channel 1 in Hz, as the debugger shows it.
+fn channel1_hz(audio: &Audio) -> String {
+    audio.channel1.to_string().split(", ").nth(1).unwrap().to_string()
+}
+
+#[test]
+fn test_sweep_up() {
+    // Each sweep adds a quarter of the frequency, and the result is written back to NR13/NR14.
+    let mut audio = start_sweep(0x12, 1024);
+    assert_eq!(channel1_hz(&audio), "128.0Hz");
+    let mut hz = vec![];
+    for _ in 0..3 {
+        audio.channel1.step_sweep();
+        hz.push(channel1_hz(&audio));
+    }
+    assert_eq!(hz, ["170.7Hz", "292.6Hz", "2730.7Hz"]);
+
+    // The check after writing back 2000 found that the next one, 2500, would overflow.
+    assert_eq!(audio.read_reg(0x26) & 1, 0);
+}
+
+#[test]
+fn test_sweep_overflow_on_restart() {
+    // 1500 + 750 is past 2047, which disables the channel as soon as it is restarted.
+    let audio = start_sweep(0x11, 1500);
+    assert_eq!(audio.read_reg(0x26) & 1, 0);
+
+    // Without a shift there's no calculation on restart.
+    let audio = start_sweep(0x10, 1500);
+    assert_eq!(audio.read_reg(0x26) & 1, 1);
+}
+
+#[test]
+fn test_sweep_down() {
+    let mut audio = start_sweep(0x1A, 1024);
+    audio.channel1.step_sweep();
+    assert_eq!(channel1_hz(&audio), "102.4Hz");
+    assert_eq!(audio.read_reg(0x26) & 1, 1);
+}
+
+#[test]
+fn test_sweep_clear_negate() {
+    // Clearing the negate bit after a calculation has subtracted disables the channel.
+    let mut audio = start_sweep(0x1A, 1024);
+    assert_eq!(audio.read_reg(0x26) & 1, 1);
+    audio.write_reg(0x10, 0x12);
+    assert_eq!(audio.read_reg(0x26) & 1, 0);
+
+    // It doesn't if no calculation has happened since the restart.
+    let mut audio = start_sweep(0x08, 1024);
+    audio.write_reg(0x10, 0x00);
+    assert_eq!(audio.read_reg(0x26) & 1, 1);
+
+    // Restarting forgets the subtraction.
+    let mut audio = start_sweep(0x1A, 1024);
+    audio.write_reg(0x10, 0x08);
+    audio.write_reg(0x14, 0x84);
+    audio.write_reg(0x10, 0x00);
+    assert_eq!(audio.read_reg(0x26) & 1, 1);
+}
+
+#[test]
+fn test_sweep_period_zero() {
+    // A period of 0 never changes the frequency.
+    let mut audio = start_sweep(0x02, 1024);
+    for _ in 0..16 {
+        audio.channel1.step_sweep();
+    }
+    assert_eq!(channel1_hz(&audio), "128.0Hz");
+
+    // But the timer is reloaded with 8, so changing the period only takes effect after 8 steps.
+    let mut audio = start_sweep(0x02, 1024);
+    audio.write_reg(0x10, 0x12);
+    for _ in 0..7 {
+        audio.channel1.step_sweep();
+    }
+    assert_eq!(channel1_hz(&audio), "128.0Hz");
+    audio.channel1.step_sweep();
+    assert_eq!(channel1_hz(&audio), "170.7Hz");
+    audio.channel1.step_sweep();
+    assert_eq!(channel1_hz(&audio), "292.6Hz");
+}
+
+/// Start channel 2 with the given length and NR24 value. Its envelope turns the volume down every
+/// envelope clock.
+fn start_channel2(length: u8, nr24: u8) -> Audio {
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x16, length);
+    audio.write_reg(0x17, 0xF1);
+    audio.write_reg(0x19, nr24);
+    audio
+}
+
+/// Clock the frame sequencer until the channels in `mask` are disabled, and return how many
+/// steps that took.
+fn steps_until_disabled(audio: &mut Audio, mask: u8) -> usize {
+    let mut steps = 0;
+    while audio.read_reg(0x26) & mask != 0 {
+        audio.step_frame_sequencer();
+        steps += 1;
+        assert!(steps < 1000);
+    }
+    steps
+}
+
+#[test]
+fn test_frame_sequencer_length() {
+    // The length counters are clocked on even steps, so 2 clocks take 3 steps from step 0.
+    let mut audio = start_channel2(62, 0xC0);
+    let mut enabled = vec![];
+    for _ in 0..3 {
+        audio.step_frame_sequencer();
+        enabled.push(audio.read_reg(0x26) & 0b10 != 0);
+    }
+    assert_eq!(enabled, [true, true, false]);
+}
+
+#[test]
+fn test_frame_sequencer_sweep() {
+    let mut audio = start_sweep(0x12, 1024);
+    let mut hz = vec![];
+    for _ in 0..8 {
+        audio.step_frame_sequencer();
+        hz.push(channel1_hz(&audio));
+    }
+    assert_eq!(hz, [
+        "128.0Hz", "128.0Hz", "170.7Hz", "170.7Hz", "170.7Hz", "170.7Hz", "292.6Hz", "292.6Hz",
+    ]);
+}
+
+#[test]
+fn test_frame_sequencer_envelope() {
+    let mut audio = start_channel2(0, 0x80);
+    let mut full_volume = vec![];
+    for _ in 0..8 {
+        audio.step_frame_sequencer();
+        full_volume.push(audio.channel2.to_string().contains("volume 15/15"));
+    }
+    assert_eq!(full_volume, [true, true, true, true, true, true, true, false]);
+}
+
+#[test]
+fn test_length_enable_extra_clock() {
+    // Enabling the length counter when the next step doesn't clock it clocks it straight away.
+    let mut audio = start_channel2(63, 0x80);
+    audio.step_frame_sequencer();
+    audio.write_reg(0x19, 0x40);
+    assert_eq!(audio.read_reg(0x26) & 0b10, 0);
+
+    // When the next step clocks it, there's no extra clock.
+    let mut audio = start_channel2(63, 0x80);
+    audio.write_reg(0x19, 0x40);
+    assert_eq!(audio.read_reg(0x26) & 0b10, 0b10);
+    assert_eq!(steps_until_disabled(&mut audio, 0b10), 1);
+
+    // Enabling it again while it's already enabled doesn't clock it.
+    let mut audio = start_channel2(62, 0xC0);
+    audio.step_frame_sequencer();
+    audio.write_reg(0x19, 0x40);
+    assert_eq!(audio.read_reg(0x26) & 0b10, 0b10);
+}
+
+#[test]
+fn test_restart_with_zero_length() {
+    // Restarting after the length ran out reloads the full length, 64 clocks from step 0.
+    let mut audio = start_channel2(63, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b10), 1);
+    audio.step_frame_sequencer();
+    assert_eq!(audio.frame_sequencer_step, 2);
+    audio.write_reg(0x19, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b10), 64 * 2 - 1);
+
+    // When the next step doesn't clock the length, it is reloaded with 63 instead.
+    let mut audio = start_channel2(63, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b10), 1);
+    audio.write_reg(0x19, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b10), 63 * 2);
+
+    // And channel 3 with 255.
+    let mut audio = Audio::new(false);
+    audio.write_reg(0x26, 0x80);
+    audio.write_reg(0x1A, 0x80);
+    audio.write_reg(0x1B, 0xFF);
+    audio.write_reg(0x1E, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b100), 1);
+    audio.write_reg(0x1E, 0xC0);
+    assert_eq!(steps_until_disabled(&mut audio, 0b100), 255 * 2);
+}
+
+#[test]
+fn test_post_boot() {
+    // Channel 1 is still enabled after the startup sound, but silent.
+    let mut audio = Audio::post_boot(Model::Dmg);
+    assert_eq!(audio.read_reg(0x26), 0xF1);
+    assert_eq!(audio.read_reg(0x11), 0xBF);
+    assert_eq!(audio.read_reg(0x12), 0xF3);
+    assert_eq!(audio.read_reg(0x14), 0xBF);
+    assert_eq!(audio.read_reg(0x24), 0x77);
+    assert_eq!(audio.read_reg(0x25), 0xF3);
+    assert_eq!(audio.channel1.step(0x10000), 0);
+    assert_eq!(Audio::post_boot(Model::Cgb).read_reg(0x26), 0xF1);
+
+    // The SGB boot ROM makes no sound.
+    assert_eq!(Audio::post_boot(Model::Sgb).read_reg(0x26), 0xF0);
+}
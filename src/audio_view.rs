@@ -0,0 +1,119 @@
+use crate::audio::{Audio, SCOPE_LENGTH};
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+
+/// Width of the window, which is also the number of scope samples shown.
+const WIDTH: u32 = 512;
+
+/// Height of each channel's oscilloscope and of the wave RAM graph.
+const ROW_HEIGHT: u32 = 80;
+
+/// Pixels per step of a 4-bit sample.
+const LEVEL_HEIGHT: i32 = 4;
+
+/// Width of the strip left of each oscilloscope which shows whether the channel is playing.
+const STATUS_WIDTH: u32 = 8;
+
+/// The color each channel is drawn in.
+const CHANNEL_COLORS: [Color; 4] = [
+    Color { r: 0xFF, g: 0x60, b: 0x60, a: 0xFF },
+    Color { r: 0xFF, g: 0xC0, b: 0x40, a: 0xFF },
+    Color { r: 0x60, g: 0xA0, b: 0xFF, a: 0xFF },
+    Color { r: 0x80, g: 0xE0, b: 0x80, a: 0xFF },
+];
+
+/// Color for channels which are muted or excluded by a solo.
+const SILENT_COLOR: Color = Color { r: 0x50, g: 0x50, b: 0x50, a: 0xFF };
+
+/// A second window with an oscilloscope for each channel and a graph of wave RAM. The decoded
+/// channel registers are printed by the debugger's `a` command.
+pub struct AudioView {
+    canvas: Canvas<Window>,
+}
+
+impl AudioView {
+    pub fn new(video: &VideoSubsystem) -> Result<AudioView, String> {
+        let window = video
+            .window("Rugby - Audio", WIDTH, ROW_HEIGHT * 5)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(AudioView { canvas })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn draw(&mut self, audio: &Audio) -> Result<(), String> {
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+
+        let enabled = [
+            audio.channel1.is_enabled(),
+            audio.channel2.is_enabled(),
+            audio.channel3.is_enabled(),
+            audio.channel4.is_enabled(),
+        ];
+        for channel in 0..4 {
+            let color = if audio.channel_audible(channel + 1) { CHANNEL_COLORS[channel] } else { SILENT_COLOR };
+            let top = (channel as u32 * ROW_HEIGHT) as i32;
+
+            if enabled[channel] {
+                self.canvas.set_draw_color(color);
+                self.canvas.fill_rect(Rect::new(0, top, STATUS_WIDTH, ROW_HEIGHT - 1))?;
+            }
+
+            if let Some(scope) = &audio.scope {
+                let samples = scope.samples(channel);
+                let start = trigger_point(samples.iter().take(SCOPE_LENGTH - WIDTH as usize));
+                let points: Vec<Point> = samples.iter()
+                    .skip(start)
+                    .take((WIDTH - STATUS_WIDTH) as usize)
+                    .enumerate()
+                    .map(|(x, &value)| Point::new(STATUS_WIDTH as i32 + x as i32, level_y(top, value)))
+                    .collect();
+                self.canvas.set_draw_color(color);
+                self.canvas.draw_lines(&points[..])?;
+            }
+        }
+
+        // Wave RAM, one bar per sample
+        let top = (4 * ROW_HEIGHT) as i32;
+        let bar_width = WIDTH / 32;
+        let color = if audio.channel_audible(3) { CHANNEL_COLORS[2] } else { SILENT_COLOR };
+        self.canvas.set_draw_color(color);
+        for (i, &byte) in audio.channel3.wave_ram.iter().enumerate() {
+            for (j, &sample) in [byte >> 4, byte & 0x0F].iter().enumerate() {
+                let x = ((i * 2 + j) as u32 * bar_width) as i32;
+                let y = level_y(top, sample);
+                let height = (top + ROW_HEIGHT as i32 - y) as u32;
+                self.canvas.fill_rect(Rect::new(x, y, bar_width - 1, height))?;
+            }
+        }
+
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+/// The y coordinate of a 4-bit level in the row starting at `top`.
+fn level_y(top: i32, value: u8) -> i32 {
+    top + ROW_HEIGHT as i32 - LEVEL_HEIGHT * 2 - value as i32 * LEVEL_HEIGHT
+}
+
+/// The index of the first rising edge, so periodic waves are drawn at the same phase each frame
+/// and stand still. Falls back to 0 if the wave doesn't rise.
+fn trigger_point<'a>(samples: impl Iterator<Item = &'a u8>) -> usize {
+    let mut previous = None;
+    for (i, &value) in samples.enumerate() {
+        if previous.map_or(false, |p| value > p) {
+            return i;
+        }
+        previous = Some(value);
+    }
+    0
+}
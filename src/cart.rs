@@ -1,29 +1,73 @@
-use crate::cart_header::{CartHeader, CartType, MemSize};
+use crate::cart_header::{CartHardware, CartHeader, CartType, MemSize};
+use crate::eeprom::{Eeprom, EEPROM_SIZE};
+use crate::flash::{Flash, FLASH_SIZE};
+use crate::ir::IrPort;
+use crate::rtc::{HuC3Rtc, Rtc, HUC3_RTC_SAVE_SIZE, RTC_SAVE_SIZE, RTC_SAVE_SIZE_SHORT};
+use enumflags2::BitFlags;
+use log::info;
 use failure_derive::Fail;
-use log::warn;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[cfg(test)]
+mod test;
 
 const ROM_BANK_SIZE: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 
+/// MBC2 has 512 half-bytes of RAM built in, regardless of the header's RAM size.
+const MBC2_RAM_SIZE: usize = 0x200;
+
+/// The accelerometer reading when level, for each axis.
+const ACCELEROMETER_CENTER: u16 = 0x81D0;
+
+/// How much the accelerometer reading changes per g of tilt.
+const ACCELEROMETER_SCALE: f32 = 0x70 as f32;
+
+/// MBC6 switches ROM, flash and RAM in two halves each, so its banks are half the usual size.
+const MBC6_ROM_BANK_SIZE: usize = 0x2000;
+const MBC6_RAM_BANK_SIZE: usize = 0x1000;
+
+/// Writing this to the RAM enable register of HuC1 and HuC3 cartridges maps the IR port where RAM
+/// would be.
+const HUC_IR_MODE: u8 = 0x0E;
+
+/// A shared handle to the other end of a cartridge's IR port.
+pub type IrPortHandle = Rc<RefCell<dyn IrPort>>;
+
+/// Each game in an MBC1 multicart takes up 256 KB of the ROM.
+const MULTICART_GAME_SIZE: usize = 0x40000;
+
+/// Where the Nintendo logo is in each game's header.
+const LOGO_RANGE: std::ops::Range<usize> = 0x0104..0x0134;
+
 #[derive(Clone, Debug)]
 pub struct CartConfig {
     pub cart_type: CartType,
+    pub hardware: BitFlags<CartHardware>,
     pub rom_size: usize,
     pub ram_size: usize,
+    /// True if an MBC1 cartridge is wired as a multicart (MBC1M), or None to detect it from the
+    /// ROM.
+    pub multicart: Option<bool>,
 }
 
 impl CartConfig {
     pub fn from_cart_header(cart_header: &CartHeader) -> Result<Self, CartError> {
         Ok(CartConfig {
             cart_type: cart_header.cart_type,
+            hardware: cart_header.hardware,
             rom_size: match cart_header.rom_size {
                 MemSize::Bytes(b) => b,
                 MemSize::Unknown(_) => return Err(CartError::RomSizeUnknown),
             },
-            ram_size: match cart_header.rom_size {
-                MemSize::Bytes(b) => b,
-                MemSize::Unknown(_) => return Err(CartError::RamSizeUnknown),
+            ram_size: match (cart_header.cart_type, cart_header.ram_size) {
+                (CartType::Mbc2, _) => MBC2_RAM_SIZE,
+                (CartType::Mbc7, _) => EEPROM_SIZE,
+                (_, MemSize::Bytes(b)) => b,
+                (_, MemSize::Unknown(_)) => return Err(CartError::RamSizeUnknown),
             },
+            multicart: None,
         })
     }
 }
@@ -32,8 +76,14 @@ impl CartConfig {
 pub enum Cart {
     NoMbc(NoMbc),
     Mbc1(Mbc1),
+    Mbc2(Mbc2),
     Mbc3(Mbc3),
     Mbc5(Mbc5),
+    Mbc7(Mbc7),
+    HuC1(HuC1),
+    HuC3(HuC3),
+    Mmm01(Mmm01),
+    Mbc6(Mbc6),
 }
 
 #[derive(Clone, Debug, Fail)]
@@ -57,8 +107,21 @@ impl Cart {
         ram_opt: Option<Box<[u8]>>,
         config: &CartConfig,
     ) -> Result<Cart, CartError> {
+        // Save files for cartridges with a clock have the clock's state after the RAM.
+        let has_rtc = config.hardware.contains(CartHardware::Timer);
+        let footer_sizes: &[usize] = match config.cart_type {
+            CartType::Mbc3 if has_rtc => &[RTC_SAVE_SIZE, RTC_SAVE_SIZE_SHORT],
+            CartType::HuC3 => &[HUC3_RTC_SAVE_SIZE],
+            CartType::Mbc6 => &[FLASH_SIZE],
+            _ => &[],
+        };
+        let mut footer = None;
         let ram = match ram_opt {
-            Some(ram) => {
+            Some(mut ram) => {
+                if footer_sizes.contains(&ram.len().saturating_sub(config.ram_size)) {
+                    footer = Some(ram[config.ram_size..].to_vec());
+                    ram = ram[..config.ram_size].into();
+                }
                 if ram.len() != config.ram_size {
                     return Err(CartError::ProvidedRamWrongSize {
                         expected: config.ram_size,
@@ -67,14 +130,41 @@ impl Cart {
                 }
                 ram
             }
+            // A blank EEPROM is all 1s.
+            None if config.cart_type == CartType::Mbc7 => vec![0xFF; config.ram_size].into_boxed_slice(),
             None => vec![0; config.ram_size].into_boxed_slice(),
         };
 
         Ok(match config.cart_type {
             CartType::NoMbc => Cart::NoMbc(NoMbc::new(rom, ram)),
-            CartType::Mbc1 => Cart::Mbc1(Mbc1::new(rom, ram)),
-            CartType::Mbc3 => Cart::Mbc3(Mbc3::new(rom, ram)),
-            CartType::Mbc5 => Cart::Mbc5(Mbc5::new(rom, ram)),
+            CartType::Mbc1 => {
+                let multicart = config.multicart.unwrap_or_else(|| is_multicart(&rom));
+                if multicart {
+                    info!("Using MBC1 multicart (MBC1M) banking");
+                }
+                Cart::Mbc1(Mbc1::new(rom, ram, multicart))
+            }
+            CartType::Mbc2 => Cart::Mbc2(Mbc2::new(rom, ram)),
+            CartType::Mbc3 => {
+                let rtc = if has_rtc { Some(footer.map_or_else(Rtc::new, |f| Rtc::from_save(&f))) } else { None };
+                Cart::Mbc3(Mbc3::new(rom, ram, rtc))
+            }
+            CartType::Mbc5 => {
+                let rumble = config.hardware.contains(CartHardware::Rumble);
+                Cart::Mbc5(Mbc5::new(rom, ram, rumble))
+            }
+            CartType::Mbc7 => Cart::Mbc7(Mbc7::new(rom, ram)),
+            CartType::HuC1 => Cart::HuC1(HuC1::new(rom, ram)),
+            CartType::HuC3 => {
+                let rtc = footer.map_or_else(HuC3Rtc::new, |f| HuC3Rtc::from_save(&f));
+                Cart::HuC3(HuC3::new(rom, ram, rtc))
+            }
+            CartType::Mmm01 => Cart::Mmm01(Mmm01::new(rom, ram)),
+            CartType::Mbc6 => {
+                // An erased flash chip is all 1s.
+                let flash = footer.unwrap_or_else(|| vec![0xFF; FLASH_SIZE]).into_boxed_slice();
+                Cart::Mbc6(Mbc6::new(rom, ram, flash))
+            }
             _ => panic!("Unimplemented Mbc Type!"),
         })
     }
@@ -83,8 +173,14 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => nombc.read(addr),
             Cart::Mbc1(mbc1) => mbc1.read(addr),
+            Cart::Mbc2(mbc2) => mbc2.read(addr),
             Cart::Mbc3(mbc3) => mbc3.read(addr),
             Cart::Mbc5(mbc5) => mbc5.read(addr),
+            Cart::Mbc7(mbc7) => mbc7.read(addr),
+            Cart::HuC1(huc1) => huc1.read(addr),
+            Cart::HuC3(huc3) => huc3.read(addr),
+            Cart::Mmm01(mmm01) => mmm01.read(addr),
+            Cart::Mbc6(mbc6) => mbc6.read(addr),
         }
     }
 
@@ -92,18 +188,85 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => nombc.write(addr, val),
             Cart::Mbc1(mbc1) => mbc1.write(addr, val),
+            Cart::Mbc2(mbc2) => mbc2.write(addr, val),
             Cart::Mbc3(mbc3) => mbc3.write(addr, val),
             Cart::Mbc5(mbc5) => mbc5.write(addr, val),
+            Cart::Mbc7(mbc7) => mbc7.write(addr, val),
+            Cart::HuC1(huc1) => huc1.write(addr, val),
+            Cart::HuC3(huc3) => huc3.write(addr, val),
+            Cart::Mmm01(mmm01) => mmm01.write(addr, val),
+            Cart::Mbc6(mbc6) => mbc6.write(addr, val),
         }
     }
 
-    #[cfg(test)] // Silence "never used" warning.
     pub fn rom(&self) -> &[u8] {
         match self {
             Cart::NoMbc(nombc) => &nombc.rom,
             Cart::Mbc1(mbc1) => &mbc1.rom,
+            Cart::Mbc2(mbc2) => &mbc2.rom,
             Cart::Mbc3(mbc3) => &mbc3.rom,
             Cart::Mbc5(mbc5) => &mbc5.rom,
+            Cart::Mbc7(mbc7) => &mbc7.rom,
+            Cart::HuC1(huc1) => &huc1.rom,
+            Cart::HuC3(huc3) => &huc3.rom,
+            Cart::Mmm01(mmm01) => &mmm01.rom,
+            Cart::Mbc6(mbc6) => &mbc6.rom,
+        }
+    }
+
+    /// The contents of a battery-backed save file: the RAM, followed by the clock's state for
+    /// cartridges with one, or the flash memory for MBC6.
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram().to_vec();
+        match self {
+            Cart::Mbc3(Mbc3 { rtc: Some(rtc), .. }) => data.extend(rtc.save()),
+            Cart::HuC3(huc3) => data.extend(huc3.rtc.save()),
+            Cart::Mbc6(mbc6) => data.extend_from_slice(&mbc6.flash.data),
+            _ => {}
+        }
+        data
+    }
+
+    /// True if the cartridge has a rumble motor and it's on.
+    pub fn motor_on(&self) -> bool {
+        match self {
+            Cart::Mbc5(mbc5) => mbc5.motor_on,
+            _ => false,
+        }
+    }
+
+    /// The setting of the tone generator in HuC3 cartridges, if it's playing.
+    pub fn huc3_tone(&self) -> Option<u8> {
+        match self {
+            Cart::HuC3(huc3) => huc3.rtc.tone(),
+            _ => None,
+        }
+    }
+
+    /// Set how far the cartridge is tilted on each axis, in g. Positive x tilts the right side
+    /// down, and positive y tilts the bottom down. Only MBC7 cartridges have an accelerometer.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        if let Cart::Mbc7(mbc7) = self {
+            mbc7.tilt = (x, y);
+        }
+    }
+
+    /// Advance hardware in the cartridge, like the clock, by some hardware cycles.
+    pub fn step(&mut self, cycles: usize) {
+        match self {
+            Cart::Mbc3(Mbc3 { rtc: Some(rtc), .. }) => rtc.step(cycles),
+            Cart::HuC3(huc3) => huc3.rtc.step(cycles),
+            _ => {}
+        }
+    }
+
+    /// Connect the other end of the IR port on HuC1 and HuC3 cartridges. Without one, the
+    /// cartridge never sees any light.
+    pub fn set_ir_port(&mut self, port: IrPortHandle) {
+        match self {
+            Cart::HuC1(huc1) => huc1.ir.port = Some(port),
+            Cart::HuC3(huc3) => huc3.ir.port = Some(port),
+            _ => {}
         }
     }
 
@@ -111,8 +274,14 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => &nombc.ram,
             Cart::Mbc1(mbc1) => &mbc1.ram,
+            Cart::Mbc2(mbc2) => &mbc2.ram,
             Cart::Mbc3(mbc3) => &mbc3.ram,
             Cart::Mbc5(mbc5) => &mbc5.ram,
+            Cart::Mbc7(mbc7) => &mbc7.eeprom.data,
+            Cart::HuC1(huc1) => &huc1.ram,
+            Cart::HuC3(huc3) => &huc3.ram,
+            Cart::Mmm01(mmm01) => &mmm01.ram,
+            Cart::Mbc6(mbc6) => &mbc6.ram,
         }
     }
 }
@@ -169,8 +338,13 @@ pub struct Mbc1 {
     ram: Box<[u8]>,
     mode: MbcMode,
     ram_enabled: bool,
+    /// The lower ROM bank bits. Writing 0 selects 1, but only the 5-bit value is checked for 0.
     bank_reg1: u8,
+    /// The upper ROM bank bits, or the RAM bank.
     bank_reg2: u8,
+    /// True for multicarts (MBC1M), which connect `bank_reg2` to ROM bank bits 4-5 instead of
+    /// 5-6, and leave bit 4 of `bank_reg1` unconnected.
+    multicart: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -180,8 +354,14 @@ pub enum MbcMode {
 }
 
 impl Mbc1 {
-    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
-        Self { rom, ram, mode: MbcMode::Rom, ram_enabled: false, bank_reg1: 1, bank_reg2: 0 }
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>, multicart: bool) -> Self {
+        Self { rom, ram, mode: MbcMode::Rom, ram_enabled: false, bank_reg1: 1, bank_reg2: 0, multicart }
+    }
+
+    /// The ROM bank selected by `bank_reg2` alone, which is mapped at 0x0000 in RAM banking mode.
+    fn upper_bank(&self) -> u16 {
+        let shift = if self.multicart { 4 } else { 5 };
+        (self.bank_reg2 as u16) << shift
     }
 
     fn read(&self, addr: u16) -> u8 {
@@ -189,14 +369,15 @@ impl Mbc1 {
             0x0000..=0x3FFF => {
                 let bank = match self.mode {
                     MbcMode::Rom => 0,
-                    MbcMode::Ram => self.bank_reg2 << 5,
+                    MbcMode::Ram => self.upper_bank(),
                 };
-                get_rom(&self.rom, bank as u16, addr)
+                get_rom(&self.rom, bank, addr)
             }
 
             0x4000..=0x7FFF => {
-                let bank = self.bank_reg2 << 5 | self.bank_reg1;
-                get_rom(&self.rom, bank as u16, addr)
+                let lower_mask = if self.multicart { 0b0000_1111 } else { 0b0001_1111 };
+                let bank = self.upper_bank() | (self.bank_reg1 & lower_mask) as u16;
+                get_rom(&self.rom, bank, addr)
             }
 
             0xA000..=0xBFFF => {
@@ -254,6 +435,69 @@ impl Mbc1 {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Mbc2 {
+    rom: Box<[u8]>,
+    /// 512 4-bit values, one per byte. Only the lower nibble is stored.
+    ram: Box<[u8]>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self { rom, ram, ram_enabled: false, rom_bank: 1 }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000..=0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM bank
+            0x4000..=0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            // Built-in RAM, repeated across the whole area
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_enabled { return 0xFF; }
+                // The upper nibble isn't connected and reads as 1s.
+                self.ram[addr as usize & (MBC2_RAM_SIZE - 1)] | 0xF0
+            }
+
+            _ => panic!("Unimplemented MBC2 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM Enable or ROM bank, depending on bit 8 of the address
+            0x0000..=0x3FFF => {
+                if addr & 0x0100 == 0 {
+                    self.ram_enabled = (val & 0b1111) == 0b1010;
+                } else {
+                    self.rom_bank = val & 0b1111;
+                    if self.rom_bank == 0 {
+                        self.rom_bank = 1;
+                    }
+                }
+            }
+
+            // No registers here
+            0x4000..=0x7FFF => {}
+
+            // Built-in RAM, repeated across the whole area
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_enabled { return; }
+                self.ram[addr as usize & (MBC2_RAM_SIZE - 1)] = val & 0b1111;
+            }
+
+            _ => panic!("Unimplemented MBC2 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Mbc3 {
     rom: Box<[u8]>,
@@ -261,12 +505,13 @@ pub struct Mbc3 {
     rom_bank: u8,
     ram_rtc_enabled: bool,
     ram_rtc_bank: u8,
-    rtc: [u8; 5],
+    /// The clock, if the cartridge has one.
+    rtc: Option<Rtc>,
 }
 
 impl Mbc3 {
-    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
-        Self { rom, ram, rom_bank: 1, ram_rtc_enabled: false, ram_rtc_bank: 0, rtc: [0; 5] }
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>, rtc: Option<Rtc>) -> Self {
+        Self { rom, ram, rom_bank: 1, ram_rtc_enabled: false, ram_rtc_bank: 0, rtc }
     }
 
     fn read(&self, addr: u16) -> u8 {
@@ -282,16 +527,15 @@ impl Mbc3 {
                 // When RAM is disabled, the hardware returns all bits set.
                 if !self.ram_rtc_enabled { return 0xFF; }
 
-                let bank = self.ram_rtc_bank as u16;
-                match bank {
+                match self.ram_rtc_bank {
                     // Read from RTC values
-                    0x8..=0xC => {
-                        //TODO(wcarlson): Read from RTC values
-                        unimplemented!()
-                    }
+                    0x8..=0xC => self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read(self.ram_rtc_bank)),
 
                     // Read from RAM bank
-                    _ => get_ram(&self.ram, bank, addr),
+                    bank => {
+                        if self.ram.len() == 0 { return 0xFF; }
+                        get_ram(&self.ram, bank as u16, addr)
+                    }
                 }
             }
 
@@ -321,7 +565,9 @@ impl Mbc3 {
 
             // Latch clock data
             0x6000..=0x7FFF => {
-                warn!("unimplemented: write to MBC3 RTC latch register");
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(val);
+                }
             }
 
             // Switchable RAM bank
@@ -329,18 +575,18 @@ impl Mbc3 {
                 // When RAM is disabled, the hardware ignores writes.
                 if !self.ram_rtc_enabled { return; }
 
-                let bank = self.ram_rtc_bank as u16;
-                match bank {
+                match self.ram_rtc_bank {
                     // Write to RTC values
                     0x8..=0xC => {
-                        warn!("unimplemented: write to MBC3 RTC data");
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.write(self.ram_rtc_bank, val);
+                        }
                     }
 
                     // Write to RAM bank
-                    _ => {
-                        // let ram_index = (addr - 0xA000) as usize;
-                        // self.ram[ram_index + bank * RAM_BANK_SIZE] = val;
-                        set_ram(&mut self.ram, bank, addr, val);
+                    bank => {
+                        if self.ram.len() == 0 { return; }
+                        set_ram(&mut self.ram, bank as u16, addr, val);
                     }
                 }
             }
@@ -358,10 +604,14 @@ pub struct Mbc5 {
     rom_bank_reg1: u8,
     rom_bank_reg2: u8,
     ram_bank_reg: u8,
+    /// True if the cartridge has a rumble motor, which is controlled by bit 3 of the RAM bank
+    /// register instead of it selecting a bank.
+    rumble: bool,
+    motor_on: bool,
 }
 
 impl Mbc5 {
-    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>, rumble: bool) -> Self {
         Self {
             rom,
             ram,
@@ -369,6 +619,8 @@ impl Mbc5 {
             rom_bank_reg1: 0,
             rom_bank_reg2: 0,
             ram_bank_reg: 0,
+            rumble,
+            motor_on: false,
         }
     }
 
@@ -410,9 +662,14 @@ impl Mbc5 {
                 self.rom_bank_reg2 = val & 0b0001;
             }
 
-            // RAM bank write
+            // RAM bank write, and the motor on rumble cartridges
             0x4000..=0x5FFF => {
-                self.ram_bank_reg = val & 0b1111;
+                if self.rumble {
+                    self.motor_on = val & 0b1000 != 0;
+                    self.ram_bank_reg = val & 0b0111;
+                } else {
+                    self.ram_bank_reg = val & 0b1111;
+                }
             }
 
             // Switchable RAM bank
@@ -427,6 +684,564 @@ impl Mbc5 {
     }
 }
 
+/// MBC1 multicarts hold several games of 256 KB each, and each has its own Nintendo logo, which
+/// a normal MBC1 game is very unlikely to have in the same place. Multicarts are 1 MB, the only
+/// size they were made in.
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != 4 * MULTICART_GAME_SIZE {
+        return false;
+    }
+    let logo = &rom[LOGO_RANGE];
+    (1..4).any(|game| {
+        let start = game * MULTICART_GAME_SIZE;
+        rom[start + LOGO_RANGE.start..start + LOGO_RANGE.end] == *logo
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc7 {
+    rom: Box<[u8]>,
+    eeprom: Eeprom,
+    rom_bank: u8,
+    /// The registers are only accessible when both enables are set.
+    ram_enabled_1: bool,
+    ram_enabled_2: bool,
+    /// The current tilt in g, set by the frontend.
+    tilt: (f32, f32),
+    /// The accelerometer readings latched by the game.
+    accelerometer_x: u16,
+    accelerometer_y: u16,
+    /// True if the latched readings have been erased, which must happen before latching again.
+    accelerometer_erased: bool,
+}
+
+impl Mbc7 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            eeprom: Eeprom::new(ram),
+            rom_bank: 1,
+            ram_enabled_1: false,
+            ram_enabled_2: false,
+            tilt: (0.0, 0.0),
+            accelerometer_x: 0x8000,
+            accelerometer_y: 0x8000,
+            accelerometer_erased: false,
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000..=0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM bank
+            0x4000..=0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            // Registers, selected by bits 4-7 of the address
+            0xA000..=0xAFFF => {
+                if !self.ram_enabled_1 || !self.ram_enabled_2 { return 0xFF; }
+                match addr & 0xF0 {
+                    0x20 => self.accelerometer_x as u8,
+                    0x30 => (self.accelerometer_x >> 8) as u8,
+                    0x40 => self.accelerometer_y as u8,
+                    0x50 => (self.accelerometer_y >> 8) as u8,
+                    0x60 => 0x00,
+                    0x80 => self.eeprom.read_pins(),
+                    _ => 0xFF,
+                }
+            }
+
+            0xB000..=0xBFFF => 0xFF,
+
+            _ => panic!("Unimplemented MBC7 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM Enable 1
+            0x0000..=0x1FFF => {
+                self.ram_enabled_1 = val == 0x0A;
+            }
+
+            // ROM bank
+            0x2000..=0x3FFF => {
+                self.rom_bank = val & 0b0111_1111;
+            }
+
+            // RAM Enable 2
+            0x4000..=0x5FFF => {
+                self.ram_enabled_2 = val == 0x40;
+            }
+
+            0x6000..=0x7FFF => {}
+
+            // Registers, selected by bits 4-7 of the address
+            0xA000..=0xAFFF => {
+                if !self.ram_enabled_1 || !self.ram_enabled_2 { return; }
+                match addr & 0xF0 {
+                    // Erase the latched accelerometer readings
+                    0x00 if val == 0x55 => {
+                        self.accelerometer_x = 0x8000;
+                        self.accelerometer_y = 0x8000;
+                        self.accelerometer_erased = true;
+                    }
+
+                    // Latch the accelerometer readings
+                    0x10 if val == 0xAA && self.accelerometer_erased => {
+                        // Tilting right or down pulls the reading below the center.
+                        let reading = |tilt: f32| (ACCELEROMETER_CENTER as f32 - tilt * ACCELEROMETER_SCALE) as u16;
+                        self.accelerometer_x = reading(self.tilt.0);
+                        self.accelerometer_y = reading(self.tilt.1);
+                        self.accelerometer_erased = false;
+                    }
+
+                    0x80 => self.eeprom.write_pins(val),
+
+                    _ => {}
+                }
+            }
+
+            0xB000..=0xBFFF => {}
+
+            _ => panic!("Unimplemented MBC7 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+/// The IR LED and sensor in HuC1 and HuC3 cartridges.
+#[derive(Clone, Debug)]
+struct HucIr {
+    led: bool,
+    port: Option<IrPortHandle>,
+}
+
+impl HucIr {
+    fn new() -> Self {
+        HucIr { led: false, port: None }
+    }
+
+    /// Bit 0 is set if the sensor sees light.
+    fn read(&self) -> u8 {
+        let light = self.port.as_ref().map_or(false, |port| port.borrow().light_detected());
+        0xC0 | light as u8
+    }
+
+    /// Bit 0 turns the LED on.
+    fn write(&mut self, val: u8) {
+        self.led = val & 1 != 0;
+        if let Some(port) = &self.port {
+            port.borrow_mut().set_led(self.led);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HuC1 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    rom_bank: u8,
+    ram_bank: u8,
+    /// True if the IR port is mapped at 0xA000 instead of RAM.
+    ir_mode: bool,
+    ir: HucIr,
+}
+
+impl HuC1 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self { rom, ram, rom_bank: 1, ram_bank: 0, ir_mode: false, ir: HucIr::new() }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000..=0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM bank
+            0x4000..=0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            // Switchable RAM bank or IR port
+            0xA000..=0xBFFF => {
+                if self.ir_mode { return self.ir.read(); }
+                if self.ram.len() == 0 { return 0xFF; }
+                get_ram(&self.ram, self.ram_bank as u16, addr)
+            }
+
+            _ => panic!("Unimplemented HuC1 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM / IR select. RAM can't be disabled.
+            0x0000..=0x1FFF => {
+                self.ir_mode = val & 0b1111 == HUC_IR_MODE;
+            }
+
+            // ROM bank
+            0x2000..=0x3FFF => {
+                self.rom_bank = val & 0b0011_1111;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+
+            // RAM bank
+            0x4000..=0x5FFF => {
+                self.ram_bank = val & 0b0011;
+            }
+
+            0x6000..=0x7FFF => {}
+
+            // Switchable RAM bank or IR port
+            0xA000..=0xBFFF => {
+                if self.ir_mode {
+                    self.ir.write(val);
+                } else if self.ram.len() > 0 {
+                    set_ram(&mut self.ram, self.ram_bank as u16, addr, val);
+                }
+            }
+
+            _ => panic!("Unimplemented HuC1 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HuC3 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    rom_bank: u8,
+    ram_bank: u8,
+    /// Selects what is mapped at 0xA000: 0x0 is read-only RAM, 0xA is RAM, 0xB is the clock's
+    /// command register, 0xC is its result, 0xD is its ready flag and 0xE is the IR port.
+    mode: u8,
+    rtc: HuC3Rtc,
+    ir: HucIr,
+}
+
+impl HuC3 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>, rtc: HuC3Rtc) -> Self {
+        Self { rom, ram, rom_bank: 1, ram_bank: 0, mode: 0, rtc, ir: HucIr::new() }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000..=0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM bank
+            0x4000..=0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            // Depends on the mode
+            0xA000..=0xBFFF => match self.mode {
+                0x0 | 0xA => {
+                    if self.ram.len() == 0 { return 0xFF; }
+                    get_ram(&self.ram, self.ram_bank as u16, addr)
+                }
+                0xC => self.rtc.read(),
+                // Commands finish instantly, so the clock is always ready.
+                0xD => 1,
+                HUC_IR_MODE => self.ir.read(),
+                _ => 0xFF,
+            },
+
+            _ => panic!("Unimplemented HuC3 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // Mode select
+            0x0000..=0x1FFF => {
+                self.mode = val & 0b1111;
+            }
+
+            // ROM bank
+            0x2000..=0x3FFF => {
+                self.rom_bank = val & 0b0111_1111;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+
+            // RAM bank
+            0x4000..=0x5FFF => {
+                self.ram_bank = val & 0b0011;
+            }
+
+            0x6000..=0x7FFF => {}
+
+            // Depends on the mode
+            0xA000..=0xBFFF => match self.mode {
+                0xA => {
+                    if self.ram.len() > 0 {
+                        set_ram(&mut self.ram, self.ram_bank as u16, addr, val);
+                    }
+                }
+                0xB => self.rtc.write(val),
+                HUC_IR_MODE => self.ir.write(val),
+                _ => {}
+            },
+
+            _ => panic!("Unimplemented HuC3 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mmm01 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    /// False until the menu locks in a game. Until then the last 32 KB of ROM is mapped, and
+    /// the upper bank bits and masks can be written.
+    mapped: bool,
+    ram_enabled: bool,
+    /// ROM bank bits 0-4, like MBC1's lower bank register.
+    rom_bank_low: u8,
+    /// ROM bank bits 5-6 and 7-8, which pick the game.
+    rom_bank_mid: u8,
+    rom_bank_high: u8,
+    /// Which of ROM bank bits 1-4 the game can't change, in bits 1-4.
+    rom_bank_mask: u8,
+    /// RAM bank bits 0-1, and 2-3 which pick the game's RAM.
+    ram_bank_low: u8,
+    ram_bank_high: u8,
+    /// Which of RAM bank bits 0-1 the game can't change.
+    ram_bank_mask: u8,
+    /// MBC1 banking mode. Ram mode maps the upper bank bits at 0x0000.
+    mode: MbcMode,
+}
+
+impl Mmm01 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            ram,
+            mapped: false,
+            ram_enabled: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            ram_bank_mask: 0,
+            mode: MbcMode::Rom,
+        }
+    }
+
+    /// The first bank of the selected game.
+    fn game_bank(&self) -> u16 {
+        (self.rom_bank_high as u16) << 7 | (self.rom_bank_mid as u16) << 5
+    }
+
+    fn rom_bank(&self, addr: u16) -> u16 {
+        if !self.mapped {
+            // The menu is in the last 32 KB.
+            let last_bank = (self.rom.len() / ROM_BANK_SIZE).saturating_sub(1) as u16;
+            return if addr < 0x4000 { last_bank.saturating_sub(1) } else { last_bank };
+        }
+        let masked_low = (self.rom_bank_low & self.rom_bank_mask) as u16;
+        if addr < 0x4000 {
+            return match self.mode {
+                MbcMode::Rom => self.game_bank() | masked_low,
+                MbcMode::Ram => self.game_bank() | masked_low | (self.ram_bank_low as u16) << 5,
+            };
+        }
+        // Only the bits the game controls count when turning bank 0 into bank 1.
+        let mut low = self.rom_bank_low as u16;
+        if low & !(self.rom_bank_mask as u16) & 0b1_1111 == 0 {
+            low |= 1;
+        }
+        self.game_bank() | low
+    }
+
+    fn ram_bank(&self) -> u16 {
+        let low = match self.mode {
+            MbcMode::Rom => self.ram_bank_low & self.ram_bank_mask,
+            MbcMode::Ram => self.ram_bank_low,
+        };
+        (self.ram_bank_high << 2 | low) as u16
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7FFF => get_rom(&self.rom, self.rom_bank(addr), addr),
+
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
+                get_ram(&self.ram, self.ram_bank(), addr)
+            }
+
+            _ => panic!("Unimplemented MMM01 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM Enable. Before mapping, also the RAM bank mask, and bit 6 locks in the game.
+            0x0000..=0x1FFF => {
+                self.ram_enabled = (val & 0b1111) == 0b1010;
+                if !self.mapped {
+                    self.ram_bank_mask = (val >> 4) & 0b0011;
+                    self.mapped = val & 0b0100_0000 != 0;
+                }
+            }
+
+            // ROM bank. Before mapping, bits 5-6 are the game's ROM bank bits 5-6.
+            0x2000..=0x3FFF => {
+                let mask = if self.mapped { self.rom_bank_mask } else { 0 };
+                self.rom_bank_low = self.rom_bank_low & mask | val & !mask & 0b1_1111;
+                if !self.mapped {
+                    self.rom_bank_mid = (val >> 5) & 0b0011;
+                }
+            }
+
+            // RAM bank. Before mapping, bits 2-3 are the game's RAM bank bits 2-3 and bits 4-5 are
+            // its ROM bank bits 7-8.
+            0x4000..=0x5FFF => {
+                let mask = if self.mapped { self.ram_bank_mask } else { 0 };
+                self.ram_bank_low = self.ram_bank_low & mask | val & !mask & 0b0011;
+                if !self.mapped {
+                    self.ram_bank_high = (val >> 2) & 0b0011;
+                    self.rom_bank_high = (val >> 4) & 0b0011;
+                }
+            }
+
+            // Banking mode. Before mapping, bits 2-5 are the ROM bank mask.
+            0x6000..=0x7FFF => {
+                self.mode = if val & 1 == 0 { MbcMode::Rom } else { MbcMode::Ram };
+                if !self.mapped {
+                    self.rom_bank_mask = (val >> 1) & 0b0001_1110;
+                }
+            }
+
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                let bank = self.ram_bank();
+                set_ram(&mut self.ram, bank, addr, val);
+            }
+
+            _ => panic!("Unimplemented MMM01 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc6 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    flash: Flash,
+    ram_enabled: bool,
+    /// The 4 KB RAM banks at 0xA000 and 0xB000.
+    ram_banks: [u8; 2],
+    /// The 8 KB ROM or flash banks at 0x4000 and 0x6000.
+    rom_banks: [u8; 2],
+    /// True if each half of 0x4000-0x7FFF maps flash instead of ROM.
+    flash_selected: [bool; 2],
+    /// Flash can only be read while enabled.
+    flash_enabled: bool,
+    /// Flash can only be programmed or erased while writes are enabled.
+    flash_write_enabled: bool,
+}
+
+impl Mbc6 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>, flash: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            ram,
+            flash: Flash::new(flash),
+            ram_enabled: false,
+            ram_banks: [0; 2],
+            rom_banks: [0; 2],
+            flash_selected: [false; 2],
+            flash_enabled: false,
+            flash_write_enabled: false,
+        }
+    }
+
+    /// The flash address a CPU address in one of the halves of 0x4000-0x7FFF maps to.
+    fn flash_addr(&self, half: usize, addr: u16) -> usize {
+        self.rom_banks[half] as usize * MBC6_ROM_BANK_SIZE + (addr as usize & (MBC6_ROM_BANK_SIZE - 1))
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000..=0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM or flash banks
+            0x4000..=0x7FFF => {
+                let half = (addr as usize - 0x4000) / MBC6_ROM_BANK_SIZE;
+                if self.flash_selected[half] {
+                    if !self.flash_enabled { return 0xFF; }
+                    self.flash.read(self.flash_addr(half, addr))
+                } else {
+                    self.rom[bank_index(self.rom_banks[half] as u16, addr, MBC6_ROM_BANK_SIZE, self.rom.len())]
+                }
+            }
+
+            // Switchable RAM banks
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
+                let half = (addr as usize - 0xA000) / MBC6_RAM_BANK_SIZE;
+                self.ram[bank_index(self.ram_banks[half] as u16, addr, MBC6_RAM_BANK_SIZE, self.ram.len())]
+            }
+
+            _ => panic!("Unimplemented MBC6 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM Enable
+            0x0000..=0x03FF => self.ram_enabled = (val & 0b1111) == 0b1010,
+
+            // RAM banks A and B
+            0x0400..=0x07FF => self.ram_banks[0] = val & 0b0111,
+            0x0800..=0x0BFF => self.ram_banks[1] = val & 0b0111,
+
+            // Flash enable
+            0x0C00..=0x0FFF => self.flash_enabled = val & 1 != 0,
+
+            // Flash write enable
+            0x1000..=0x1FFF => self.flash_write_enabled = val & 1 != 0,
+
+            // ROM or flash bank A, and whether it's ROM (0x00) or flash (0x08)
+            0x2000..=0x27FF => self.rom_banks[0] = val & 0b0111_1111,
+            0x2800..=0x2FFF => self.flash_selected[0] = val == 0x08,
+
+            // ROM or flash bank B, and whether it's ROM or flash
+            0x3000..=0x37FF => self.rom_banks[1] = val & 0b0111_1111,
+            0x3800..=0x3FFF => self.flash_selected[1] = val == 0x08,
+
+            // Flash commands
+            0x4000..=0x7FFF => {
+                let half = (addr as usize - 0x4000) / MBC6_ROM_BANK_SIZE;
+                if self.flash_selected[half] && self.flash_enabled && self.flash_write_enabled {
+                    self.flash.write(self.flash_addr(half, addr), val);
+                }
+            }
+
+            // Switchable RAM banks
+            0xA000..=0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                let half = (addr as usize - 0xA000) / MBC6_RAM_BANK_SIZE;
+                let i = bank_index(self.ram_banks[half] as u16, addr, MBC6_RAM_BANK_SIZE, self.ram.len());
+                self.ram[i] = val;
+            }
+
+            _ => panic!("Unimplemented MBC6 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
 fn bank_index(bank: u16, addr: u16, bank_size: usize, total_size: usize) -> usize {
     let bank_base = bank as usize * bank_size;
     let addr_in_bank = addr as usize & (bank_size - 1);
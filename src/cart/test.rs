@@ -0,0 +1,315 @@
+use super::*;
+
+/// A ROM of `banks` banks of `bank_size` bytes, where the first byte of each bank is its number.
+fn numbered_rom(banks: usize, bank_size: usize) -> Box<[u8]> {
+    let mut rom = vec![0; banks * bank_size];
+    for bank in 0..banks {
+        rom[bank * bank_size] = bank as u8;
+    }
+    rom.into_boxed_slice()
+}
+
+fn new_cart(cart_type: CartType, rom: Box<[u8]>, ram_size: usize) -> Cart {
+    let config = CartConfig {
+        cart_type,
+        hardware: CartHardware::Ram | CartHardware::Battery,
+        rom_size: rom.len(),
+        ram_size,
+        multicart: None,
+    };
+    Cart::new(rom, None, &config).unwrap()
+}
+
+#[test]
+fn test_config_ram_size() {
+    // An MBC1 cartridge with 64 KB of ROM and 32 KB of RAM.
+    let mut rom = vec![0; 0x10000];
+    rom[0x147] = 0x03;
+    rom[0x148] = 0x01;
+    rom[0x149] = 0x03;
+    let config = CartConfig::from_cart_header(&CartHeader::from_rom(&rom).unwrap()).unwrap();
+    assert_eq!((config.rom_size, config.ram_size), (0x10000, 0x8000));
+
+    // MBC2's RAM is built in, whatever the header says.
+    rom[0x147] = 0x06;
+    let config = CartConfig::from_cart_header(&CartHeader::from_rom(&rom).unwrap()).unwrap();
+    assert_eq!(config.ram_size, MBC2_RAM_SIZE);
+}
+
+#[test]
+fn test_mbc2_registers() {
+    let mut cart = new_cart(CartType::Mbc2, numbered_rom(16, ROM_BANK_SIZE), MBC2_RAM_SIZE);
+    assert_eq!(cart.read(0x4000), 1);
+
+    // With bit 8 of the address set, writes select the ROM bank anywhere in 0x0000-0x3FFF.
+    cart.write(0x0100, 0x03);
+    assert_eq!(cart.read(0x4000), 3);
+    cart.write(0x3FFF, 0xFA);
+    assert_eq!(cart.read(0x4000), 10);
+    cart.write(0x2100, 0x00);
+    assert_eq!(cart.read(0x4000), 1);
+
+    // With bit 8 clear, they enable or disable RAM and leave the ROM bank alone.
+    cart.write(0x3EFF, 0x0A);
+    assert_eq!(cart.read(0x4000), 1);
+    cart.write(0xA000, 0x05);
+    assert_eq!(cart.read(0xA000), 0xF5);
+    cart.write(0x0000, 0x00);
+    assert_eq!(cart.read(0xA000), 0xFF);
+}
+
+#[test]
+fn test_mbc2_ram() {
+    let mut cart = new_cart(CartType::Mbc2, numbered_rom(2, ROM_BANK_SIZE), MBC2_RAM_SIZE);
+    cart.write(0x0000, 0x0A);
+
+    // Only the lower nibble is stored, and the upper nibble reads as 1s.
+    cart.write(0xA123, 0x5C);
+    assert_eq!(cart.read(0xA123), 0xFC);
+    assert_eq!(cart.ram()[0x123], 0x0C);
+
+    // The 512 nibbles repeat across the whole area.
+    assert_eq!(cart.read(0xA323), 0xFC);
+    assert_eq!(cart.read(0xBF23), 0xFC);
+    cart.write(0xB1FF, 0x07);
+    assert_eq!(cart.read(0xA1FF), 0xF7);
+}
+
+/// A 1 MB ROM with numbered banks and a logo in the header of the given 256 KB games.
+fn multicart_rom(games_with_logo: &[usize]) -> Box<[u8]> {
+    let mut rom = numbered_rom(64, ROM_BANK_SIZE);
+    for &game in games_with_logo {
+        let start = game * MULTICART_GAME_SIZE;
+        for (i, byte) in rom[start + LOGO_RANGE.start..start + LOGO_RANGE.end].iter_mut().enumerate() {
+            *byte = 0xC0 | i as u8;
+        }
+    }
+    rom
+}
+
+#[test]
+fn test_is_multicart() {
+    assert!(is_multicart(&multicart_rom(&[0, 1, 2, 3])));
+    assert!(is_multicart(&multicart_rom(&[0, 2])));
+    assert!(!is_multicart(&multicart_rom(&[0])));
+
+    // Only 1 MB ROMs are multicarts.
+    let mut rom = multicart_rom(&[0, 1]).into_vec();
+    rom.truncate(2 * MULTICART_GAME_SIZE);
+    assert!(!is_multicart(&rom));
+}
+
+#[test]
+fn test_mbc1_multicart_banks() {
+    let mut cart = new_cart(CartType::Mbc1, multicart_rom(&[0, 1, 2, 3]), 0);
+
+    // The upper bank bits select the game, and bit 4 of the lower bits is ignored.
+    cart.write(0x4000, 0x02);
+    cart.write(0x2000, 0x13);
+    assert_eq!(cart.read(0x4000), 0x23);
+    assert_eq!(cart.read(0x0000), 0x00);
+
+    // In mode 1, the game's first bank is mapped at 0x0000.
+    cart.write(0x6000, 0x01);
+    assert_eq!(cart.read(0x0000), 0x20);
+
+    // Only a 5-bit 0 is turned into 1, so 0x10 maps the game's first bank at 0x4000 too.
+    cart.write(0x2000, 0x10);
+    assert_eq!(cart.read(0x4000), 0x20);
+}
+
+#[test]
+fn test_mbc1_large_rom() {
+    let mut cart = new_cart(CartType::Mbc1, numbered_rom(128, ROM_BANK_SIZE), 0);
+    cart.write(0x4000, 0x03);
+    cart.write(0x2000, 0x05);
+    assert_eq!(cart.read(0x4000), 0x65);
+    assert_eq!(cart.read(0x0000), 0x00);
+
+    // In mode 1, the upper bits also apply at 0x0000.
+    cart.write(0x6000, 0x01);
+    assert_eq!(cart.read(0x0000), 0x60);
+
+    // Writing 0 or 0x20 selects bank 1 within the upper bits, never bank 0x60.
+    cart.write(0x2000, 0x20);
+    assert_eq!(cart.read(0x4000), 0x61);
+}
+
+#[test]
+fn test_mbc1_large_ram() {
+    let mut cart = new_cart(CartType::Mbc1, numbered_rom(4, ROM_BANK_SIZE), 0x8000);
+    cart.write(0x0000, 0x0A);
+    cart.write(0xA000, 0x10);
+
+    // In mode 1, the upper bits select the RAM bank.
+    cart.write(0x6000, 0x01);
+    cart.write(0x4000, 0x02);
+    assert_eq!(cart.read(0xA000), 0x00);
+    cart.write(0xA000, 0x12);
+    assert_eq!(cart.ram()[2 * RAM_BANK_SIZE], 0x12);
+
+    // In mode 0, bank 0 is always mapped.
+    cart.write(0x6000, 0x00);
+    assert_eq!(cart.read(0xA000), 0x10);
+}
+
+#[test]
+fn test_mbc7_accelerometer() {
+    let mut cart = new_cart(CartType::Mbc7, numbered_rom(4, ROM_BANK_SIZE), EEPROM_SIZE);
+    cart.set_tilt(0.5, -1.0);
+
+    // The registers need both enables.
+    cart.write(0x0000, 0x0A);
+    assert_eq!(cart.read(0xA030), 0xFF);
+    cart.write(0x4000, 0x40);
+    assert_eq!((cart.read(0xA020), cart.read(0xA030)), (0x00, 0x80));
+
+    // Latching only works after erasing.
+    cart.write(0xA010, 0xAA);
+    assert_eq!(cart.read(0xA030), 0x80);
+    cart.write(0xA000, 0x55);
+    cart.write(0xA010, 0xAA);
+    assert_eq!((cart.read(0xA020), cart.read(0xA030)), (0x98, 0x81));
+    assert_eq!((cart.read(0xA040), cart.read(0xA050)), (0x40, 0x82));
+
+    // The readings stay latched until the next erase and latch.
+    cart.set_tilt(0.0, 0.0);
+    cart.write(0xA010, 0xAA);
+    assert_eq!(cart.read(0xA020), 0x98);
+    cart.write(0xA000, 0x55);
+    assert_eq!((cart.read(0xA020), cart.read(0xA030)), (0x00, 0x80));
+    cart.write(0xA010, 0xAA);
+    assert_eq!((cart.read(0xA020), cart.read(0xA030)), (0xD0, 0x81));
+}
+
+#[test]
+fn test_mmm01_menu() {
+    let mut cart = new_cart(CartType::Mmm01, numbered_rom(256, ROM_BANK_SIZE), 0);
+
+    // The menu in the last 32 KB is mapped until a game is locked in.
+    assert_eq!((cart.read(0x0000), cart.read(0x4000)), (254, 255));
+    cart.write(0x2000, 0x25);
+    cart.write(0x4000, 0x10);
+    assert_eq!((cart.read(0x0000), cart.read(0x4000)), (254, 255));
+
+    // Locking in maps the game, which starts at bank 5 << 5 | 1 << 7.
+    cart.write(0x0000, 0x40);
+    assert_eq!((cart.read(0x0000), cart.read(0x4000)), (0xA0, 0xA5));
+
+    // The game can change the low ROM bits, but not the bits picking the game.
+    cart.write(0x2000, 0x7F);
+    assert_eq!(cart.read(0x4000), 0xBF);
+    cart.write(0x4000, 0x00);
+    cart.write(0x2000, 0x00);
+    assert_eq!((cart.read(0x0000), cart.read(0x4000)), (0xA0, 0xA1));
+
+    // Locking in can't be undone.
+    cart.write(0x0000, 0x00);
+    assert_eq!(cart.read(0x0000), 0xA0);
+}
+
+#[test]
+fn test_mmm01_masks() {
+    let mut cart = new_cart(CartType::Mmm01, numbered_rom(128, ROM_BANK_SIZE), 0x8000);
+
+    // Before locking in, mask ROM bits 2-3 and RAM bit 1, and set them to 1.
+    cart.write(0x2000, 0x0C);
+    cart.write(0x6000, 0b0000_1100 << 1);
+    cart.write(0x4000, 0x02);
+    cart.write(0x0000, 0x40 | 0b10 << 4 | 0x0A);
+
+    // Masked bits keep their values.
+    cart.write(0x2000, 0x01);
+    assert_eq!(cart.read(0x4000), 0x0D);
+
+    // Masked bits don't count when turning bank 0 into bank 1.
+    cart.write(0x2000, 0x00);
+    assert_eq!(cart.read(0x4000), 0x0D);
+
+    // In mode 0, the masked RAM bank bit still applies, and the unmasked one is ignored.
+    cart.write(0x4000, 0x01);
+    cart.write(0xA000, 0x42);
+    assert_eq!(cart.ram()[2 * RAM_BANK_SIZE], 0x42);
+}
+
+#[test]
+fn test_mmm01_small_rom() {
+    // A tiny ROM with an MMM01 header doesn't crash.
+    let mut cart = new_cart(CartType::Mmm01, numbered_rom(1, ROM_BANK_SIZE), 0);
+    assert_eq!((cart.read(0x0000), cart.read(0x4000)), (0, 0));
+    cart.write(0x0000, 0x40);
+    assert_eq!(cart.read(0x4000), 0);
+}
+
+#[test]
+fn test_mbc6_banks() {
+    let mut cart = new_cart(CartType::Mbc6, numbered_rom(128, MBC6_ROM_BANK_SIZE), 0x8000);
+    assert_eq!((cart.read(0x0000), cart.read(0x2000)), (0, 1));
+
+    // Each half of 0x4000-0x7FFF has its own 8 KB bank.
+    cart.write(0x2000, 5);
+    cart.write(0x3000, 9);
+    assert_eq!((cart.read(0x4000), cart.read(0x6000)), (5, 9));
+
+    // Each half of 0xA000-0xBFFF has its own 4 KB RAM bank.
+    cart.write(0x0000, 0x0A);
+    cart.write(0x0400, 2);
+    cart.write(0x0800, 7);
+    cart.write(0xA000, 0x22);
+    cart.write(0xB000, 0x77);
+    assert_eq!(cart.ram()[2 * MBC6_RAM_BANK_SIZE], 0x22);
+    assert_eq!(cart.ram()[7 * MBC6_RAM_BANK_SIZE], 0x77);
+    cart.write(0x0800, 2);
+    assert_eq!(cart.read(0xB000), 0x22);
+}
+
+#[test]
+fn test_mbc6_flash() {
+    let mut cart = new_cart(CartType::Mbc6, numbered_rom(128, MBC6_ROM_BANK_SIZE), 0x8000);
+
+    // Bank B maps flash, which is blank, and only readable while enabled.
+    cart.write(0x3800, 0x08);
+    cart.write(0x3000, 3);
+    assert_eq!(cart.read(0x6000), 0xFF);
+    cart.write(0x0C00, 0x01);
+
+    // Write a flash command through bank B, with 0x5555 and 0x2AAA in flash banks 2 and 1.
+    let write_flash = |cart: &mut Cart, flash_addr: usize, val: u8| {
+        cart.write(0x3000, (flash_addr / MBC6_ROM_BANK_SIZE) as u8);
+        cart.write(0x6000 | (flash_addr % MBC6_ROM_BANK_SIZE) as u16, val);
+    };
+    let program = |cart: &mut Cart, flash_addr: usize, val: u8| {
+        write_flash(cart, 0x5555, 0xAA);
+        write_flash(cart, 0x2AAA, 0x55);
+        write_flash(cart, 0x5555, 0xA0);
+        write_flash(cart, flash_addr, val);
+    };
+
+    // Programming needs writes to be enabled.
+    program(&mut cart, 0x6010, 0x42);
+    assert_eq!(cart.read(0x6010), 0xFF);
+    cart.write(0x1000, 0x01);
+    program(&mut cart, 0x6010, 0x42);
+    assert_eq!(cart.read(0x6010), 0x42);
+
+    // Switching bank B back to ROM hides the flash.
+    cart.write(0x3800, 0x00);
+    assert_eq!(cart.read(0x6000), 3);
+
+    // The flash is saved after the RAM.
+    let save = cart.save_data();
+    assert_eq!(save.len(), 0x8000 + FLASH_SIZE);
+    assert_eq!(save[0x8000 + 0x6010], 0x42);
+    let config = CartConfig {
+        cart_type: CartType::Mbc6,
+        hardware: CartHardware::Ram | CartHardware::Battery,
+        rom_size: 128 * MBC6_ROM_BANK_SIZE,
+        ram_size: 0x8000,
+        multicart: None,
+    };
+    let mut cart = Cart::new(numbered_rom(128, MBC6_ROM_BANK_SIZE), Some(save.into_boxed_slice()), &config).unwrap();
+    cart.write(0x0C00, 0x01);
+    cart.write(0x2800, 0x08);
+    cart.write(0x2000, 3);
+    assert_eq!(cart.read(0x4010), 0x42);
+}
@@ -1,6 +1,19 @@
 use enumflags2::BitFlags;
 use failure_derive::Fail;
 
+#[cfg(test)]
+mod test;
+
+/// Size of the menu at the end of an MMM01 cartridge's ROM.
+const MMM01_MENU_SIZE: usize = 0x8000;
+
+/// The Nintendo logo every header has at 0x104-0x133, which the boot ROM checks.
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct CartHeader {
     /// The title of the game. At most 16 bytes.
@@ -169,8 +182,20 @@ pub enum HeaderParseError {
 impl CartHeader {
     /// Parse the cartridge header from the given ROM. The header is in the range 0x100..0x150, so
     /// the input slice must be at least large enough to contain that.
+    ///
+    /// MMM01 multicarts boot into a menu in the last 32 KB of ROM, and only that copy of the header
+    /// describes the cartridge, so it's used instead when it names an MMM01. The copy must also
+    /// have the Nintendo logo and a correct checksum, so a stray byte at the end of another ROM
+    /// isn't mistaken for one.
     pub fn from_rom(rom: &[u8]) -> Result<Self, HeaderParseError> {
         let bytes = rom.get(0x100..0x150).ok_or(HeaderParseError::RomTooShort(rom.len()))?;
+        let menu_start = rom.len().saturating_sub(MMM01_MENU_SIZE);
+        let bytes = match rom.get(menu_start + 0x100..menu_start + 0x150) {
+            Some(menu_bytes) if (0x0B..=0x0D).contains(&menu_bytes[0x47]) && is_valid(menu_bytes) => {
+                menu_bytes
+            }
+            _ => bytes,
+        };
 
         // The last byte of the title is used to determine if this is a Game Boy Color game. This
         // GBC flag uses byts which aren't valid ASCII, so we know this isn't actually part of the
@@ -232,7 +257,7 @@ impl CartHeader {
             0x02 | 0x08 | 0x0C | 0x12 | 0x1A => hardware |= Ram,
             0x03 | 0x06 | 0x09 | 0x0D | 0x13 | 0x1B | 0x20 | 0xFF => hardware |= Ram | Battery,
             0x0F => hardware |= Timer | Battery,
-            0x10 => hardware |= Ram | Timer | Battery,
+            0x10 | 0xFE => hardware |= Ram | Timer | Battery,
             0x1C => hardware |= Rumble,
             0x1D => hardware |= Ram | Rumble,
             0x1E => hardware |= Ram | Battery | Rumble,
@@ -278,3 +303,14 @@ impl CartHeader {
         })
     }
 }
+
+/// True if the header, given as the bytes at 0x100-0x14F, has the Nintendo logo and a correct
+/// header checksum.
+fn is_valid(bytes: &[u8]) -> bool {
+    bytes[0x04..0x34] == NINTENDO_LOGO[..] && header_checksum(bytes) == bytes[0x4D]
+}
+
+/// The checksum of the header bytes at 0x134-0x14C, which the boot ROM checks against 0x14D.
+fn header_checksum(bytes: &[u8]) -> u8 {
+    bytes[0x34..0x4D].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1))
+}
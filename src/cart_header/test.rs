@@ -0,0 +1,62 @@
+use super::*;
+
+/// Write a header with the Nintendo logo, the given title and cartridge type, and a correct
+/// checksum at `start` in the ROM.
+fn write_header(rom: &mut [u8], start: usize, title: &[u8], cart_type: u8) {
+    let header = &mut rom[start + 0x100..start + 0x150];
+    header[0x04..0x34].copy_from_slice(&NINTENDO_LOGO);
+    header[0x34..0x34 + title.len()].copy_from_slice(title);
+    header[0x47] = cart_type;
+    header[0x4D] = header_checksum(header);
+}
+
+#[test]
+fn test_header_checksum() {
+    // The header of a ROM with an empty title and all other fields zero.
+    let mut rom = vec![0; 0x8000];
+    write_header(&mut rom, 0, b"", 0x00);
+    assert_eq!(rom[0x14D], 0xE7);
+}
+
+#[test]
+fn test_parse() {
+    let mut rom = vec![0; 0x8000];
+    write_header(&mut rom, 0, b"TETRIS", 0x01);
+    let header = CartHeader::from_rom(&rom).unwrap();
+    assert_eq!(header.title, b"TETRIS");
+    assert_eq!(header.cart_type, CartType::Mbc1);
+
+    assert_eq!(CartHeader::from_rom(&rom[..0x14F]), Err(HeaderParseError::RomTooShort(0x14F)));
+}
+
+#[test]
+fn test_mmm01_menu_header() {
+    // An MMM01 cartridge's first game has its own header, but the menu in the last 32 KB has the
+    // one which describes the cartridge.
+    let mut rom = vec![0; 0x20000];
+    write_header(&mut rom, 0, b"FIRST GAME", 0x01);
+    write_header(&mut rom, 0x18000, b"MENU", 0x0B);
+    let header = CartHeader::from_rom(&rom).unwrap();
+    assert_eq!(header.title, b"MENU");
+    assert_eq!(header.cart_type, CartType::Mmm01);
+
+    // A menu header with a bad checksum is ignored.
+    rom[0x1814D] ^= 1;
+    let header = CartHeader::from_rom(&rom).unwrap();
+    assert_eq!(header.title, b"FIRST GAME");
+    assert_eq!(header.cart_type, CartType::Mbc1);
+}
+
+#[test]
+fn test_mmm01_type_byte_without_header() {
+    // A normal ROM can have 0x0B at the same place near the end by chance, without the rest of a
+    // header around it.
+    let mut rom = vec![0; 0x20000];
+    write_header(&mut rom, 0, b"GAME", 0x01);
+    rom[0x18147] = 0x0B;
+    assert_eq!(CartHeader::from_rom(&rom).unwrap().cart_type, CartType::Mbc1);
+
+    // Or with the logo but not a matching checksum.
+    rom[0x18104..0x18134].copy_from_slice(&NINTENDO_LOGO);
+    assert_eq!(CartHeader::from_rom(&rom).unwrap().cart_type, CartType::Mbc1);
+}
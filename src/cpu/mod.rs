@@ -4,9 +4,11 @@ use crate::debug::Watch;
 use crate::gpu::Gpu;
 use crate::interrupts::Interrupt;
 use crate::joypad::Joypad;
+use crate::model::Model;
+use crate::sgb::Sgb;
 use crate::timer::Timer;
 use std::collections::HashSet;
-use sdl2::audio::AudioQueue;
+use std::rc::Rc;
 use enumflags2::BitFlags;
 use log::{debug, info, log_enabled, trace, warn};
 use self::inst::{Cond, Inst, Operand16, Operand8};
@@ -19,7 +21,11 @@ pub mod registers;
 mod test;
 
 // TODO: Refactor later to extract memory-related stuff out of the cpu module.
-const WORK_RAM_SIZE: usize = 8 * 1024; // 8 KB
+const WORK_RAM_BANK_SIZE: usize = 4 * 1024; // 4 KB
+const DMG_WORK_RAM_BANKS: usize = 2;
+const CGB_WORK_RAM_BANKS: usize = 8;
+const HDMA_BLOCK_SIZE: u16 = 16; // HDMA transfers happen in blocks of 16 bytes.
+const HDMA_BLOCK_CYCLES: usize = 32; // Each general purpose HDMA block halts the CPU for 32 cycles.
 const HIGH_RAM_SIZE: usize = 127; // For the address range 0xFF80-0xFFFE (inclusive).
 
 enum Dest {
@@ -34,10 +40,24 @@ pub struct Cpu {
     /// The core CPU registers.
     regs: Registers,
 
+    /// True if the CGB-only features are enabled. A CGB runs games without CGB support in a DMG
+    /// compatibility mode, which the boot ROM picks based on the cartridge header.
+    cgb_mode: bool,
+
+    /// The boot ROM, which is mapped over the start of the cartridge ROM until it is disabled by
+    /// writing to 0xFF50.
+    boot_rom: Option<Box<[u8]>>,
+
+    /// The last value written to the DMA register 0xFF46.
+    dma_source: u8,
+
     /// Work RAM internal to the Game Boy, as opposed to external cartridge RAM. Limited to 8 KB in
-    /// the original Game Boy.
+    /// the original Game Boy. The CGB has 32 KB in 8 banks of 4 KB.
     work_ram: Box<[u8]>,
 
+    /// The work RAM bank mapped at 0xD000-0xDFFF. (SVBK at 0xFF70, CGB only)
+    work_ram_bank: usize,
+
     /// High RAM internal to the Game Boy. This is a small range of 127 bytes at 0xFF80-0xFFFE.
     high_ram: Box<[u8]>,
 
@@ -47,6 +67,9 @@ pub struct Cpu {
     /// The graphics procession unit.
     pub gpu: Gpu,
 
+    /// The Super Game Boy, if running in SGB mode.
+    pub sgb: Option<Sgb>,
+
     /// The player controller hardware.
     pub joypad: Joypad,
 
@@ -90,21 +113,80 @@ pub struct Cpu {
     /// If the cpu is stopped
     stopped: bool,
 
+    /// True if the CPU is running at double speed. (Bit 7 of KEY1 at 0xFF4D, CGB only)
+    double_speed: bool,
+
+    /// True if the next STOP instruction should switch speeds. (Bit 0 of KEY1 at 0xFF4D, CGB only)
+    speed_switch_armed: bool,
+
+    /// The HDMA source address. (HDMA1 and HDMA2 at 0xFF51-0xFF52, CGB only)
+    hdma_source: u16,
+
+    /// The HDMA destination address, relative to the start of VRAM. (HDMA3 and HDMA4 at
+    /// 0xFF53-0xFF54, CGB only)
+    hdma_dest: u16,
+
+    /// The number of 16 byte blocks left to copy in the current HDMA transfer. (Bits 0-6 of HDMA5
+    /// at 0xFF55 hold this minus 1, CGB only)
+    hdma_blocks_left: u8,
+
+    /// True if an H-Blank DMA transfer is in progress, copying one block per H-Blank.
+    hdma_hblank_active: bool,
+
+    /// Allow the CPU to access VRAM and OAM during every LCD mode. Real hardware locks them while
+    /// the LCD is reading from them, but relaxing this can help when debugging homebrew.
+    pub unlocked_vram: bool,
+
+    /// Called with the new state each time a rumble cartridge turns its motor on or off.
+    pub rumble_callback: Option<Rc<dyn Fn(bool)>>,
+
+    /// Called with the new setting each time a HuC3 cartridge starts, changes or stops its tone.
+    pub tone_callback: Option<Rc<dyn Fn(Option<u8>)>>,
+
     /// Symbolic information for more detailed debug output.
     // TODO(solson): Should we find another place to store this?
     pub debug_symbols: Option<crate::wla_symbols::WlaSymbols>,
 }
 
 impl Cpu {
-    pub fn new(cart: Cart) -> Cpu {
-        Cpu {
-            regs: Registers::new(),
-            work_ram: vec![0; WORK_RAM_SIZE].into_boxed_slice(),
+    /// Create a CPU for the given model. If a boot ROM is given, execution starts at power on with
+    /// the boot ROM. Otherwise it starts with the state the boot ROM would have left behind.
+    pub fn new(cart: Cart, model: Model, boot_rom: Option<Box<[u8]>>) -> Cpu {
+        let cgb_hardware = model == Model::Cgb;
+        // The CGB boot ROM itself always runs in CGB mode.
+        let cgb_mode = cgb_hardware && (boot_rom.is_some() || supports_cgb(&cart));
+        let work_ram_banks = if cgb_hardware { CGB_WORK_RAM_BANKS } else { DMG_WORK_RAM_BANKS };
+
+        let (regs, timer, audio) = if boot_rom.is_some() {
+            (Registers::power_on(), Timer::new(), Audio::new(cgb_hardware))
+        } else {
+            let header_checksum = cart.rom().get(0x014D).copied().unwrap_or(0);
+            let regs = Registers::post_boot(model, header_checksum);
+            (regs, Timer::post_boot(model), Audio::post_boot(model))
+        };
+
+        let mut joypad = Joypad::new();
+        let sgb = if model == Model::Sgb {
+            joypad.enable_sgb();
+            Some(Sgb::new())
+        } else {
+            None
+        };
+
+        let mut cpu = Cpu {
+            regs,
+            cgb_mode,
+            boot_rom,
+            // DMA reads back 0xFF after the DMG boot ROM, but the CGB boot ROM clears it.
+            dma_source: if cgb_hardware { 0 } else { 0xFF },
+            work_ram: vec![0; work_ram_banks * WORK_RAM_BANK_SIZE].into_boxed_slice(),
+            work_ram_bank: 1,
             high_ram: vec![0; HIGH_RAM_SIZE].into_boxed_slice(),
-            timer: Timer::new(),
-            gpu: Gpu::new(),
-            joypad: Joypad::new(),
-            audio: Audio::new(),
+            timer,
+            gpu: Gpu::new(cgb_mode),
+            sgb,
+            joypad,
+            audio,
             cart,
             current_opcode: 0,
             cycles: 0,
@@ -116,25 +198,48 @@ impl Cpu {
             interrupt_enable_unused_bits: 0,
             halted: false,
             stopped: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma_blocks_left: 0,
+            hdma_hblank_active: false,
+            unlocked_vram: false,
+            rumble_callback: None,
+            tone_callback: None,
             debug_symbols: None,
+        };
+
+        if cpu.boot_rom.is_some() {
+            // The LCD starts off and the boot ROM turns it on.
+            cpu.gpu.write_reg(0x40, 0);
+            cpu.interrupt_flags_register = BitFlags::empty();
+        } else {
+            cpu.gpu.write_reg(0x47, 0xFC);
         }
+
+        cpu
     }
 
     /// Keep executing instructions until more than the given number of cycles have passed.
     /// Returns true if we have hit a watch.
-    pub fn step_cycles(&mut self, cycles: usize, audio_queue: &mut AudioQueue<u8>, watches: &HashSet<Watch>) -> bool {
+    pub fn step_cycles(&mut self, cycles: usize, watches: &HashSet<Watch>) -> bool {
         let mut curr_cycles: usize = 0;
         let check_watches = watches.len() > 0;
         while curr_cycles < cycles {
             let mut interrupts = BitFlags::empty();
             match self.step(false, check_watches, watches) {
                 Some(step_cycles) => {
-                    self.audio.step(step_cycles, audio_queue);
-                    interrupts |= self.gpu.step(step_cycles);
+                    let hardware_cycles = self.hardware_cycles(step_cycles);
+                    self.audio.step(hardware_cycles);
+                    self.cart.step(hardware_cycles);
+                    interrupts |= self.step_gpu(hardware_cycles);
                     interrupts |= self.timer.step(step_cycles);
+                    self.step_frame_sequencer();
                     interrupts |= self.joypad.step();
+                    self.step_hblank_dma();
                     self.request_interrupts(interrupts);
-                    curr_cycles += step_cycles;
+                    curr_cycles += hardware_cycles;
                 },
                 None => return true,
             }
@@ -149,9 +254,12 @@ impl Cpu {
             let mut interrupts = BitFlags::empty();
             match self.step(true, check_watches, watches) {
                 Some(step_cycles) => {
-                    interrupts |= self.gpu.step(step_cycles);
+                    self.cart.step(self.hardware_cycles(step_cycles));
+                    interrupts |= self.step_gpu(self.hardware_cycles(step_cycles));
                     interrupts |= self.timer.step(step_cycles);
+                    self.step_frame_sequencer();
                     interrupts |= self.joypad.step();
+                    self.step_hblank_dma();
                     self.request_interrupts(interrupts);
                 },
                 None => break,
@@ -159,6 +267,35 @@ impl Cpu {
         }
     }
 
+    /// Convert a number of CPU cycles to the number of cycles which pass for the rest of the
+    /// hardware. In double speed mode the CPU and timer run twice as fast as everything else.
+    fn hardware_cycles(&self, cpu_cycles: usize) -> usize {
+        if self.double_speed { cpu_cycles / 2 } else { cpu_cycles }
+    }
+
+    /// Step the GPU, and pass each finished frame on to the SGB.
+    fn step_gpu(&mut self, cycles: usize) -> BitFlags<Interrupt> {
+        let interrupts = self.gpu.step(cycles);
+        if interrupts.contains(Interrupt::VBlank) {
+            if let Some(sgb) = &mut self.sgb {
+                sgb.frame_finished(&self.gpu.screen_buffer);
+            }
+        }
+        interrupts
+    }
+
+    /// Clock the APU frame sequencer for each time the timer's divider bit for it has fallen.
+    fn step_frame_sequencer(&mut self) {
+        for _ in 0..self.timer.take_frame_sequencer_clocks() {
+            self.audio.step_frame_sequencer();
+        }
+    }
+
+    /// Returns true if the CGB-only features are enabled.
+    fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
     /// Execute a single instruction. Returns how many cycles it took and None if a watch is hit.
     fn step(&mut self, print_instr: bool, check_watches: bool, watches: &HashSet<Watch>) -> Option<usize> {
         let pending_enable_interrupts = self.pending_enable_interrupts;
@@ -205,7 +342,12 @@ impl Cpu {
         }
         self.regs.pc += instruction_len as u16;
 
+        // Executing the instruction may add more cycles, e.g. for conditional jumps which are
+        // taken or for general purpose HDMA transfers.
+        let start_cycles = self.cycles;
+        self.cycles += cycles;
         self.execute(inst);
+        let cycles = self.cycles - start_cycles;
 
         if pending_enable_interrupts {
             self.interrupts_enabled = true;
@@ -253,7 +395,7 @@ impl Cpu {
     fn execute(&mut self, inst: Inst) {
         match inst {
             Inst::Nop => {}
-            Inst::Stop => self.stopped = true,
+            Inst::Stop => self.stop(),
             Inst::Halt => self.halted = true,
             Inst::Di => self.pending_disable_interrupts = true,
             Inst::Ei => self.pending_enable_interrupts = true,
@@ -308,6 +450,20 @@ impl Cpu {
         }
     }
 
+    /// The `Inst::Stop` instruction.
+    ///
+    /// On the CGB, this switches between normal and double speed if a switch was armed via KEY1.
+    fn stop(&mut self) {
+        if self.cgb_mode() && self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            self.double_speed = !self.double_speed;
+            self.timer.set_double_speed(self.double_speed);
+            info!("Switched to {} speed", if self.double_speed { "double" } else { "normal" });
+        } else {
+            self.stopped = true;
+        }
+    }
+
     /// The `Inst::Jp` instruction.
     ///
     /// Jump to the specified address if the condition is met.
@@ -832,18 +988,32 @@ impl Cpu {
         }
     }
 
+    /// Read memory the way the debugger sees it, bypassing the VRAM and OAM locks so the true
+    /// contents are always visible.
     pub fn read_mem_debug(&self, addr: u16) -> u8 {
-        self.read_mem(addr)
+        match addr {
+            0x8000..=0x9FFF => self.gpu.read_vram((addr - 0x8000) as usize),
+            0xFE00..=0xFE9F => self.gpu.read_sprite_ram((addr - 0xFE00) as usize),
+            _ => self.read_mem(addr),
+        }
     }
 
     fn read_mem(&self, addr: u16) -> u8 {
         let val = match addr {
             // First 16KB is ROM Bank 00 (in cartridge, fixed at bank 00)
             // Second 16KB are ROM Banks 01..NN (in cartridge, switchable bank number)
-            0x0000..=0x7FFF => self.cart.read(addr),
+            //
+            // While the boot ROM is mapped it hides the start of the cartridge ROM.
+            0x0000..=0x7FFF => match self.read_boot_rom(addr) {
+                Some(val) => val,
+                None => self.cart.read(addr),
+            },
 
             // 8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
+            //
+            // VRAM is inaccessible during mode 3, when reads return 0xFF.
             0x8000..=0x9FFF => {
+                if !self.unlocked_vram && !self.gpu.vram_accessible() { return 0xFF; }
                 let i = (addr - 0x8000) as usize;
                 self.gpu.read_vram(i)
             }
@@ -852,20 +1022,25 @@ impl Cpu {
             0xA000..=0xBFFF => self.cart.read(addr),
 
             // C000-CFFF: 4KB Work RAM Bank 0 (WRAM)
-            // D000-DFFF: 4KB Work RAM Bank 1 (WRAM) (switchable bank 1-7 in CGB Mode)
-            //
-            // NOTE: Since we don't support CGB mode yet, there is no switching and we handle this
-            // like a contiguous 8KB block.
-            0xC000..=0xDFFF => {
+            0xC000..=0xCFFF => {
                 let i = (addr - 0xC000) as usize;
                 self.work_ram[i]
             }
 
+            // D000-DFFF: 4KB Work RAM Bank 1 (WRAM) (switchable bank 1-7 in CGB Mode)
+            0xD000..=0xDFFF => {
+                let i = (addr - 0xD000) as usize;
+                self.work_ram[self.work_ram_bank * WORK_RAM_BANK_SIZE + i]
+            }
+
             // Same as C000-DDFF (ECHO) (typically not used)
             0xE000..=0xFDFF => self.read_mem(addr - 0xE000 + 0xC000),
 
             // Sprite Attribute Table (OAM)
+            //
+            // OAM is inaccessible during modes 2 and 3, when reads return 0xFF.
             0xFE00..=0xFE9F => {
+                if !self.unlocked_vram && !self.gpu.oam_accessible() { return 0xFF; }
                 let i = (addr - 0xFE00) as usize;
                 self.gpu.read_sprite_ram(i)
             }
@@ -903,32 +1078,41 @@ impl Cpu {
 
         match addr {
             // 32KB cartridge write
-            0x0000..=0x7FFF => self.cart.write(addr, val),
+            0x0000..=0x7FFF => self.write_cart(addr, val),
 
             // 8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
+            //
+            // Writes are ignored during mode 3.
             0x8000..=0x9FFF => {
+                if !self.unlocked_vram && !self.gpu.vram_accessible() { return; }
                 let i = (addr - 0x8000) as usize;
                 self.gpu.write_vram(i, val);
             }
 
             // 8KB External RAM (in cartridge, switchable bank, if any)
-            0xA000..=0xBFFF => self.cart.write(addr, val),
+            0xA000..=0xBFFF => self.write_cart(addr, val),
 
             // C000-CFFF: 4KB Work RAM Bank 0 (WRAM)
-            // D000-DFFF: 4KB Work RAM Bank 1 (WRAM) (switchable bank 1-7 in CGB Mode)
-            //
-            // NOTE: Since we don't support CGB mode yet, there is no switching and we handle this
-            // like a contiguous 8KB block.
-            0xC000..=0xDFFF => {
+            0xC000..=0xCFFF => {
                 let i = (addr - 0xC000) as usize;
                 self.work_ram[i] = val;
             }
 
+            // D000-DFFF: 4KB Work RAM Bank 1 (WRAM) (switchable bank 1-7 in CGB Mode)
+            0xD000..=0xDFFF => {
+                let i = (addr - 0xD000) as usize;
+                self.work_ram[self.work_ram_bank * WORK_RAM_BANK_SIZE + i] = val;
+            }
+
             // Same as C000-DDFF (ECHO) (typically not used)
             0xE000..=0xFDFF => self.write_mem(addr - 0xE000 + 0xC000, val),
 
-            // Sprite Attribute Table (OAM). TODO: Can only write during H-Blank or V-Blank phase
+            // Sprite Attribute Table (OAM)
+            //
+            // Writes are ignored during modes 2 and 3, so OAM can only be written during H-Blank
+            // or V-Blank.
             0xFE00..=0xFE9F => {
+                if !self.unlocked_vram && !self.gpu.oam_accessible() { return; }
                 let i = (addr - 0xFE00) as usize;
                 self.gpu.write_sprite_ram(i, val);
             }
@@ -968,6 +1152,34 @@ impl Cpu {
         self.write_mem(addr.wrapping_add(1), high);
     }
 
+    /// Write to the cartridge, and let the frontend know if that turned the rumble motor or the
+    /// HuC3 tone on or off.
+    fn write_cart(&mut self, addr: u16, val: u8) {
+        let motor_on = self.cart.motor_on();
+        let tone = self.cart.huc3_tone();
+        self.cart.write(addr, val);
+        if self.cart.motor_on() != motor_on {
+            if let Some(callback) = &self.rumble_callback {
+                callback(!motor_on);
+            }
+        }
+        if self.cart.huc3_tone() != tone {
+            if let Some(callback) = &self.tone_callback {
+                callback(self.cart.huc3_tone());
+            }
+        }
+    }
+
+    /// Read from the boot ROM, or return None if it doesn't cover the given address. The
+    /// cartridge header at 0x0100-0x01FF always shows through.
+    fn read_boot_rom(&self, addr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        if (0x0100..0x0200).contains(&addr) {
+            return None;
+        }
+        boot_rom.get(addr as usize).copied()
+    }
+
     fn read_io_port(&self, port: u8) -> u8 {
         match port {
             0x00 => self.joypad.read_reg(),
@@ -981,10 +1193,27 @@ impl Cpu {
             0x10..=0x14 | 0x16..=0x19 | 0x1A..=0x1E | 0x20..=0x26 | 0x30..=0x3F =>
                 self.audio.read_reg(port),
             0x40..=0x45 | 0x47..=0x4B => self.gpu.read_reg(port),
+            0x4F | 0x68..=0x6C if self.cgb_mode() => self.gpu.read_reg(port),
 
-            // Cannot read from DMA transfer register.
-            // TODO(solson): Should it return 0xFF like most other inaccessible registers?
-            0x46 => 0,
+            // CGB speed switch. The unused bits are always 1.
+            0x4D if self.cgb_mode() => {
+                (self.double_speed as u8) << 7 | 0b0111_1110 | self.speed_switch_armed as u8
+            }
+
+            // CGB HDMA. Only the length/mode/start register can be read.
+            0x51..=0x54 if self.cgb_mode() => 0xFF,
+            0x55 if self.cgb_mode() => {
+                // Bit 7 is 0 while an H-Blank transfer is in progress. After a transfer completes
+                // this reads 0xFF.
+                (!self.hdma_hblank_active as u8) << 7
+                    | self.hdma_blocks_left.wrapping_sub(1) & 0b0111_1111
+            }
+
+            // CGB work RAM bank. The unused bits are always 1.
+            0x70 if self.cgb_mode() => 0b1111_1000 | self.work_ram_bank as u8,
+
+            // The DMA transfer register reads back the last value written.
+            0x46 => self.dma_source,
 
             // Unmapped I/O ports always return all bits high.
             0x03 | 0x08..=0x0E | 0x15 | 0x1F | 0x27..=0x2F | 0x4C..=0x7F => 0xFF,
@@ -995,13 +1224,37 @@ impl Cpu {
 
     fn write_io_port(&mut self, port: u8, val: u8) {
         match port {
-            0x00 => self.joypad.write_reg(val),
+            0x00 => {
+                self.joypad.write_reg(val);
+                if let (Some(sgb), Some(packet)) = (&mut self.sgb, self.joypad.take_sgb_packet()) {
+                    sgb.receive_packet(packet, &mut self.joypad);
+                }
+            }
             0x01..=0x02 => warn!("unimplemented write to serial I/O port FF{:02X}", port),
-            0x04..=0x07 => self.timer.write_reg(port, val),
+            0x04..=0x07 => {
+                let interrupts = self.timer.write_reg(port, val);
+                // Resetting the divider can clock the frame sequencer too.
+                self.step_frame_sequencer();
+                self.request_interrupts(interrupts);
+            }
             0x0F => self.interrupt_flags_register = BitFlags::from_bits_truncate(val),
             0x10..=0x14 | 0x16..=0x19 | 0x1A..=0x1E | 0x20..=0x26 | 0x30..=0x3F =>
                 self.audio.write_reg(port, val),
             0x40..=0x45 | 0x47..=0x4B => self.gpu.write_reg(port, val),
+            0x4F | 0x68..=0x6C if self.cgb_mode() => self.gpu.write_reg(port, val),
+
+            // CGB speed switch. Only bit 0 is writable, and the switch happens on the next STOP.
+            0x4D if self.cgb_mode() => self.speed_switch_armed = val & 1 == 1,
+
+            // CGB HDMA
+            0x51 if self.cgb_mode() => self.hdma_source = (self.hdma_source & 0x00FF) | (val as u16) << 8,
+            0x52 if self.cgb_mode() => self.hdma_source = (self.hdma_source & 0xFF00) | (val & 0xF0) as u16,
+            0x53 if self.cgb_mode() => self.hdma_dest = (self.hdma_dest & 0x00FF) | ((val & 0x1F) as u16) << 8,
+            0x54 if self.cgb_mode() => self.hdma_dest = (self.hdma_dest & 0xFF00) | (val & 0xF0) as u16,
+            0x55 if self.cgb_mode() => self.start_hdma(val),
+
+            // CGB work RAM bank. Selecting bank 0 selects bank 1.
+            0x70 if self.cgb_mode() => self.work_ram_bank = ((val & 0b111) as usize).max(1),
 
             // DMA Transfer - Takes 160 microseconds to complete. During this time, only HRAM can
             // be accessed.
@@ -1009,9 +1262,24 @@ impl Cpu {
             // over time rather than in a single instant.
             0x46 => {
                 info!("DMA TRANSFER START");
+                self.dma_source = val;
                 let start_addr: u16 = val as u16 * 0x100; // Addresses are from 0xXX00 - 0xXX9F
+                // The DMA unit writes OAM directly, so it isn't subject to the OAM lock.
                 for i in 0..0xA0 {
-                    self.write_mem(0xFE00 + i, self.read_mem(start_addr + i))
+                    self.gpu.write_sprite_ram(i as usize, self.read_mem(start_addr + i))
+                }
+            }
+
+            // Disable the boot ROM. It can't be enabled again until the next power on.
+            0x50 => {
+                if val != 0 && self.boot_rom.is_some() {
+                    info!("Boot ROM disabled");
+                    self.boot_rom = None;
+                    if self.cgb_mode && !supports_cgb(&self.cart) {
+                        info!("Switching to DMG compatibility mode");
+                        self.cgb_mode = false;
+                        self.gpu.enter_dmg_compat_mode();
+                    }
                 }
             }
 
@@ -1022,6 +1290,50 @@ impl Cpu {
         }
     }
 
+    /// Handle a write to HDMA5, which starts or cancels an HDMA transfer.
+    fn start_hdma(&mut self, val: u8) {
+        if self.hdma_hblank_active && (val >> 7) & 1 == 0 {
+            // Writing with bit 7 clear during an H-Blank transfer cancels it.
+            self.hdma_hblank_active = false;
+            return;
+        }
+
+        self.hdma_blocks_left = (val & 0b0111_1111) + 1;
+        if (val >> 7) & 1 == 1 {
+            // H-Blank DMA copies one block at the start of each H-Blank.
+            self.hdma_hblank_active = true;
+        } else {
+            // General purpose DMA copies everything at once while the CPU is halted.
+            let blocks = self.hdma_blocks_left as usize;
+            while self.hdma_blocks_left > 0 {
+                self.copy_hdma_block();
+            }
+            let cycles_per_block = if self.double_speed { HDMA_BLOCK_CYCLES * 2 } else { HDMA_BLOCK_CYCLES };
+            self.cycles += blocks * cycles_per_block;
+        }
+    }
+
+    /// Copy the next block of an H-Blank DMA transfer if the LCD just entered H-Blank.
+    fn step_hblank_dma(&mut self) {
+        if self.gpu.take_entered_hblank() && self.hdma_hblank_active && !self.halted {
+            self.copy_hdma_block();
+            if self.hdma_blocks_left == 0 {
+                self.hdma_hblank_active = false;
+            }
+        }
+    }
+
+    /// Copy one 16 byte HDMA block into the current VRAM bank.
+    fn copy_hdma_block(&mut self) {
+        for _ in 0..HDMA_BLOCK_SIZE {
+            let val = self.read_mem(self.hdma_source);
+            self.gpu.write_vram((self.hdma_dest & 0x1FFF) as usize, val);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = self.hdma_dest.wrapping_add(1);
+        }
+        self.hdma_blocks_left -= 1;
+    }
+
     fn is_watch_hit(&self, inst: Inst, watches: &HashSet<Watch>) -> bool {
         match inst {
             Inst::Ld8(n, _) | Inst::Inc8(n) | Inst::Dec8(n) | Inst::Rlc(n) | Inst::Rl(n)
@@ -1122,6 +1434,11 @@ impl Cpu {
     }
 }
 
+/// Returns true if the cartridge header's CGB flag (bit 7 of 0x0143) is set.
+fn supports_cgb(cart: &Cart) -> bool {
+    cart.rom().get(0x0143).copied().unwrap_or(0) & 0x80 != 0
+}
+
 /// Returns true if `left + right` should set the half-carry flag, i.e. it requires a carry
 /// from bit 3 into bit 4.
 fn get_add_half_carry(left: u8, right: u8) -> bool {
@@ -1,3 +1,4 @@
+use crate::model::Model;
 use enumflags2::BitFlags;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -92,13 +93,44 @@ pub struct Registers {
 }
 
 impl Registers {
-    pub fn new() -> Self {
+    /// The registers at power on, before the boot ROM runs.
+    pub fn power_on() -> Self {
         Self {
-            a: 0x01,
-            f: Flag::Carry | Flag::HalfCarry | Flag::Zero,
-            bc: Register(0x0013),
-            de: Register(0x00D8),
-            hl: Register(0x014D),
+            a: 0,
+            f: BitFlags::empty(),
+            bc: Register(0),
+            de: Register(0),
+            hl: Register(0),
+            sp: Register(0),
+            pc: Register(0),
+        }
+    }
+
+    /// The registers the given model's boot ROM leaves behind when it jumps to the cartridge.
+    /// Games use these to tell which model they are running on.
+    pub fn post_boot(model: Model, header_checksum: u8) -> Self {
+        let (a, f, bc, de, hl) = match model {
+            Model::Dmg0 => (0x01, BitFlags::empty(), 0xFF13, 0x00C1, 0x8403),
+            Model::Dmg | Model::Mgb => {
+                // The DMG boot ROM leaves H and C set unless the header checksum is 0.
+                let f = if header_checksum == 0 {
+                    BitFlags::from(Flag::Zero)
+                } else {
+                    Flag::Carry | Flag::HalfCarry | Flag::Zero
+                };
+                let a = if model == Model::Mgb { 0xFF } else { 0x01 };
+                (a, f, 0x0013, 0x00D8, 0x014D)
+            }
+            Model::Sgb => (0x01, BitFlags::empty(), 0x0014, 0x0000, 0xC060),
+            Model::Cgb => (0x11, BitFlags::from(Flag::Zero), 0x0000, 0xFF56, 0x000D),
+        };
+
+        Self {
+            a,
+            f,
+            bc: Register(bc),
+            de: Register(de),
+            hl: Register(hl),
             sp: Register(0xFFFE),
             pc: Register(0x0100),
         }
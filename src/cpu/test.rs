@@ -3,13 +3,16 @@ use std::fmt::{Debug, UpperHex, Write};
 use std::mem;
 use std::collections::HashSet;
 use super::*;
+use crate::cart::CartConfig;
+use crate::cart_header::{CartHardware, CartType};
+use crate::ir::IrLoopback;
+use std::cell::RefCell;
 
 fn setup(rom: Vec<u8>) -> (Cpu, Cpu) {
-    use crate::cart::CartConfig;
-    use crate::cart_header::CartType;
     let rom_size = rom.len();
-    let cart_config = CartConfig { cart_type: CartType::NoMbc, rom_size, ram_size: 0 };
-    let mut actual = Cpu::new(Cart::new(rom.into_boxed_slice(), None, &cart_config).unwrap());
+    let cart_config = CartConfig { cart_type: CartType::NoMbc, hardware: BitFlags::empty(), rom_size, ram_size: 0, multicart: None };
+    let cart = Cart::new(rom.into_boxed_slice(), None, &cart_config).unwrap();
+    let mut actual = Cpu::new(cart, Model::Dmg, None);
     let mut expected = actual.clone();
     actual.regs.pc.set(0);
     expected.regs.pc.set(rom_size as u16);
@@ -226,3 +229,379 @@ cpu_tests! {
         ],
     }
 }
+
+/// Run `code` from address 0 of a cartridge of the given type, until it runs off the end.
+/// `setup` is called on the CPU first.
+fn run_cart_code(
+    cart_type: CartType,
+    hardware: BitFlags<CartHardware>,
+    ram_size: usize,
+    code: &[u8],
+    setup: impl FnOnce(&mut Cpu),
+) -> Cpu {
+    let mut rom = vec![0; 0x8000];
+    rom[..code.len()].copy_from_slice(code);
+    let cart_config = CartConfig {
+        cart_type,
+        hardware,
+        rom_size: rom.len(),
+        ram_size,
+        multicart: None,
+    };
+    let cart = Cart::new(rom.into_boxed_slice(), None, &cart_config).unwrap();
+    let mut cpu = Cpu::new(cart, Model::Dmg, None);
+    cpu.regs.pc.set(0);
+    setup(&mut cpu);
+
+    while cpu.regs.pc.get() as usize != code.len() {
+        cpu.step(false, false, &HashSet::new());
+    }
+    cpu
+}
+
+#[test]
+fn test_rumble_callback() {
+    let code = [
+        0x3E, 0x08,       // ld a, 0x08
+        0xEA, 0x00, 0x40, // ld (0x4000), a ; Motor on
+        0xEA, 0x00, 0x40, // ld (0x4000), a ; No change
+        0xAF,             // xor a
+        0xEA, 0x00, 0x40, // ld (0x4000), a ; Motor off
+    ];
+    let states = Rc::new(RefCell::new(Vec::new()));
+    let callback_states = states.clone();
+    run_cart_code(CartType::Mbc5, CartHardware::Rumble.into(), 0, &code, |cpu| {
+        cpu.rumble_callback = Some(Rc::new(move |on| callback_states.borrow_mut().push(on)));
+    });
+    assert_eq!(*states.borrow(), vec![true, false]);
+}
+
+#[test]
+fn test_tone_callback() {
+    let code = [
+        0x3E, 0x0B,       // ld a, 0x0B
+        0xEA, 0x00, 0x00, // ld (0x0000), a ; Map the command register
+        0x3E, 0x6E,       // ld a, 0x6E
+        0xEA, 0x00, 0xA0, // ld (0xA000), a ; Tone E on
+        0xEA, 0x00, 0xA0, // ld (0xA000), a ; No change
+        0x3E, 0x10,       // ld a, 0x10
+        0xEA, 0x00, 0xA0, // ld (0xA000), a ; A read, which doesn't change the tone
+        0x3E, 0x60,       // ld a, 0x60
+        0xEA, 0x00, 0xA0, // ld (0xA000), a ; Tone off
+    ];
+    let tones = Rc::new(RefCell::new(Vec::new()));
+    let callback_tones = tones.clone();
+    run_cart_code(CartType::HuC3, CartHardware::Ram | CartHardware::Battery, 0x2000, &code, |cpu| {
+        cpu.tone_callback = Some(Rc::new(move |tone| callback_tones.borrow_mut().push(tone)));
+    });
+    assert_eq!(*tones.borrow(), vec![Some(0xE), None]);
+}
+
+#[test]
+fn test_ir_loopback() {
+    let code = [
+        0x3E, 0x0E,       // ld a, 0x0E
+        0xEA, 0x00, 0x00, // ld (0x0000), a ; Map the IR port
+        0xFA, 0x00, 0xA0, // ld a, (0xA000) ; No light
+        0x47,             // ld b, a
+        0x3E, 0x01,       // ld a, 0x01
+        0xEA, 0x00, 0xA0, // ld (0xA000), a ; LED on
+        0xFA, 0x00, 0xA0, // ld a, (0xA000) ; Sees its own light
+    ];
+    let cpu = run_cart_code(CartType::HuC1, CartHardware::Ram | CartHardware::Battery, 0x2000, &code, |cpu| {
+        cpu.cart.set_ir_port(Rc::new(RefCell::new(IrLoopback::new())));
+    });
+    assert_eq!(cpu.regs.get_8(Reg8::B), 0xC0);
+    assert_eq!(cpu.regs.get_8(Reg8::A), 0xC1);
+}
+
+#[test]
+fn test_vram_and_oam_locks() {
+    let (mut cpu, _) = setup(vec![0; 0x8000]);
+    cpu.write_mem(0xFF40, 0x00);
+    cpu.write_mem(0x8000, 0x12);
+    cpu.write_mem(0xFE00, 0x34);
+    cpu.write_mem(0xFF40, 0x91);
+
+    // In mode 2, OAM reads return 0xFF and writes are dropped.
+    assert_eq!(cpu.read_mem(0x8000), 0x12);
+    assert_eq!(cpu.read_mem(0xFE00), 0xFF);
+    cpu.write_mem(0xFE00, 0x56);
+
+    // In mode 3, the same goes for VRAM.
+    cpu.gpu.step(81);
+    assert_eq!(cpu.read_mem(0x8000), 0xFF);
+    assert_eq!(cpu.read_mem(0xFE00), 0xFF);
+    cpu.write_mem(0x8000, 0x78);
+
+    // The debugger sees through the locks.
+    assert_eq!(cpu.read_mem_debug(0x8000), 0x12);
+    assert_eq!(cpu.read_mem_debug(0xFE00), 0x34);
+
+    // So does the CPU with --unlocked-vram.
+    cpu.unlocked_vram = true;
+    assert_eq!(cpu.read_mem(0x8000), 0x12);
+    cpu.write_mem(0x8000, 0x9A);
+    assert_eq!(cpu.read_mem(0x8000), 0x9A);
+    cpu.unlocked_vram = false;
+
+    // With the LCD off, both are accessible.
+    cpu.write_mem(0xFF40, 0x00);
+    assert_eq!(cpu.read_mem(0x8000), 0x9A);
+    cpu.write_mem(0xFE00, 0xBC);
+    assert_eq!(cpu.read_mem(0xFE00), 0xBC);
+}
+
+/// A CPU for the given model, running a cartridge with no MBC.
+fn model_cpu(rom: Vec<u8>, model: Model, boot_rom: Option<Box<[u8]>>) -> Cpu {
+    let cart_config = CartConfig { cart_type: CartType::NoMbc, hardware: BitFlags::empty(), rom_size: rom.len(), ram_size: 0, multicart: None };
+    let cart = Cart::new(rom.into_boxed_slice(), None, &cart_config).unwrap();
+    Cpu::new(cart, model, boot_rom)
+}
+
+/// A CPU running a CGB-only cartridge as a CGB.
+fn cgb_cpu() -> Cpu {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0xC0;
+    model_cpu(rom, Model::Cgb, None)
+}
+
+/// Fill work RAM from 0xC000 with a counting pattern and point HDMA from there to 0x8000.
+fn setup_hdma(cpu: &mut Cpu) {
+    for i in 0..0x40 {
+        cpu.write_mem(0xC000 + i, i as u8 + 1);
+    }
+    cpu.write_mem(0xFF51, 0xC0);
+    cpu.write_mem(0xFF52, 0x00);
+    cpu.write_mem(0xFF53, 0x80);
+    cpu.write_mem(0xFF54, 0x00);
+}
+
+#[test]
+fn test_general_purpose_hdma() {
+    let mut cpu = cgb_cpu();
+    setup_hdma(&mut cpu);
+
+    // Copy 2 blocks at once, which takes the CPU's time.
+    let cycles = cpu.cycles;
+    cpu.write_mem(0xFF55, 0x01);
+    assert_eq!(cpu.cycles - cycles, 2 * HDMA_BLOCK_CYCLES);
+    assert_eq!(cpu.read_mem(0xFF55), 0xFF);
+    for i in 0..0x20 {
+        assert_eq!(cpu.read_mem_debug(0x8000 + i), i as u8 + 1);
+    }
+    assert_eq!(cpu.read_mem_debug(0x8020), 0x00);
+}
+
+#[test]
+fn test_hblank_hdma() {
+    let mut cpu = cgb_cpu();
+    setup_hdma(&mut cpu);
+    cpu.write_mem(0xFF40, 0x00);
+    cpu.write_mem(0xFF40, 0x91);
+
+    // 3 blocks, one per H-Blank. Bit 7 reads 0 while the transfer is active.
+    cpu.write_mem(0xFF55, 0x82);
+    assert_eq!(cpu.read_mem(0xFF55), 0x02);
+    assert_eq!(cpu.read_mem_debug(0x8000), 0x00);
+
+    let next_hblank = |cpu: &mut Cpu| {
+        cpu.gpu.step(81);
+        cpu.gpu.step(173);
+        cpu.step_hblank_dma();
+        // Nothing more is copied until the next H-Blank.
+        cpu.step_hblank_dma();
+        cpu.gpu.step(204);
+    };
+    next_hblank(&mut cpu);
+    assert_eq!(cpu.read_mem(0xFF55), 0x01);
+    assert_eq!(cpu.read_mem_debug(0x800F), 0x10);
+    assert_eq!(cpu.read_mem_debug(0x8010), 0x00);
+
+    next_hblank(&mut cpu);
+    assert_eq!(cpu.read_mem(0xFF55), 0x00);
+    assert_eq!(cpu.read_mem_debug(0x801F), 0x20);
+
+    // Writing with bit 7 clear cancels the last block.
+    cpu.write_mem(0xFF55, 0x00);
+    assert_eq!(cpu.read_mem(0xFF55), 0x80);
+    next_hblank(&mut cpu);
+    assert_eq!(cpu.read_mem_debug(0x8020), 0x00);
+}
+
+#[test]
+fn test_speed_switch() {
+    let mut cpu = cgb_cpu();
+    assert_eq!(cpu.read_mem(0xFF4D), 0x7E);
+
+    // STOP without preparing a switch just stops.
+    cpu.stop();
+    assert!(cpu.stopped);
+    cpu.stopped = false;
+
+    cpu.write_mem(0xFF4D, 0x01);
+    assert_eq!(cpu.read_mem(0xFF4D), 0x7F);
+    cpu.stop();
+    assert!(!cpu.stopped);
+    assert_eq!(cpu.read_mem(0xFF4D), 0xFE);
+    assert_eq!(cpu.hardware_cycles(8), 4);
+
+    cpu.write_mem(0xFF4D, 0x01);
+    cpu.stop();
+    assert_eq!(cpu.read_mem(0xFF4D), 0x7E);
+
+    // The DMG has no KEY1.
+    let (mut dmg, _) = setup(vec![0; 0x8000]);
+    dmg.write_mem(0xFF4D, 0x01);
+    assert_eq!(dmg.read_mem(0xFF4D), 0xFF);
+}
+
+#[test]
+fn test_work_ram_banks() {
+    let mut cpu = cgb_cpu();
+    cpu.write_mem(0xC000, 0xC0);
+    cpu.write_mem(0xD000, 0x01);
+    cpu.write_mem(0xFF70, 0x02);
+    assert_eq!(cpu.read_mem(0xFF70), 0xFA);
+    assert_eq!(cpu.read_mem(0xD000), 0x00);
+    cpu.write_mem(0xD000, 0x02);
+
+    // Bank 0 selects bank 1, and 0xC000-0xCFFF is always bank 0.
+    cpu.write_mem(0xFF70, 0x00);
+    assert_eq!(cpu.read_mem(0xFF70), 0xF9);
+    assert_eq!(cpu.read_mem(0xD000), 0x01);
+    assert_eq!(cpu.read_mem(0xC000), 0xC0);
+    cpu.write_mem(0xFF70, 0x0A);
+    assert_eq!(cpu.read_mem(0xD000), 0x02);
+}
+
+#[test]
+fn test_vram_banks() {
+    let mut cpu = cgb_cpu();
+    cpu.write_mem(0xFF40, 0x00);
+    cpu.write_mem(0x8000, 0x11);
+    cpu.write_mem(0xFF4F, 0x01);
+    assert_eq!(cpu.read_mem(0xFF4F), 0xFF);
+    assert_eq!(cpu.read_mem(0x8000), 0x00);
+    cpu.write_mem(0x8000, 0x22);
+    cpu.write_mem(0xFF4F, 0x00);
+    assert_eq!(cpu.read_mem(0xFF4F), 0xFE);
+    assert_eq!(cpu.read_mem(0x8000), 0x11);
+}
+
+#[test]
+fn test_post_boot_registers() {
+    let regs_with_checksum = |model, header_checksum| {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = header_checksum;
+        let cpu = model_cpu(rom, model, None);
+        let r = &cpu.regs;
+        [r.get_16(Reg16::AF), r.bc.get(), r.de.get(), r.hl.get(), r.sp.get(), r.pc.get()]
+    };
+    let regs = |model| regs_with_checksum(model, 0x42);
+    assert_eq!(regs(Model::Dmg0), [0x0100, 0xFF13, 0x00C1, 0x8403, 0xFFFE, 0x0100]);
+    assert_eq!(regs(Model::Dmg), [0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100]);
+    assert_eq!(regs(Model::Mgb), [0xFFB0, 0x0013, 0x00D8, 0x014D, 0xFFFE, 0x0100]);
+    assert_eq!(regs(Model::Sgb), [0x0100, 0x0014, 0x0000, 0xC060, 0xFFFE, 0x0100]);
+    assert_eq!(regs(Model::Cgb), [0x1180, 0x0000, 0xFF56, 0x000D, 0xFFFE, 0x0100]);
+
+    // The DMG boot ROM only leaves H and C set if the header checksum isn't 0.
+    assert_eq!(regs_with_checksum(Model::Dmg, 0)[0], 0x0180);
+}
+
+#[test]
+fn test_boot_rom() {
+    let mut rom = vec![0x55; 0x8000];
+    rom[0x100] = 0x66;
+    let mut cpu = model_cpu(rom, Model::Dmg, Some(vec![0xAA; 0x100].into_boxed_slice()));
+    assert_eq!(cpu.regs.pc.get(), 0);
+    assert_eq!(cpu.read_mem(0x0000), 0xAA);
+    assert_eq!(cpu.read_mem(0x00FF), 0xAA);
+    assert_eq!(cpu.read_mem(0x0100), 0x66);
+
+    // Writing 0 to 0xFF50 leaves the boot ROM mapped.
+    cpu.write_mem(0xFF50, 0);
+    assert_eq!(cpu.read_mem(0x0000), 0xAA);
+    cpu.write_mem(0xFF50, 1);
+    assert_eq!(cpu.read_mem(0x0000), 0x55);
+    assert_eq!(cpu.read_mem(0x00FF), 0x55);
+
+    // The CGB boot ROM is split around the cartridge header.
+    let mut cpu = model_cpu(vec![0x55; 0x8000], Model::Cgb, Some(vec![0xAA; 0x900].into_boxed_slice()));
+    assert_eq!(cpu.read_mem(0x00FF), 0xAA);
+    assert_eq!(cpu.read_mem(0x0100), 0x55);
+    assert_eq!(cpu.read_mem(0x01FF), 0x55);
+    assert_eq!(cpu.read_mem(0x0200), 0xAA);
+    assert_eq!(cpu.read_mem(0x08FF), 0xAA);
+    assert_eq!(cpu.read_mem(0x0900), 0x55);
+    cpu.write_mem(0xFF50, 0x11);
+    assert_eq!(cpu.read_mem(0x0200), 0x55);
+}
+
+#[test]
+fn test_dmg_compat_mode() {
+    // A CGB runs games without CGB support in DMG compatibility mode.
+    let cpu = model_cpu(vec![0; 0x8000], Model::Cgb, None);
+    assert!(!cpu.cgb_mode());
+    assert!(!cpu.gpu.cgb_mode());
+    // It is still a CGB as far as the game can tell.
+    assert_eq!(cpu.regs.a, 0x11);
+    assert!(cgb_cpu().gpu.cgb_mode());
+
+    // The boot ROM runs in CGB mode and picks the mode for the game when it finishes.
+    let mut cpu = model_cpu(vec![0; 0x8000], Model::Cgb, Some(vec![0; 0x900].into_boxed_slice()));
+    assert!(cpu.cgb_mode());
+    assert!(cpu.gpu.cgb_mode());
+    cpu.write_mem(0xFF50, 1);
+    assert!(!cpu.cgb_mode());
+    assert!(!cpu.gpu.cgb_mode());
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    let mut cpu = model_cpu(rom, Model::Cgb, Some(vec![0; 0x900].into_boxed_slice()));
+    cpu.write_mem(0xFF50, 1);
+    assert!(cpu.cgb_mode());
+    assert!(cpu.gpu.cgb_mode());
+}
+
+/// Restart the APU and play channel 2 with one length clock left.
+fn start_length_one(cpu: &mut Cpu) {
+    cpu.write_mem(0xFF26, 0x00);
+    cpu.write_mem(0xFF26, 0x80);
+    cpu.write_mem(0xFF16, 0x3F);
+    cpu.write_mem(0xFF17, 0xF0);
+    cpu.write_mem(0xFF19, 0xC0);
+}
+
+#[test]
+fn test_frame_sequencer_div() {
+    // The frame sequencer is clocked each time bit 12 of the divider falls, every 8192 cycles.
+    let (mut cpu, _) = setup(vec![0; 0x8000]);
+    cpu.write_mem(0xFF04, 0);
+    start_length_one(&mut cpu);
+    cpu.step_cycles(8188, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0b10);
+    cpu.step_cycles(4, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0);
+
+    // Resetting the divider while bit 12 is set clocks it too.
+    start_length_one(&mut cpu);
+    cpu.step_cycles(0x1000, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0b10);
+    cpu.write_mem(0xFF04, 0);
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0);
+}
+
+#[test]
+fn test_frame_sequencer_div_double_speed() {
+    // In double speed the divider counts twice as fast, so bit 13 is used to keep the same rate.
+    let mut cpu = cgb_cpu();
+    cpu.write_mem(0xFF4D, 0x01);
+    cpu.stop();
+    cpu.write_mem(0xFF04, 0);
+    start_length_one(&mut cpu);
+    cpu.step_cycles(8190, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0b10);
+    cpu.step_cycles(2, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF26) & 0b10, 0);
+}
@@ -0,0 +1,200 @@
+#[cfg(test)]
+mod test;
+
+/// Size of the 93LC56 EEPROM: 128 16-bit words.
+pub const EEPROM_SIZE: usize = 256;
+
+/// Number of bits in a command after the start bit: a 2-bit opcode and an 8-bit address.
+const COMMAND_BITS: u8 = 10;
+
+/// Number of bits in a data word.
+const WORD_BITS: u8 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EepromState {
+    /// Waiting for a start bit.
+    Idle,
+    /// Shifting in the opcode and address.
+    Command,
+    /// Shifting out the word at the address.
+    Reading,
+    /// Shifting in a word to write at the address, or to every address if `all` is true.
+    Writing { addr: u8, all: bool },
+    /// Finished a command, waiting for chip select to go low.
+    Done,
+}
+
+/// The 93LC56 serial EEPROM in MBC7 cartridges, which holds the save data. The game drives the
+/// chip select, clock and data in pins directly and reads back the data out pin. Commands are
+/// shifted in MSB first on rising clock edges: a start bit of 1, a 2-bit opcode and an address.
+#[derive(Clone, Debug)]
+pub struct Eeprom {
+    /// The data, with each word stored little-endian.
+    pub data: Box<[u8]>,
+
+    chip_select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+
+    /// True if writes and erases are allowed, which the EWEN and EWDS commands set.
+    write_enabled: bool,
+
+    state: EepromState,
+
+    /// The bits shifted in or still to be shifted out.
+    shift: u16,
+
+    /// Number of bits shifted in or out so far in this state.
+    bit_count: u8,
+}
+
+impl Eeprom {
+    pub fn new(data: Box<[u8]>) -> Self {
+        Eeprom {
+            data,
+            chip_select: false,
+            clock: false,
+            data_in: false,
+            data_out: true,
+            write_enabled: false,
+            state: EepromState::Idle,
+            shift: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// The pins as seen by the game: chip select in bit 7, clock in bit 6, data in in bit 1 and
+    /// data out in bit 0.
+    pub fn read_pins(&self) -> u8 {
+        (self.chip_select as u8) << 7
+            | (self.clock as u8) << 6
+            | (self.data_in as u8) << 1
+            | self.data_out as u8
+    }
+
+    pub fn write_pins(&mut self, val: u8) {
+        let chip_select = val & 0x80 != 0;
+        let clock = val & 0x40 != 0;
+        self.data_in = val & 0x02 != 0;
+
+        if !chip_select {
+            // Deselecting the chip cancels any command. The data out pin shows the chip is
+            // ready, since writes finish instantly.
+            self.state = EepromState::Idle;
+            self.data_out = true;
+        } else if clock && !self.clock {
+            self.clock_in();
+        }
+        self.chip_select = chip_select;
+        self.clock = clock;
+    }
+
+    /// Handle a rising clock edge while the chip is selected.
+    fn clock_in(&mut self) {
+        let bit = self.data_in as u16;
+        match self.state {
+            EepromState::Idle => {
+                if bit == 1 {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bit_count = 0;
+                }
+            }
+
+            EepromState::Command => {
+                self.shift = self.shift << 1 | bit;
+                self.bit_count += 1;
+                if self.bit_count == COMMAND_BITS {
+                    self.run_command((self.shift >> 8) as u8, self.shift as u8);
+                }
+            }
+
+            EepromState::Reading => {
+                self.data_out = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bit_count += 1;
+                if self.bit_count == WORD_BITS {
+                    self.state = EepromState::Done;
+                }
+            }
+
+            EepromState::Writing { addr, all } => {
+                self.shift = self.shift << 1 | bit;
+                self.bit_count += 1;
+                if self.bit_count == WORD_BITS {
+                    if self.write_enabled {
+                        if all {
+                            for addr in 0..(EEPROM_SIZE / 2) as u8 {
+                                self.write_word(addr, self.shift);
+                            }
+                        } else {
+                            self.write_word(addr, self.shift);
+                        }
+                    }
+                    self.data_out = true;
+                    self.state = EepromState::Done;
+                }
+            }
+
+            EepromState::Done => {}
+        }
+    }
+
+    fn run_command(&mut self, opcode: u8, addr: u8) {
+        // Only the low 7 bits of the address select a word.
+        let word_addr = addr & 0x7F;
+        self.bit_count = 0;
+        self.shift = 0;
+        self.state = EepromState::Done;
+        match opcode {
+            // READ: A dummy 0 bit comes out first, then the word.
+            0b10 => {
+                self.data_out = false;
+                self.shift = self.read_word(word_addr);
+                self.state = EepromState::Reading;
+            }
+
+            // WRITE
+            0b01 => self.state = EepromState::Writing { addr: word_addr, all: false },
+
+            // ERASE
+            0b11 => {
+                if self.write_enabled {
+                    self.write_word(word_addr, 0xFFFF);
+                }
+            }
+
+            // The top two address bits pick between the other commands.
+            _ => match addr >> 6 {
+                // EWDS: Disable writes
+                0b00 => self.write_enabled = false,
+
+                // WRAL: Write a word to every address
+                0b01 => self.state = EepromState::Writing { addr: 0, all: true },
+
+                // ERAL: Erase every address
+                0b10 => {
+                    if self.write_enabled {
+                        for byte in self.data.iter_mut() {
+                            *byte = 0xFF;
+                        }
+                    }
+                }
+
+                // EWEN: Enable writes
+                _ => self.write_enabled = true,
+            },
+        }
+    }
+
+    fn read_word(&self, addr: u8) -> u16 {
+        let i = addr as usize * 2;
+        u16::from_le_bytes([self.data[i], self.data[i + 1]])
+    }
+
+    fn write_word(&mut self, addr: u8, val: u16) {
+        let i = addr as usize * 2;
+        self.data[i..i + 2].copy_from_slice(&val.to_le_bytes());
+    }
+}
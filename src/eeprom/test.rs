@@ -0,0 +1,116 @@
+use super::*;
+
+const CHIP_SELECT: u8 = 0x80;
+const CLOCK: u8 = 0x40;
+
+const READ: u8 = 0b10;
+const WRITE: u8 = 0b01;
+const ERASE: u8 = 0b11;
+const OTHER: u8 = 0b00;
+
+/// The addresses which pick between the commands with the `OTHER` opcode.
+const EWDS: u8 = 0b0000_0000;
+const WRAL: u8 = 0b0100_0000;
+const ERAL: u8 = 0b1000_0000;
+const EWEN: u8 = 0b1100_0000;
+
+fn blank_eeprom() -> Eeprom {
+    Eeprom::new(vec![0xFF; EEPROM_SIZE].into_boxed_slice())
+}
+
+/// Clock in the lowest `count` bits of `bits`, MSB first, and return the data out pin after each
+/// rising edge.
+fn shift(eeprom: &mut Eeprom, bits: u16, count: u8) -> Vec<bool> {
+    (0..count).rev().map(|i| {
+        let data_in = ((bits >> i) as u8 & 1) << 1;
+        eeprom.write_pins(CHIP_SELECT | data_in);
+        eeprom.write_pins(CHIP_SELECT | CLOCK | data_in);
+        eeprom.read_pins() & 1 != 0
+    }).collect()
+}
+
+/// Select the chip and send a start bit, an opcode and an address.
+fn command(eeprom: &mut Eeprom, opcode: u8, addr: u8) {
+    eeprom.write_pins(0);
+    shift(eeprom, 1 << 10 | (opcode as u16) << 8 | addr as u16, 11);
+}
+
+fn write_word(eeprom: &mut Eeprom, addr: u8, val: u16) {
+    command(eeprom, WRITE, addr);
+    shift(eeprom, val, 16);
+    eeprom.write_pins(0);
+}
+
+fn read_word(eeprom: &mut Eeprom, addr: u8) -> u16 {
+    command(eeprom, READ, addr);
+    // A dummy 0 bit comes first.
+    assert_eq!(eeprom.read_pins() & 1, 0);
+    let bits = shift(eeprom, 0, 16);
+    eeprom.write_pins(0);
+    bits.iter().fold(0, |word, &bit| word << 1 | bit as u16)
+}
+
+#[test]
+fn test_pins() {
+    let mut eeprom = blank_eeprom();
+    // Data out is high while idle, which means the chip is ready.
+    assert_eq!(eeprom.read_pins(), 0x01);
+    eeprom.write_pins(CHIP_SELECT | CLOCK | 0x02);
+    assert_eq!(eeprom.read_pins(), 0xC3);
+}
+
+#[test]
+fn test_write_and_read() {
+    let mut eeprom = blank_eeprom();
+
+    // Writes are ignored until enabled.
+    write_word(&mut eeprom, 0x05, 0x1234);
+    assert_eq!(read_word(&mut eeprom, 0x05), 0xFFFF);
+
+    command(&mut eeprom, OTHER, EWEN);
+    write_word(&mut eeprom, 0x05, 0x1234);
+    write_word(&mut eeprom, 0x7F, 0xBEEF);
+    assert_eq!(read_word(&mut eeprom, 0x05), 0x1234);
+    assert_eq!(read_word(&mut eeprom, 0x7F), 0xBEEF);
+    assert_eq!(&eeprom.data[0x0A..0x0C], &[0x34, 0x12]);
+
+    // Only 7 address bits are used.
+    assert_eq!(read_word(&mut eeprom, 0x85), 0x1234);
+
+    command(&mut eeprom, ERASE, 0x05);
+    assert_eq!(read_word(&mut eeprom, 0x05), 0xFFFF);
+    assert_eq!(read_word(&mut eeprom, 0x7F), 0xBEEF);
+
+    // Disabling writes again also blocks erases.
+    command(&mut eeprom, OTHER, EWDS);
+    write_word(&mut eeprom, 0x05, 0x0000);
+    command(&mut eeprom, ERASE, 0x7F);
+    assert_eq!(read_word(&mut eeprom, 0x05), 0xFFFF);
+    assert_eq!(read_word(&mut eeprom, 0x7F), 0xBEEF);
+}
+
+#[test]
+fn test_write_and_erase_all() {
+    let mut eeprom = blank_eeprom();
+    command(&mut eeprom, OTHER, EWEN);
+
+    command(&mut eeprom, OTHER, WRAL);
+    shift(&mut eeprom, 0xA55A, 16);
+    eeprom.write_pins(0);
+    assert!(eeprom.data.chunks(2).all(|word| word == [0x5A, 0xA5]));
+
+    command(&mut eeprom, OTHER, ERAL);
+    assert!(eeprom.data.iter().all(|&byte| byte == 0xFF));
+}
+
+#[test]
+fn test_deselect_cancels() {
+    let mut eeprom = blank_eeprom();
+    command(&mut eeprom, OTHER, EWEN);
+
+    // Deselecting halfway through the data cancels the write.
+    command(&mut eeprom, WRITE, 0x10);
+    shift(&mut eeprom, 0x00, 8);
+    eeprom.write_pins(0);
+    assert_eq!(read_word(&mut eeprom, 0x10), 0xFFFF);
+}
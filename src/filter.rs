@@ -0,0 +1,134 @@
+use crate::palette::Rgb;
+use failure_derive::Fail;
+
+#[cfg(test)]
+mod test;
+
+/// How much of the previous output stays visible in each new frame when ghosting.
+const GHOSTING_PERSISTENCE: f32 = 0.55;
+
+/// How bright the gaps between pixels are with the dot-matrix filter.
+const DOT_MATRIX_GAP_BRIGHTNESS: u16 = 180; // Out of 256
+
+/// How consecutive frames are blended together, to imitate the slow response of the LCD. Games
+/// which flicker sprites on alternate frames for transparency look much better with this.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blend {
+    /// Show each frame as is.
+    None,
+
+    /// Show the average of each frame and the one before it.
+    Average,
+
+    /// Fade each frame in over the previous output, so old frames fade out exponentially.
+    Ghosting,
+}
+
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "unknown blend mode '{}' (expected none, average or ghosting)", _0)]
+pub struct UnknownBlendError(String);
+
+impl std::str::FromStr for Blend {
+    type Err = UnknownBlendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Blend::None),
+            "average" => Ok(Blend::Average),
+            "ghosting" => Ok(Blend::Ghosting),
+            _ => Err(UnknownBlendError(s.to_string())),
+        }
+    }
+}
+
+/// Post-processing applied to each frame before it is shown, done entirely in software.
+pub struct Filter {
+    pub blend: Blend,
+
+    /// Draw each pixel as a separate dot with a darker grid between them, like the DMG's LCD.
+    pub dot_matrix: bool,
+
+    /// The previous frame before blending, used by `Blend::Average`.
+    previous: Vec<Rgb>,
+
+    /// The previous output with fractional color components, used by `Blend::Ghosting`.
+    accumulated: Vec<[f32; 3]>,
+}
+
+impl Filter {
+    pub fn new(blend: Blend, dot_matrix: bool) -> Self {
+        Filter { blend, dot_matrix, previous: Vec::new(), accumulated: Vec::new() }
+    }
+
+    /// Blend the given frame with earlier ones, in place.
+    pub fn blend(&mut self, frame: &mut [Rgb]) {
+        // Start over when the screen size changes, or on the first frame.
+        if self.previous.len() != frame.len() {
+            self.previous = frame.to_vec();
+            self.accumulated = frame.iter().map(|&c| to_f32(c)).collect();
+            return;
+        }
+
+        match self.blend {
+            Blend::None => {}
+
+            Blend::Average => {
+                for (pixel, previous) in frame.iter_mut().zip(&mut self.previous) {
+                    let current = *pixel;
+                    *pixel = (
+                        ((current.0 as u16 + previous.0 as u16) / 2) as u8,
+                        ((current.1 as u16 + previous.1 as u16) / 2) as u8,
+                        ((current.2 as u16 + previous.2 as u16) / 2) as u8,
+                    );
+                    *previous = current;
+                }
+            }
+
+            Blend::Ghosting => {
+                for (pixel, acc) in frame.iter_mut().zip(&mut self.accumulated) {
+                    let current = to_f32(*pixel);
+                    for (a, c) in acc.iter_mut().zip(&current) {
+                        *a = *a * GHOSTING_PERSISTENCE + c * (1.0 - GHOSTING_PERSISTENCE);
+                    }
+                    *pixel = (acc[0].round() as u8, acc[1].round() as u8, acc[2].round() as u8);
+                }
+            }
+        }
+    }
+
+    /// Write the frame to `image` with 4 bytes per pixel in BGRX order, the layout of an SDL
+    /// `RGB888` surface. Each pixel of the frame becomes a `scale` by `scale` square, so the
+    /// dot-matrix grid lines up with the pixels on the display.
+    pub fn render(&self, frame: &[Rgb], width: usize, scale: usize, image: &mut Vec<u8>) {
+        const BYTES_PER_PIXEL: usize = 4;
+        let height = frame.len() / width;
+        let out_width = width * scale;
+        image.clear();
+        image.resize(out_width * height * scale * BYTES_PER_PIXEL, 0);
+
+        for y in 0..height * scale {
+            for x in 0..out_width {
+                let mut color = frame[(y / scale) * width + x / scale];
+                // The last row and column of each dot form the grid.
+                let on_grid = x % scale == scale - 1 || y % scale == scale - 1;
+                if self.dot_matrix && scale > 1 && on_grid {
+                    color = darken(color, DOT_MATRIX_GAP_BRIGHTNESS);
+                }
+                let pixel_i = (y * out_width + x) * BYTES_PER_PIXEL;
+                image[pixel_i + 2] = color.0;
+                image[pixel_i + 1] = color.1;
+                image[pixel_i + 0] = color.2;
+            }
+        }
+    }
+}
+
+fn to_f32(color: Rgb) -> [f32; 3] {
+    [color.0 as f32, color.1 as f32, color.2 as f32]
+}
+
+/// Scale a color's brightness by `brightness / 256`.
+fn darken(color: Rgb, brightness: u16) -> Rgb {
+    let scale = |c: u8| ((c as u16 * brightness) >> 8) as u8;
+    (scale(color.0), scale(color.1), scale(color.2))
+}
@@ -0,0 +1,114 @@
+use super::*;
+
+const BLACK: Rgb = (0, 0, 0);
+const WHITE: Rgb = (255, 255, 255);
+
+/// Read the pixel at the given position of a rendered BGRX image.
+fn image_pixel(image: &[u8], image_width: usize, x: usize, y: usize) -> Rgb {
+    let i = (y * image_width + x) * 4;
+    (image[i + 2], image[i + 1], image[i])
+}
+
+#[test]
+fn test_blend_none() {
+    let mut filter = Filter::new(Blend::None, false);
+    let mut frame = vec![BLACK; 4];
+    filter.blend(&mut frame);
+    let mut frame = vec![WHITE; 4];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [WHITE; 4]);
+}
+
+#[test]
+fn test_blend_average() {
+    let mut filter = Filter::new(Blend::Average, false);
+
+    // The first frame has nothing to blend with.
+    let mut frame = vec![(10, 100, 255), BLACK];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [(10, 100, 255), BLACK]);
+
+    let mut frame = vec![(20, 51, 0), WHITE];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [(15, 75, 127), (127, 127, 127)]);
+
+    // Each frame is averaged with the previous frame as it was before blending.
+    let mut frame = vec![(20, 51, 0), WHITE];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [(20, 51, 0), WHITE]);
+}
+
+#[test]
+fn test_blend_ghosting() {
+    let mut filter = Filter::new(Blend::Ghosting, false);
+    let mut frame = vec![BLACK];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [BLACK]);
+
+    // A new color fades in over several frames.
+    let mut shades = vec![];
+    for _ in 0..3 {
+        let mut frame = vec![WHITE];
+        filter.blend(&mut frame);
+        shades.push(frame[0].0);
+    }
+    assert_eq!(shades, [115, 178, 213]);
+
+    // And fades out exponentially.
+    let mut shades = vec![];
+    for _ in 0..3 {
+        let mut frame = vec![BLACK];
+        filter.blend(&mut frame);
+        shades.push(frame[0].0);
+    }
+    assert_eq!(shades, [117, 64, 35]);
+
+    // Changing the screen size starts over.
+    let mut frame = vec![WHITE; 2];
+    filter.blend(&mut frame);
+    assert_eq!(frame, [WHITE; 2]);
+}
+
+#[test]
+fn test_render() {
+    let filter = Filter::new(Blend::None, false);
+    let frame = [(1, 2, 3), (4, 5, 6), (7, 8, 9), (10, 11, 12)];
+    let mut image = vec![];
+    filter.render(&frame, 2, 1, &mut image);
+    assert_eq!(image, [3, 2, 1, 0, 6, 5, 4, 0, 9, 8, 7, 0, 12, 11, 10, 0]);
+
+    // Each pixel becomes a square.
+    filter.render(&frame, 2, 2, &mut image);
+    assert_eq!(image.len(), 4 * 4 * 4);
+    for y in 0..4 {
+        for x in 0..4 {
+            assert_eq!(image_pixel(&image, 4, x, y), frame[(y / 2) * 2 + x / 2]);
+        }
+    }
+}
+
+#[test]
+fn test_render_dot_matrix() {
+    let filter = Filter::new(Blend::None, true);
+    let frame = [(100, 150, 200), WHITE];
+    let mut image = vec![];
+
+    // There's no room for gaps at 1x.
+    filter.render(&frame, 2, 1, &mut image);
+    assert_eq!(image_pixel(&image, 2, 0, 0), (100, 150, 200));
+
+    // The last row and column of each dot are darkened.
+    filter.render(&frame, 2, 3, &mut image);
+    for y in 0..3 {
+        for x in 0..6 {
+            let gap = x % 3 == 2 || y == 2;
+            let expected = match (x / 3, gap) {
+                (0, false) => (100, 150, 200),
+                (0, true) => (70, 105, 140),
+                (_, false) => WHITE,
+                (_, true) => (179, 179, 179),
+            };
+            assert_eq!(image_pixel(&image, 6, x, y), expected, "pixel ({}, {})", x, y);
+        }
+    }
+}
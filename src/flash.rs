@@ -0,0 +1,107 @@
+#[cfg(test)]
+mod test;
+
+/// Size of the MX29F008 flash chip in MBC6 cartridges.
+pub const FLASH_SIZE: usize = 0x100000;
+
+/// Size of each sector the flash can erase separately.
+const SECTOR_SIZE: usize = 0x10000;
+
+/// The two addresses of the unlock sequence which comes before each command.
+const UNLOCK_ADDR_1: usize = 0x5555;
+const UNLOCK_ADDR_2: usize = 0x2AAA;
+
+/// The IDs read in ID mode: Macronix, MX29F008.
+const MANUFACTURER_ID: u8 = 0xC2;
+const DEVICE_ID: u8 = 0x81;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FlashState {
+    /// Reading the data, waiting for the first unlock write.
+    Read,
+    /// Got 0xAA at the first unlock address.
+    Unlock1,
+    /// Got 0x55 at the second unlock address, so the next write is a command.
+    Unlock2,
+    /// The next write programs a byte.
+    Program,
+    /// Got the erase command, waiting for a second unlock sequence.
+    EraseSetup,
+    EraseUnlock1,
+    EraseUnlock2,
+    /// Reads return the chip IDs.
+    Id,
+}
+
+/// The flash memory in MBC6 cartridges, which games can program and erase to save downloaded
+/// content. Programming can only clear bits, and erasing sets them back to 1. Commands are written
+/// after a two-write unlock sequence, and take effect instantly.
+#[derive(Clone, Debug)]
+pub struct Flash {
+    pub data: Box<[u8]>,
+    state: FlashState,
+}
+
+impl Flash {
+    pub fn new(data: Box<[u8]>) -> Self {
+        Flash { data, state: FlashState::Read }
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        if self.state == FlashState::Id {
+            return match addr & 1 {
+                0 => MANUFACTURER_ID,
+                _ => DEVICE_ID,
+            };
+        }
+        self.data[addr & (self.data.len() - 1)]
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) {
+        let addr = addr & (self.data.len() - 1);
+        // The unlock addresses only use the low 15 address bits.
+        let unlock_addr = addr & 0x7FFF;
+        self.state = match (self.state, unlock_addr, val) {
+            // Any value can be programmed, including the reset command.
+            (FlashState::Program, _, _) => {
+                self.data[addr] &= val;
+                FlashState::Read
+            }
+
+            // Reset to reading
+            (_, _, 0xF0) => FlashState::Read,
+
+            (FlashState::Read, UNLOCK_ADDR_1, 0xAA) | (FlashState::Id, UNLOCK_ADDR_1, 0xAA) =>
+                FlashState::Unlock1,
+            (FlashState::Unlock1, UNLOCK_ADDR_2, 0x55) => FlashState::Unlock2,
+
+            // Commands
+            (FlashState::Unlock2, UNLOCK_ADDR_1, 0xA0) => FlashState::Program,
+            (FlashState::Unlock2, UNLOCK_ADDR_1, 0x80) => FlashState::EraseSetup,
+            (FlashState::Unlock2, UNLOCK_ADDR_1, 0x90) => FlashState::Id,
+
+            (FlashState::EraseSetup, UNLOCK_ADDR_1, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, UNLOCK_ADDR_2, 0x55) => FlashState::EraseUnlock2,
+
+            // Erase the sector holding the address
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                let start = addr & !(SECTOR_SIZE - 1);
+                for byte in &mut self.data[start..start + SECTOR_SIZE] {
+                    *byte = 0xFF;
+                }
+                FlashState::Read
+            }
+
+            // Erase the whole chip
+            (FlashState::EraseUnlock2, UNLOCK_ADDR_1, 0x10) => {
+                for byte in self.data.iter_mut() {
+                    *byte = 0xFF;
+                }
+                FlashState::Read
+            }
+
+            // Anything else cancels the command.
+            _ => FlashState::Read,
+        };
+    }
+}
@@ -0,0 +1,112 @@
+use super::*;
+
+fn blank_flash() -> Flash {
+    Flash::new(vec![0xFF; FLASH_SIZE].into_boxed_slice())
+}
+
+/// Write the unlock sequence and then a command.
+fn command(flash: &mut Flash, command: u8) {
+    flash.write(UNLOCK_ADDR_1, 0xAA);
+    flash.write(UNLOCK_ADDR_2, 0x55);
+    flash.write(UNLOCK_ADDR_1, command);
+}
+
+fn program(flash: &mut Flash, addr: usize, val: u8) {
+    command(flash, 0xA0);
+    flash.write(addr, val);
+}
+
+fn erase(flash: &mut Flash, addr: usize, val: u8) {
+    command(flash, 0x80);
+    flash.write(UNLOCK_ADDR_1, 0xAA);
+    flash.write(UNLOCK_ADDR_2, 0x55);
+    flash.write(addr, val);
+}
+
+#[test]
+fn test_program() {
+    let mut flash = blank_flash();
+    program(&mut flash, 0x12345, 0x3C);
+    assert_eq!(flash.read(0x12345), 0x3C);
+
+    // Programming can only clear bits.
+    program(&mut flash, 0x12345, 0xF0);
+    assert_eq!(flash.read(0x12345), 0x30);
+
+    // Writes without the unlock sequence do nothing.
+    flash.write(0x12346, 0x00);
+    assert_eq!(flash.read(0x12346), 0xFF);
+
+    // A wrong unlock sequence cancels, so the next write isn't programmed.
+    flash.write(UNLOCK_ADDR_1, 0xAA);
+    flash.write(UNLOCK_ADDR_1, 0x55);
+    flash.write(UNLOCK_ADDR_1, 0xA0);
+    flash.write(0x12346, 0x00);
+    assert_eq!(flash.read(0x12346), 0xFF);
+}
+
+#[test]
+fn test_program_reset_value() {
+    // 0xF0 is the reset command, but after the program command it's just data.
+    let mut flash = blank_flash();
+    program(&mut flash, 0x00100, 0xF0);
+    assert_eq!(flash.read(0x00100), 0xF0);
+}
+
+#[test]
+fn test_unlock_mirrors() {
+    // Only the low 15 bits of the unlock addresses are checked.
+    let mut flash = blank_flash();
+    flash.write(0x8000 | UNLOCK_ADDR_1, 0xAA);
+    flash.write(0x18000 | UNLOCK_ADDR_2, 0x55);
+    flash.write(UNLOCK_ADDR_1, 0xA0);
+    flash.write(0x00000, 0x12);
+    assert_eq!(flash.read(0x00000), 0x12);
+}
+
+#[test]
+fn test_sector_erase() {
+    let mut flash = blank_flash();
+    for &addr in &[0x0FFFF, 0x10000, 0x1FFFF, 0x20000] {
+        program(&mut flash, addr, 0x00);
+    }
+    erase(&mut flash, 0x1ABCD, 0x30);
+    assert_eq!(flash.read(0x0FFFF), 0x00);
+    assert_eq!(flash.read(0x10000), 0xFF);
+    assert_eq!(flash.read(0x1FFFF), 0xFF);
+    assert_eq!(flash.read(0x20000), 0x00);
+}
+
+#[test]
+fn test_chip_erase() {
+    let mut flash = blank_flash();
+    program(&mut flash, 0x00000, 0x00);
+    program(&mut flash, FLASH_SIZE - 1, 0x00);
+
+    // The chip erase command needs the first unlock address.
+    erase(&mut flash, 0x1234, 0x10);
+    assert_eq!(flash.read(0x00000), 0x00);
+
+    erase(&mut flash, UNLOCK_ADDR_1, 0x10);
+    assert!(flash.data.iter().all(|&byte| byte == 0xFF));
+}
+
+#[test]
+fn test_id_mode() {
+    let mut flash = blank_flash();
+    program(&mut flash, 0x00000, 0x00);
+
+    command(&mut flash, 0x90);
+    assert_eq!(flash.read(0x00000), MANUFACTURER_ID);
+    assert_eq!(flash.read(0x00001), DEVICE_ID);
+    assert_eq!(flash.read(0x40002), MANUFACTURER_ID);
+
+    // Reset returns to reading the data.
+    flash.write(0x00000, 0xF0);
+    assert_eq!(flash.read(0x00000), 0x00);
+
+    // Commands can be given straight from ID mode.
+    command(&mut flash, 0x90);
+    program(&mut flash, 0x00001, 0x00);
+    assert_eq!(flash.read(0x00001), 0x00);
+}
@@ -1,50 +1,89 @@
-use crate::audio::SAMPLE_BUFFER_SIZE;
+use crate::audio::{Scope, SAMPLE_BUFFER_SIZE};
+use crate::audio_view::AudioView;
 use crate::cpu::Cpu;
 use crate::cpu::registers::{Reg8, Reg16};
 use crate::debug::Watch;
 use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
-use crate::joypad::{ButtonKey, DirKey};
-use log::info;
+use crate::joypad::{ButtonKey, DirKey, MAX_PLAYERS};
+use crate::filter::Filter;
+use crate::palette::Palette;
+use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+use log::{info, warn};
 use sdl2::audio::{AudioQueue, AudioSpecDesired};
-use sdl2::controller::Button;
-use sdl2::event::Event;
+use sdl2::controller::{Axis, Button};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::EventPump;
-use sdl2::keyboard::{Keycode, Mod};
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::rect::Rect;
 use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::video::{FullscreenType, Window};
 use sdl2::gfx::framerate::FPSManager;
 use sdl2::GameControllerSubsystem;
 use sdl2::controller::GameController;
 use linefeed::{Interface, ReadResult};
 use hex;
 use hex::FromHex;
+use std::cell::Cell;
 use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod test;
 
 const CYCLES_PER_FRAME: usize = 69905;
-const WINDOW_SCALE: usize = 5;
+const CYCLES_PER_SECOND: f64 = 4_194_304.0;
 const BASE_FPS: u32 = 60;
+const SPEED_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most the audio sample rate is adjusted by to keep the queue at the target latency. A
+/// pitch change this small isn't noticeable.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+
+/// If the audio queue gets this many times longer than the target latency, for example after
+/// the window was dragged, it is cleared instead of slowly drained.
+const MAX_LATENCY_FACTOR: usize = 4;
+
+/// How long each rumble request lasts. Requests are repeated every frame while the motor runs,
+/// so rumble stops soon after the game stops it even if the request to stop is lost.
+const RUMBLE_DURATION_MS: u32 = 100;
+
+/// Analog stick positions closer to the center than this fraction are treated as centered.
+const STICK_DEAD_ZONE: f32 = 0.15;
+
+/// Display and audio settings for the frontend.
+pub struct FrontendConfig {
+    /// The palettes available for DMG games, which F9 cycles through.
+    pub palettes: Vec<Palette>,
+
+    /// The index of the palette in use.
+    pub palette_index: usize,
 
-/// The four colors of the original Game Boy screen, from lightest to darkest, in RGB.
-const GAME_BOY_COLORS: [sdl2::pixels::Color; 4] = [
-    sdl2::pixels::Color { r: 155, g: 188, b: 15, a: 0xFF },
-    sdl2::pixels::Color { r: 139, g: 172, b: 15, a: 0xFF },
-    sdl2::pixels::Color { r: 48,  g: 98,  b: 48, a: 0xFF },
-    sdl2::pixels::Color { r: 15,  g: 56,  b: 15, a: 0xFF },
-];
+    /// The initial window size, as a multiple of the screen size.
+    pub scale: usize,
+
+    /// Scale the screen to fill as much of the window as possible, instead of only scaling by
+    /// whole multiples.
+    pub fit_to_window: bool,
+
+    /// The game title to show in the window title.
+    pub title: String,
+
+    /// Frame blending and other post-processing.
+    pub filter: Filter,
+
+    /// The audio sample rate to ask SDL for.
+    pub sample_rate: u32,
+
+    /// How much audio to keep queued, in milliseconds. Lower values reduce delay but may
+    /// crackle.
+    pub audio_latency: u32,
+}
 
-pub fn start_frontend(cpu: &mut Cpu) {
+pub fn start_frontend(cpu: &mut Cpu, config: &mut FrontendConfig) {
     let sdl = sdl2::init().expect("Failed to initialize SDL");
 
-    let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
-    let window = sdl_video
-        .window(
-            "Rugby",
-            (SCREEN_WIDTH * WINDOW_SCALE) as u32,
-            (SCREEN_HEIGHT * WINDOW_SCALE) as u32,
-        )
-        .build()
-        .expect("Failed to create SDL window");
-    let mut canvas = window.into_canvas().build().expect("Failed to get SDL window canvas");
+    let mut canvas = create_canvas(&sdl, cpu, config);
     let mut sdl_events = sdl.event_pump().expect("Failed to get SDL event pump");
 
     let mut sdl_fps = sdl2::gfx::framerate::FPSManager::new();
@@ -53,53 +92,221 @@ pub fn start_frontend(cpu: &mut Cpu) {
     let sdl_controllers = sdl.game_controller().expect("Failed to get SDL game controllers");
     let mut controllers = vec![];
 
+    let mut audio_queue = open_audio_queue(&sdl, cpu, config);
+
+    run_emulator(cpu, config, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, false, None, &HashSet::new())
+}
+
+/// Open a stereo audio queue at the configured sample rate, and set up the emulator to output
+/// samples at the rate SDL gives us.
+fn open_audio_queue(sdl: &sdl2::Sdl, cpu: &mut Cpu, config: &FrontendConfig) -> AudioQueue<i16> {
     let sdl_audio = sdl.audio().expect("Failed to access SDL audio subsystem");
     let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
+        freq: Some(config.sample_rate as i32),
         channels: Some(2), // Stereo
         samples: Some(SAMPLE_BUFFER_SIZE as u16),
     };
-    let mut audio_queue = sdl_audio.open_queue(None, &desired_spec).expect("Failed to open audio queue");
+    let audio_queue = sdl_audio.open_queue(None, &desired_spec).expect("Failed to open audio queue");
+    let freq = audio_queue.spec().freq;
+    if freq != config.sample_rate as i32 {
+        info!("Using audio sample rate {} Hz", freq);
+    }
+    cpu.audio.set_sample_rate(freq as f64);
     audio_queue.resume();
+    audio_queue
+}
+
+/// Queue the samples generated since the last frame. The frame timer and the audio device's clock
+/// never match exactly, so the sample rate is nudged up or down to hold the queue near the target
+/// latency. Otherwise the queue would slowly grow, adding delay, or run dry and crackle.
+fn queue_audio(cpu: &mut Cpu, config: &FrontendConfig, audio_queue: &AudioQueue<i16>, samples: &mut Vec<i16>) {
+    const BYTES_PER_FRAME: usize = 4; // Two 16-bit samples
+    let spec = audio_queue.spec();
+    let target = spec.freq as usize * config.audio_latency as usize / 1000;
+    let queued = audio_queue.size() as usize / BYTES_PER_FRAME;
+    if queued > target * MAX_LATENCY_FACTOR {
+        audio_queue.clear();
+    }
+
+    samples.clear();
+    cpu.audio.drain_samples(samples);
+    audio_queue.queue(samples);
+
+    // Generate more samples when the queue is short, and fewer when it is long. This applies from
+    // the next frame, since the frame just drained was timed at the old rate.
+    let fill = queued.min(2 * target) as f64 / target.max(1) as f64;
+    let adjustment = 1.0 + MAX_RATE_ADJUSTMENT * (1.0 - fill);
+    cpu.audio.set_sample_rate(spec.freq as f64 * adjustment);
+}
+
+/// Create a resizable window which starts at the configured scale.
+fn create_canvas(sdl: &sdl2::Sdl, cpu: &Cpu, config: &FrontendConfig) -> Canvas<Window> {
+    let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
+    let window = sdl_video
+        .window(
+            &window_title(config, None),
+            (screen_width(cpu) * config.scale) as u32,
+            (screen_height(cpu) * config.scale) as u32,
+        )
+        .resizable()
+        .build()
+        .expect("Failed to create SDL window");
+    window.into_canvas().build().expect("Failed to get SDL window canvas")
+}
+
+/// The window title, with the game title and the emulation speed as a percentage of a real Game
+/// Boy's, or None while paused.
+fn window_title(config: &FrontendConfig, speed: Option<f64>) -> String {
+    let mut title = String::from("Rugby");
+    if !config.title.is_empty() {
+        title += &format!(" - {}", config.title);
+    }
+    match speed {
+        Some(speed) => title += &format!(" - {:.0}%", speed * 100.0),
+        None => title += " - Paused",
+    }
+    title
+}
+
+/// Find where to draw the screen in a window of the given size. It is centered, and the rest of
+/// the window is left black.
+fn screen_rect(window_size: (u32, u32), width: usize, height: usize, fit_to_window: bool) -> Rect {
+    let (window_width, window_height) = window_size;
+    let scale_x = window_width as f64 / width as f64;
+    let scale_y = window_height as f64 / height as f64;
+    let mut scale = scale_x.min(scale_y);
+    if !fit_to_window {
+        // Never scale below 1x, even if the window is smaller than the screen.
+        scale = scale.floor().max(1.0);
+    }
+    let (w, h) = ((width as f64 * scale) as u32, (height as f64 * scale) as u32);
+    let x = (window_width as i32 - w as i32) / 2;
+    let y = (window_height as i32 - h as i32) / 2;
+    Rect::new(x, y, w, h)
+}
+
+/// Switch between windowed mode and fullscreen at the desktop resolution.
+fn toggle_fullscreen(canvas: &mut Canvas<Window>) {
+    let window = canvas.window_mut();
+    let fullscreen = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    if let Err(e) = window.set_fullscreen(fullscreen) {
+        warn!("Failed to toggle fullscreen: {}", e);
+    }
+}
+
+/// The width of the emulated screen, which includes the border in SGB mode.
+fn screen_width(cpu: &Cpu) -> usize {
+    if cpu.sgb.is_some() { SGB_SCREEN_WIDTH } else { SCREEN_WIDTH }
+}
+
+/// The height of the emulated screen, which includes the border in SGB mode.
+fn screen_height(cpu: &Cpu) -> usize {
+    if cpu.sgb.is_some() { SGB_SCREEN_HEIGHT } else { SCREEN_HEIGHT }
+}
+
+/// Pick the SGB player controlled by a game controller. The keyboard and the first controller
+/// control player 1, and each additional controller controls the next player.
+fn controller_player(controllers: &[GameController], which: u32) -> usize {
+    let i = controllers.iter().position(|c| c.instance_id() == which).unwrap_or(0);
+    i.min(MAX_PLAYERS - 1)
+}
 
-    run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, false, None, &HashSet::new())
+/// Convert a 15-bit CGB color to 24-bit RGB, scaling each 5-bit channel to 8 bits.
+fn bgr555_to_rgb(color: u16) -> (u8, u8, u8) {
+    let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    (scale(color & 0x1F), scale((color >> 5) & 0x1F), scale((color >> 10) & 0x1F))
 }
 
 fn run_emulator(
-    cpu: &mut Cpu, canvas: &mut Canvas<Window>, sdl_events: &mut EventPump, sdl_fps: &mut FPSManager,
-    sdl_controllers: &GameControllerSubsystem, controllers: &mut Vec<GameController>, audio_queue: &mut AudioQueue<u8>,
+    cpu: &mut Cpu, config: &mut FrontendConfig, canvas: &mut Canvas<Window>, sdl_events: &mut EventPump, sdl_fps: &mut FPSManager,
+    sdl_controllers: &GameControllerSubsystem, controllers: &mut Vec<GameController>, audio_queue: &mut AudioQueue<i16>,
     debug: bool, num_instrs: Option<usize>, watches: &HashSet<Watch>
 ) {
     let mut paused = false;
     let mut pause_next_frame = false;
+    let mut speed_timer = Instant::now();
+    let mut frames_since_speed_update = 0;
+    let mut frame = Vec::new();
+    let mut image = Vec::new();
+    let mut samples = Vec::new();
+    let mut audio_view: Option<AudioView> = None;
+
+    // Games often switch the motor on and off many times a frame to control its strength, so
+    // rumble whenever the motor was on at any point during the frame.
+    let motor_started = Rc::new(Cell::new(false));
+    let callback_motor_started = motor_started.clone();
+    cpu.rumble_callback = Some(Rc::new(move |on| if on { callback_motor_started.set(true) }));
+    let mut rumbling = false;
+
+    // The HuC3 piezo speaker isn't emulated, so at least show when the game uses it.
+    cpu.tone_callback = Some(Rc::new(|tone| match tone {
+        Some(tone) => info!("HuC3 tone {:X} on", tone),
+        None => info!("HuC3 tone off"),
+    }));
+
     'main: loop {
         const BYTES_PER_PIXEL: usize = 4;
-        let mut image = [0u8; SCREEN_WIDTH * SCREEN_HEIGHT * BYTES_PER_PIXEL];
-
-        for tile_row in 0..SCREEN_HEIGHT {
-            for tile_col in 0..SCREEN_WIDTH {
-                let pixel_i = (tile_row * SCREEN_WIDTH + tile_col) * 4;
-                let color_i = cpu.gpu.screen_buffer[tile_row][tile_col] as usize;
-                let color = GAME_BOY_COLORS[color_i].rgb();
-                image[pixel_i + 2] = color.0;
-                image[pixel_i + 1] = color.1;
-                image[pixel_i + 0] = color.2;
+        let (width, height) = (screen_width(cpu), screen_height(cpu));
+
+        frame.clear();
+        for tile_row in 0..height {
+            for tile_col in 0..width {
+                let color = if let Some(sgb) = &cpu.sgb {
+                    bgr555_to_rgb(sgb.screen_buffer[tile_row][tile_col])
+                } else if cpu.gpu.cgb_mode() {
+                    bgr555_to_rgb(cpu.gpu.color_screen_buffer[tile_row][tile_col])
+                } else {
+                    let shade = cpu.gpu.screen_buffer[tile_row][tile_col];
+                    let layer = cpu.gpu.layer_buffer[tile_row][tile_col];
+                    config.palettes[config.palette_index].color(layer, shade)
+                };
+                frame.push(color);
             }
         }
 
+        // Render the dot-matrix filter at the size the screen is shown at, so its grid isn't
+        // distorted by scaling.
+        let window_size = canvas.output_size().expect("Failed to get window size");
+        let rect = screen_rect(window_size, width, height, config.fit_to_window);
+        let scale = if config.filter.dot_matrix { (rect.width() as usize / width).max(2) } else { 1 };
+        config.filter.blend(&mut frame);
+        config.filter.render(&frame, width, scale, &mut image);
+
         let surface = sdl2::surface::Surface::from_data(
             &mut image[..],
-            SCREEN_WIDTH as u32,
-            SCREEN_HEIGHT as u32,
-            (SCREEN_WIDTH * BYTES_PER_PIXEL) as u32,
+            (width * scale) as u32,
+            (height * scale) as u32,
+            (width * scale * BYTES_PER_PIXEL) as u32,
             sdl2::pixels::PixelFormatEnum::RGB888,
         ).unwrap();
         let texture_creator = canvas.texture_creator();
         let texture = texture_creator.create_texture_from_surface(&surface).unwrap();
 
-        canvas.copy(&texture, None, None).unwrap();
+        canvas.set_draw_color(sdl2::pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.copy(&texture, None, rect).unwrap();
         canvas.present();
 
+        if let Some(view) = &mut audio_view {
+            if let Err(e) = view.draw(&cpu.audio) {
+                warn!("Failed to draw audio view: {}", e);
+            }
+        }
+
+        let elapsed = speed_timer.elapsed();
+        if elapsed >= SPEED_UPDATE_INTERVAL {
+            let emulated_seconds = (frames_since_speed_update * CYCLES_PER_FRAME) as f64 / CYCLES_PER_SECOND;
+            let speed = if paused { None } else { Some(emulated_seconds / elapsed.as_secs_f64()) };
+            if let Err(e) = canvas.window_mut().set_title(&window_title(config, speed)) {
+                warn!("Failed to set window title: {}", e);
+            }
+            speed_timer = Instant::now();
+            frames_since_speed_update = 0;
+        }
+
         if pause_next_frame {
             pause_next_frame = false;
             paused = true;
@@ -109,6 +316,15 @@ fn run_emulator(
             match event {
                 Event::Quit { .. } => break 'main,
 
+                // Closing the audio view leaves the emulator running.
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                    if audio_view.as_ref().map_or(false, |view| view.window_id() == window_id) {
+                        toggle_audio_view(cpu, canvas, &mut audio_view);
+                    } else {
+                        break 'main;
+                    }
+                }
+
                 Event::KeyDown { keycode: Some(keycode), keymod, repeat, .. } => {
                     let modifiers = Mod::LSHIFTMOD | Mod::RSHIFTMOD | Mod::LCTRLMOD |
                         Mod::RCTRLMOD | Mod::LALTMOD | Mod::RALTMOD | Mod::LGUIMOD |
@@ -139,6 +355,16 @@ fn run_emulator(
                             Keycode::F2 if !repeat => cpu.audio.channel_2_muted = !cpu.audio.channel_2_muted,
                             Keycode::F3 if !repeat => cpu.audio.channel_3_muted = !cpu.audio.channel_3_muted,
                             Keycode::F4 if !repeat => cpu.audio.channel_4_muted = !cpu.audio.channel_4_muted,
+                            Keycode::F5 if !repeat => cpu.audio.toggle_solo(1),
+                            Keycode::F6 if !repeat => cpu.audio.toggle_solo(2),
+                            Keycode::F7 if !repeat => cpu.audio.toggle_solo(3),
+                            Keycode::F8 if !repeat => cpu.audio.toggle_solo(4),
+                            Keycode::F10 if !repeat => toggle_audio_view(cpu, canvas, &mut audio_view),
+                            Keycode::F11 if !repeat => toggle_fullscreen(canvas),
+                            Keycode::F9 if !repeat => {
+                                config.palette_index = (config.palette_index + 1) % config.palettes.len();
+                                info!("Switched to palette '{}'", config.palettes[config.palette_index].name);
+                            }
                             _ => {}
                         }
                     }
@@ -177,30 +403,32 @@ fn run_emulator(
                     info!("Removed controller with index {}", which);
                 }
 
-                Event::ControllerButtonDown { button, .. } => {
+                Event::ControllerButtonDown { which, button, .. } => {
+                    let player = controller_player(controllers, which);
                     match button {
-                        Button::A => cpu.joypad.button_key_down(ButtonKey::A),
-                        Button::X => cpu.joypad.button_key_down(ButtonKey::B),
-                        Button::Start => cpu.joypad.button_key_down(ButtonKey::Start),
-                        Button::Back => cpu.joypad.button_key_down(ButtonKey::Select),
-                        Button::DPadLeft => cpu.joypad.dir_key_down(DirKey::Left),
-                        Button::DPadRight => cpu.joypad.dir_key_down(DirKey::Right),
-                        Button::DPadUp => cpu.joypad.dir_key_down(DirKey::Up),
-                        Button::DPadDown => cpu.joypad.dir_key_down(DirKey::Down),
+                        Button::A => cpu.joypad.player_button_key_down(player, ButtonKey::A),
+                        Button::X => cpu.joypad.player_button_key_down(player, ButtonKey::B),
+                        Button::Start => cpu.joypad.player_button_key_down(player, ButtonKey::Start),
+                        Button::Back => cpu.joypad.player_button_key_down(player, ButtonKey::Select),
+                        Button::DPadLeft => cpu.joypad.player_dir_key_down(player, DirKey::Left),
+                        Button::DPadRight => cpu.joypad.player_dir_key_down(player, DirKey::Right),
+                        Button::DPadUp => cpu.joypad.player_dir_key_down(player, DirKey::Up),
+                        Button::DPadDown => cpu.joypad.player_dir_key_down(player, DirKey::Down),
                         _ => {}
                     }
                 }
 
-                Event::ControllerButtonUp { button, .. } => {
+                Event::ControllerButtonUp { which, button, .. } => {
+                    let player = controller_player(controllers, which);
                     match button {
-                        Button::A => cpu.joypad.button_key_up(ButtonKey::A),
-                        Button::X => cpu.joypad.button_key_up(ButtonKey::B),
-                        Button::Start => cpu.joypad.button_key_up(ButtonKey::Start),
-                        Button::Back => cpu.joypad.button_key_up(ButtonKey::Select),
-                        Button::DPadLeft => cpu.joypad.dir_key_up(DirKey::Left),
-                        Button::DPadRight => cpu.joypad.dir_key_up(DirKey::Right),
-                        Button::DPadUp => cpu.joypad.dir_key_up(DirKey::Up),
-                        Button::DPadDown => cpu.joypad.dir_key_up(DirKey::Down),
+                        Button::A => cpu.joypad.player_button_key_up(player, ButtonKey::A),
+                        Button::X => cpu.joypad.player_button_key_up(player, ButtonKey::B),
+                        Button::Start => cpu.joypad.player_button_key_up(player, ButtonKey::Start),
+                        Button::Back => cpu.joypad.player_button_key_up(player, ButtonKey::Select),
+                        Button::DPadLeft => cpu.joypad.player_dir_key_up(player, DirKey::Left),
+                        Button::DPadRight => cpu.joypad.player_dir_key_up(player, DirKey::Right),
+                        Button::DPadUp => cpu.joypad.player_dir_key_up(player, DirKey::Up),
+                        Button::DPadDown => cpu.joypad.player_dir_key_up(player, DirKey::Down),
                         _ => {}
                     }
                 }
@@ -216,20 +444,91 @@ fn run_emulator(
             },
             None => {
                 if !paused {
-                    let should_break = cpu.step_cycles(
-                        CYCLES_PER_FRAME,
-                        audio_queue,
-                        watches,
-                    );
+                    let (tilt_x, tilt_y) = read_tilt(sdl_events, canvas, controllers);
+                    cpu.cart.set_tilt(tilt_x, tilt_y);
+                    let should_break = cpu.step_cycles(CYCLES_PER_FRAME, watches);
+                    queue_audio(cpu, config, audio_queue, &mut samples);
+                    let rumble = motor_started.replace(false) || cpu.cart.motor_on();
+                    if rumble || rumbling {
+                        set_rumble(controllers, rumble);
+                    }
+                    rumbling = rumble;
                     if should_break {
                         break 'main;
                     }
+                    frames_since_speed_update += 1;
                 }
             },
         }
 
         sdl_fps.delay();
     }
+
+    // The scope only records while the view is open.
+    cpu.audio.scope = None;
+    cpu.rumble_callback = None;
+    cpu.tone_callback = None;
+    if rumbling {
+        set_rumble(controllers, false);
+    }
+}
+
+/// How far the player is tilting the cartridge on each axis, from -1.0 to 1.0, for cartridges
+/// with an accelerometer. The arrow keys tilt fully, dragging with the left mouse button tilts
+/// towards the mouse from the center of the window, and the left analog stick tilts in its
+/// direction. These add together.
+fn read_tilt(sdl_events: &EventPump, canvas: &Canvas<Window>, controllers: &[GameController]) -> (f32, f32) {
+    let mut x = 0.0;
+    let mut y = 0.0;
+
+    let keys = sdl_events.keyboard_state();
+    let key = |scancode| if keys.is_scancode_pressed(scancode) { 1.0 } else { 0.0 };
+    x += key(Scancode::Right) - key(Scancode::Left);
+    y += key(Scancode::Down) - key(Scancode::Up);
+
+    let mouse = sdl_events.mouse_state();
+    if mouse.left() {
+        let (width, height) = canvas.window().size();
+        let half_width = width as f32 / 2.0;
+        let half_height = height as f32 / 2.0;
+        x += (mouse.x() as f32 - half_width) / half_width;
+        y += (mouse.y() as f32 - half_height) / half_height;
+    }
+
+    let stick = |value: i16| {
+        let value = value as f32 / i16::max_value() as f32;
+        if value.abs() < STICK_DEAD_ZONE { 0.0 } else { value }
+    };
+    for controller in controllers {
+        x += stick(controller.axis(Axis::LeftX));
+        y += stick(controller.axis(Axis::LeftY));
+    }
+
+    (x.max(-1.0).min(1.0), y.max(-1.0).min(1.0))
+}
+
+/// Start or stop rumble on all the controllers which support it.
+fn set_rumble(controllers: &mut [GameController], on: bool) {
+    let (strength, duration) = if on { (u16::max_value(), RUMBLE_DURATION_MS) } else { (0, 0) };
+    for controller in controllers {
+        // Controllers without rumble return an error, which is expected.
+        let _ = controller.set_rumble(strength, strength, duration);
+    }
+}
+
+/// Open the audio view, or close it if it's open.
+fn toggle_audio_view(cpu: &mut Cpu, canvas: &Canvas<Window>, audio_view: &mut Option<AudioView>) {
+    if audio_view.take().is_some() {
+        cpu.audio.scope = None;
+        return;
+    }
+    match AudioView::new(&canvas.window().subsystem()) {
+        Ok(view) => {
+            *audio_view = Some(view);
+            cpu.audio.scope = Some(Scope::new());
+        }
+        Err(e) => warn!("Failed to open audio view: {}", e),
+    }
 }
 
 const COMMANDS: &str = "\
@@ -239,25 +538,17 @@ wm <addr> [end_addr]:   Watch writes to a memory address 'addr'. Specifying 'end
 wr <reg>:               Watch writes to register 'reg'. Supports 8 and 16 bit registers. e.g. HL, AF, A, B, etc
 rm <addr> [end_addr]:   Read memory address 'addr'. Specifying 'end_addr' will read a range. Hex format
 rr:                     Read registers
+a:                      Show the audio channels' registers and wave RAM
 l:                      List watches
 dm <addr> [end_addr]:   Delete memory address watch. Hex format
 dr <reg>:               Delete register watch.
 s [n]:                  Step forward 'n' instructions (defaults to 1). n = 1 will pass over breaks.
 e:                      Exit debugger";
 
-pub fn start_frontend_debug(cpu: &mut Cpu) {
+pub fn start_frontend_debug(cpu: &mut Cpu, config: &mut FrontendConfig) {
     let sdl = sdl2::init().expect("Failed to initialize SDL");
 
-    let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
-    let window = sdl_video
-        .window(
-            "Rugby",
-            (SCREEN_WIDTH * WINDOW_SCALE) as u32,
-            (SCREEN_HEIGHT * WINDOW_SCALE) as u32,
-        )
-        .build()
-        .expect("Failed to create SDL window");
-    let mut canvas = window.into_canvas().build().expect("Failed to get SDL window canvas");
+    let mut canvas = create_canvas(&sdl, cpu, config);
     let mut sdl_events = sdl.event_pump().expect("Failed to get SDL event pump");
 
     let mut sdl_fps = FPSManager::new();
@@ -266,14 +557,7 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
     let sdl_controllers = sdl.game_controller().expect("Failed to get SDL game controllers");
     let mut controllers = vec![];
 
-    let sdl_audio = sdl.audio().expect("Failed to access SDL audio subsystem");
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1), // mono
-        samples: Some(SAMPLE_BUFFER_SIZE as u16),
-    };
-    let mut audio_queue = sdl_audio.open_queue(None, &desired_spec).expect("Failed to open audio queue");
-    audio_queue.resume();
+    let mut audio_queue = open_audio_queue(&sdl, cpu, config);
 
     let reader = Interface::new("rugby-interactive-debugger").expect("Failed to create interactive terminal");
     println!("\nWelcome to the rugby debugger! Press h for help");
@@ -288,15 +572,18 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
                 println!("{}", COMMANDS);
             }
             "p" => {
-                run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, None, &watches)
+                run_emulator(cpu, config, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, None, &watches)
             }
             "s" => {
                 let n= if let Some(x) = args.parse::<usize>().ok() { x } else { 1 };
-                run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, Some(n), &watches)
+                run_emulator(cpu, config, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, Some(n), &watches)
             }
             "rr" => {
                 cpu.print_regs();
             }
+            "a" => {
+                print_audio(cpu)
+            }
             "rm" => {
                 print_mem(cpu, args)
             }
@@ -363,6 +650,27 @@ fn print_mem(cpu: &mut Cpu, args: &str) -> () {
     }
 }
 
+fn print_audio(cpu: &Cpu) {
+    let audio = &cpu.audio;
+    let channels = [
+        audio.channel1.to_string(),
+        audio.channel2.to_string(),
+        audio.channel3.to_string(),
+        audio.channel4.to_string(),
+    ];
+    for (i, channel) in channels.iter().enumerate() {
+        let note = if audio.channel_audible(i + 1) { "" } else { " (silenced)" };
+        println!("CH{}{}: {}", i + 1, note, channel);
+    }
+
+    // Wave RAM as a graph, one column per sample
+    let samples: Vec<u8> = audio.channel3.wave_ram.iter().flat_map(|&b| vec![b >> 4, b & 0x0F]).collect();
+    for level in (0..16).rev() {
+        let row: String = samples.iter().map(|&s| if s >= level { '#' } else { ' ' }).collect();
+        println!("{:X} |{}", level, row);
+    }
+}
+
 fn add_mem_watch(watches: &mut HashSet<Watch>, args: &str) {
     let addrs = args.trim().split(" ").collect::<Vec<&str>>();
     match addrs.len() {
@@ -0,0 +1,51 @@
+use super::*;
+use crate::filter::Blend;
+
+fn config(title: &str) -> FrontendConfig {
+    FrontendConfig {
+        palettes: Palette::presets(),
+        palette_index: 0,
+        scale: 1,
+        fit_to_window: false,
+        title: title.to_string(),
+        filter: Filter::new(Blend::None, false),
+        sample_rate: 44100,
+        audio_latency: 50,
+    }
+}
+
+#[test]
+fn test_window_title() {
+    assert_eq!(window_title(&config("TETRIS"), Some(1.0)), "Rugby - TETRIS - 100%");
+    assert_eq!(window_title(&config("TETRIS"), Some(0.996)), "Rugby - TETRIS - 100%");
+    assert_eq!(window_title(&config("TETRIS"), Some(2.5)), "Rugby - TETRIS - 250%");
+    assert_eq!(window_title(&config("TETRIS"), None), "Rugby - TETRIS - Paused");
+    // Games without a title leave it out.
+    assert_eq!(window_title(&config(""), Some(0.5)), "Rugby - 50%");
+}
+
+#[test]
+fn test_screen_rect_integer_scale() {
+    let rect = |window_size| screen_rect(window_size, SCREEN_WIDTH, SCREEN_HEIGHT, false);
+    assert_eq!(rect((480, 432)), Rect::new(0, 0, 480, 432));
+    // The screen is letterboxed at the largest whole multiple that fits.
+    assert_eq!(rect((500, 432)), Rect::new(10, 0, 480, 432));
+    assert_eq!(rect((400, 400)), Rect::new(40, 56, 320, 288));
+    // It is never scaled below 1x, so it gets cropped in a tiny window.
+    assert_eq!(rect((100, 100)), Rect::new(-30, -22, 160, 144));
+
+    // The SGB screen is scaled the same way.
+    let rect = screen_rect((800, 600), SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT, false);
+    assert_eq!(rect, Rect::new(144, 76, 512, 448));
+}
+
+#[test]
+fn test_screen_rect_fit_to_window() {
+    let rect = |window_size| screen_rect(window_size, SCREEN_WIDTH, SCREEN_HEIGHT, true);
+    assert_eq!(rect((480, 432)), Rect::new(0, 0, 480, 432));
+    // The screen fills the window along one axis, keeping its aspect ratio.
+    assert_eq!(rect((400, 400)), Rect::new(0, 20, 400, 360));
+    assert_eq!(rect((1000, 288)), Rect::new(340, 0, 320, 288));
+    // It can shrink below 1x.
+    assert_eq!(rect((80, 100)), Rect::new(0, 14, 80, 72));
+}
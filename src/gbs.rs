@@ -0,0 +1,219 @@
+use crate::cart::CartConfig;
+use crate::cart_header::{CartHardware, CartType};
+use crate::model::Model;
+use failure_derive::Fail;
+
+#[cfg(test)]
+mod test;
+
+/// Size of the GBS header. The music data follows it.
+const HEADER_SIZE: usize = 0x70;
+
+/// The lowest load address allowed, which leaves room below for the driver.
+const MIN_LOAD_ADDR: u16 = 0x0400;
+
+/// Where the driver which calls INIT and then waits for interrupts is placed. This is where
+/// execution starts after the boot ROM.
+const DRIVER_ADDR: usize = 0x0100;
+
+/// Where the CGB flag is in the cartridge header. The driver has to end before it.
+const CGB_FLAG_ADDR: usize = 0x0143;
+
+/// The smallest ROM an MBC5 cartridge can have.
+const MIN_ROM_SIZE: usize = 0x8000;
+
+/// Size of the cartridge RAM, which some GBS files use for variables.
+const RAM_SIZE: usize = 0x2000;
+
+/// A GBS (Game Boy Sound System) file, which holds the music code and data ripped from a game,
+/// plus the addresses of the routines which start a song and play it.
+#[derive(Clone, Debug)]
+pub struct Gbs {
+    /// Number of songs in the file.
+    pub song_count: u8,
+
+    /// The song to play first, counting from 1.
+    pub first_song: u8,
+
+    /// Where the data is loaded in the address space.
+    load_addr: u16,
+
+    /// The routine which starts a song, called with the song number (counting from 0) in A.
+    init_addr: u16,
+
+    /// The routine which plays the next bit of the song, called at a steady rate.
+    play_addr: u16,
+
+    /// The initial stack pointer.
+    stack_pointer: u16,
+
+    /// The timer modulo `TMA`, if the timer calls PLAY.
+    timer_modulo: u8,
+
+    /// The timer control `TAC`. If bit 2 is set the timer calls PLAY, otherwise VBlank does. If
+    /// bit 7 is set the music expects the CGB's double speed mode.
+    timer_control: u8,
+
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+
+    /// The code and data, loaded at `load_addr`.
+    data: Box<[u8]>,
+}
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum GbsError {
+    #[fail(display = "file is {} bytes, which is too short for a GBS header", _0)]
+    TooShort(usize),
+
+    #[fail(display = "not a GBS file (missing the 'GBS' signature)")]
+    InvalidSignature,
+
+    #[fail(display = "unsupported GBS version {}", _0)]
+    UnsupportedVersion(u8),
+
+    #[fail(display = "load address 0x{:04X} is below 0x0400", _0)]
+    InvalidLoadAddress(u16),
+
+    #[fail(display = "data doesn't fit in a cartridge when loaded at 0x{:04X}", _0)]
+    TooLarge(u16),
+
+    #[fail(display = "song {} doesn't exist (the file has songs 1 to {})", song, count)]
+    InvalidSong { song: u8, count: u8 },
+}
+
+impl Gbs {
+    pub fn parse(file: &[u8]) -> Result<Gbs, GbsError> {
+        let header = file.get(..HEADER_SIZE).ok_or(GbsError::TooShort(file.len()))?;
+        if &header[0x00..0x03] != b"GBS" {
+            return Err(GbsError::InvalidSignature);
+        }
+        if header[0x03] != 1 {
+            return Err(GbsError::UnsupportedVersion(header[0x03]));
+        }
+
+        let word = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let text = |range: std::ops::Range<usize>| {
+            let bytes = &header[range];
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+        };
+
+        let load_addr = word(0x06);
+        if load_addr < MIN_LOAD_ADDR {
+            return Err(GbsError::InvalidLoadAddress(load_addr));
+        }
+        let data = file[HEADER_SIZE..].to_vec().into_boxed_slice();
+        // MBC5 has up to 512 banks of ROM.
+        if load_addr as usize + data.len() > 512 * 0x4000 {
+            return Err(GbsError::TooLarge(load_addr));
+        }
+
+        Ok(Gbs {
+            song_count: header[0x04],
+            first_song: header[0x05].max(1),
+            load_addr,
+            init_addr: word(0x08),
+            play_addr: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: header[0x0E],
+            timer_control: header[0x0F],
+            title: text(0x10..0x30),
+            author: text(0x30..0x50),
+            copyright: text(0x50..0x70),
+            data,
+        })
+    }
+
+    /// The model to play the music on. Music which expects double speed needs a CGB.
+    pub fn model(&self) -> Model {
+        if self.double_speed() { Model::Cgb } else { Model::Dmg }
+    }
+
+    fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    /// The cartridge a ROM built by `rom` goes in.
+    pub fn cart_config(&self, rom_size: usize) -> CartConfig {
+        CartConfig { cart_type: CartType::Mbc5, hardware: CartHardware::Ram.into(), rom_size, ram_size: RAM_SIZE, multicart: None }
+    }
+
+    /// Build a cartridge ROM which plays the given song, counting from 1. A small driver at the
+    /// entry point calls INIT and then halts forever, while the VBlank or timer interrupt calls
+    /// PLAY.
+    pub fn rom(&self, song: u8) -> Result<Box<[u8]>, GbsError> {
+        if song == 0 || song > self.song_count {
+            return Err(GbsError::InvalidSong { song, count: self.song_count });
+        }
+
+        let data_end = self.load_addr as usize + self.data.len();
+        let mut rom = vec![0xFF; data_end.max(MIN_ROM_SIZE).next_power_of_two()];
+        rom[self.load_addr as usize..data_end].copy_from_slice(&self.data);
+
+        // The RST instructions jump to the matching offset from the load address.
+        for rst in (0x00..0x40).step_by(8) {
+            let [lo, hi] = (self.load_addr + rst).to_le_bytes();
+            rom[rst as usize..rst as usize + 3].copy_from_slice(&[0xC3, lo, hi]); // JP
+        }
+
+        // Interrupts other than the one driving PLAY just return.
+        let use_timer = self.timer_control & 0b100 != 0;
+        let [play_lo, play_hi] = self.play_addr.to_le_bytes();
+        for (i, vector) in (0x40..0x68).step_by(8).enumerate() {
+            let drives_play = if use_timer { i == 2 } else { i == 0 };
+            if drives_play {
+                rom[vector..vector + 4].copy_from_slice(&[0xCD, play_lo, play_hi, 0xD9]); // CALL, RETI
+            } else {
+                rom[vector] = 0xD9; // RETI
+            }
+        }
+
+        let [sp_lo, sp_hi] = self.stack_pointer.to_le_bytes();
+        let [init_lo, init_hi] = self.init_addr.to_le_bytes();
+        let interrupt = if use_timer { 0b100 } else { 0b001 };
+        let mut driver = vec![
+            0xF3,                      // DI
+            0x31, sp_lo, sp_hi,        // LD SP, stack_pointer
+        ];
+        if self.double_speed() {
+            // Mark the cartridge as supporting the CGB, so it runs in CGB mode, and switch speeds.
+            rom[CGB_FLAG_ADDR] = 0x80;
+            driver.extend_from_slice(&[
+                0x3E, 0x01,            // LD A, 1
+                0xE0, 0x4D,            // LDH (KEY1), A      ; Prepare a speed switch
+                0x10, 0x00,            // STOP
+            ]);
+        }
+        driver.extend_from_slice(&[
+            0x3E, 0x0A,                // LD A, 0x0A
+            0xEA, 0x00, 0x00,          // LD (0x0000), A     ; Enable cartridge RAM
+            0x3E, 0x01,                // LD A, 1
+            0xEA, 0x00, 0x20,          // LD (0x2000), A     ; Map bank 1 at 0x4000
+            0x3E, 0x80,                // LD A, 0x80
+            0xE0, 0x26,                // LDH (NR52), A      ; Sound on
+            0x3E, 0x77,                // LD A, 0x77
+            0xE0, 0x24,                // LDH (NR50), A      ; Full volume
+            0x3E, 0xFF,                // LD A, 0xFF
+            0xE0, 0x25,                // LDH (NR51), A      ; All channels to both sides
+            0x3E, song - 1,            // LD A, song - 1
+            0xCD, init_lo, init_hi,    // CALL init
+            0x3E, self.timer_modulo,   // LD A, timer_modulo
+            0xE0, 0x06,                // LDH (TMA), A
+            0x3E, self.timer_control,  // LD A, timer_control
+            0xE0, 0x07,                // LDH (TAC), A
+            0x3E, interrupt,           // LD A, interrupt
+            0xE0, 0xFF,                // LDH (IE), A
+            0xAF,                      // XOR A
+            0xE0, 0x0F,                // LDH (IF), A
+            0xFB,                      // EI
+            0x76,                      // HALT
+            0x18, 0xFD,                // JR -3
+        ]);
+        debug_assert!(DRIVER_ADDR + driver.len() <= CGB_FLAG_ADDR);
+        rom[DRIVER_ADDR..DRIVER_ADDR + driver.len()].copy_from_slice(&driver);
+
+        Ok(rom.into_boxed_slice())
+    }
+}
@@ -0,0 +1,131 @@
+use super::*;
+
+/// INIT stores the song number at 0xC000, and PLAY counts its calls at 0xC001.
+const MUSIC_CODE: [u8; 9] = [
+    0xEA, 0x00, 0xC0, // ld (0xC000), a
+    0xC9,             // ret
+    0x21, 0x01, 0xC0, // ld hl, 0xC001
+    0x34,             // inc (hl)
+    0xC9,             // ret
+];
+
+/// A GBS file with the music code loaded at `load_addr`.
+fn gbs_file(load_addr: u16, timer_control: u8) -> Vec<u8> {
+    let mut file = vec![0; HEADER_SIZE];
+    file[0x00..0x04].copy_from_slice(b"GBS\x01");
+    file[0x04] = 3; // Song count
+    file[0x05] = 2; // First song
+    file[0x06..0x08].copy_from_slice(&load_addr.to_le_bytes());
+    file[0x08..0x0A].copy_from_slice(&load_addr.to_le_bytes());
+    file[0x0A..0x0C].copy_from_slice(&(load_addr + 4).to_le_bytes());
+    file[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+    file[0x0E] = 0xC0;
+    file[0x0F] = timer_control;
+    file[0x10..0x15].copy_from_slice(b"Theme");
+    file[0x30..0x37].copy_from_slice(b"Someone");
+    file[0x50..0x54].copy_from_slice(b"1998");
+    file.extend_from_slice(&MUSIC_CODE);
+    file
+}
+
+#[test]
+fn test_parse() {
+    let gbs = Gbs::parse(&gbs_file(0x0400, 0)).unwrap();
+    assert_eq!((gbs.song_count, gbs.first_song), (3, 2));
+    assert_eq!((gbs.load_addr, gbs.init_addr, gbs.play_addr), (0x0400, 0x0400, 0x0404));
+    assert_eq!(gbs.stack_pointer, 0xDFFF);
+    assert_eq!((gbs.title.as_str(), gbs.author.as_str(), gbs.copyright.as_str()), ("Theme", "Someone", "1998"));
+    assert_eq!(&gbs.data[..], &MUSIC_CODE[..]);
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(Gbs::parse(&[0; 0x20]).unwrap_err(), GbsError::TooShort(0x20));
+
+    let mut file = gbs_file(0x0400, 0);
+    file[0x00] = b'X';
+    assert_eq!(Gbs::parse(&file).unwrap_err(), GbsError::InvalidSignature);
+
+    let mut file = gbs_file(0x0400, 0);
+    file[0x03] = 2;
+    assert_eq!(Gbs::parse(&file).unwrap_err(), GbsError::UnsupportedVersion(2));
+
+    assert_eq!(Gbs::parse(&gbs_file(0x03FF, 0)).unwrap_err(), GbsError::InvalidLoadAddress(0x03FF));
+
+    let mut file = gbs_file(0x4000, 0);
+    file.resize(HEADER_SIZE + 511 * 0x4000 + 1, 0);
+    assert_eq!(Gbs::parse(&file).unwrap_err(), GbsError::TooLarge(0x4000));
+}
+
+#[test]
+fn test_rom_layout() {
+    let gbs = Gbs::parse(&gbs_file(0x0400, 0)).unwrap();
+    assert_eq!(gbs.rom(0).unwrap_err(), GbsError::InvalidSong { song: 0, count: 3 });
+    assert_eq!(gbs.rom(4).unwrap_err(), GbsError::InvalidSong { song: 4, count: 3 });
+
+    let rom = gbs.rom(1).unwrap();
+    assert_eq!(rom.len(), MIN_ROM_SIZE);
+    assert_eq!(&rom[0x0400..0x0409], &MUSIC_CODE[..]);
+
+    // RST 0x38 jumps to the load address plus 0x38.
+    assert_eq!(&rom[0x38..0x3B], &[0xC3, 0x38, 0x04]);
+
+    // VBlank calls PLAY, and the other interrupts return.
+    assert_eq!(&rom[0x40..0x44], &[0xCD, 0x04, 0x04, 0xD9]);
+    for &vector in &[0x48, 0x50, 0x58, 0x60] {
+        assert_eq!(rom[vector], 0xD9);
+    }
+    assert_eq!(rom[DRIVER_ADDR], 0xF3);
+
+    // With bit 2 of TAC set, the timer calls PLAY instead.
+    let gbs = Gbs::parse(&gbs_file(0x0400, 0b100)).unwrap();
+    let rom = gbs.rom(1).unwrap();
+    assert_eq!(rom[0x40], 0xD9);
+    assert_eq!(&rom[0x50..0x54], &[0xCD, 0x04, 0x04, 0xD9]);
+
+    // Data past 32 KB rounds the ROM up to a power of two.
+    let mut file = gbs_file(0x7000, 0);
+    file.resize(HEADER_SIZE + 0x2000, 0);
+    assert_eq!(Gbs::parse(&file).unwrap().rom(1).unwrap().len(), 0x10000);
+}
+
+/// Play the given song for 10 frames on the model the file asks for.
+fn play(gbs: &Gbs, song: u8) -> crate::cpu::Cpu {
+    use crate::cart::Cart;
+    use crate::cpu::Cpu;
+    use std::collections::HashSet;
+
+    let rom = gbs.rom(song).unwrap();
+    let cart = Cart::new(rom.clone(), None, &gbs.cart_config(rom.len())).unwrap();
+    let mut cpu = Cpu::new(cart, gbs.model(), None);
+    cpu.step_cycles(10 * 70224, &HashSet::new());
+    cpu
+}
+
+#[test]
+fn test_rom_plays() {
+    // PLAY is called once per VBlank.
+    let gbs = Gbs::parse(&gbs_file(0x0400, 0)).unwrap();
+    assert_eq!(gbs.model(), Model::Dmg);
+    let cpu = play(&gbs, 3);
+    assert_eq!(cpu.read_mem_debug(0xC000), 2);
+    assert!((9..=10).contains(&cpu.read_mem_debug(0xC001)));
+
+    // With TMA at 0xC0 the timer overflows 64 times a second, after a first overflow from 0 which
+    // takes 1/16 of a second.
+    let cpu = play(&Gbs::parse(&gbs_file(0x0400, 0b100)).unwrap(), 1);
+    assert!((6..=7).contains(&cpu.read_mem_debug(0xC001)));
+}
+
+#[test]
+fn test_rom_plays_double_speed() {
+    // Bit 7 of TAC asks for double speed, which runs the timer twice as fast.
+    let gbs = Gbs::parse(&gbs_file(0x0400, 0b1000_0100)).unwrap();
+    assert_eq!(gbs.model(), Model::Cgb);
+    assert_eq!(gbs.rom(1).unwrap()[CGB_FLAG_ADDR], 0x80);
+
+    let cpu = play(&gbs, 1);
+    assert_eq!(cpu.read_mem_debug(0xFF4D) & 0x80, 0x80);
+    assert_eq!(cpu.read_mem_debug(0xC000), 0);
+    assert!((17..=18).contains(&cpu.read_mem_debug(0xC001)));
+}
@@ -0,0 +1,54 @@
+/// Number of bytes of palette RAM: 8 palettes of 4 colors, 2 bytes per color.
+const PALETTE_RAM_SIZE: usize = 64;
+
+/// CGB color palette RAM, accessed through an index register (BCPS/OCPS at 0xFF68/0xFF6A) and a
+/// data register (BCPD/OCPD at 0xFF69/0xFF6B).
+#[derive(Clone)]
+pub struct ColorPaletteRam {
+    /// The palette data. Each color is a little-endian 15-bit BGR value.
+    ram: [u8; PALETTE_RAM_SIZE],
+
+    /// The byte of palette RAM accessed by the data register. Bits 0-5 of the index register.
+    index: u8,
+
+    /// True if writes to the data register increment `index`. Bit 7 of the index register.
+    auto_increment: bool,
+}
+
+impl ColorPaletteRam {
+    pub fn new() -> Self {
+        Self {
+            // Start out with every color white.
+            ram: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        // Bit 6 is unused and always 1.
+        (self.auto_increment as u8) << 7 | 0b0100_0000 | self.index
+    }
+
+    pub fn write_index(&mut self, val: u8) {
+        self.index = val & 0b0011_1111;
+        self.auto_increment = (val >> 7) & 1 == 1;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.ram[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.ram[self.index as usize] = val;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0b0011_1111;
+        }
+    }
+
+    /// Get the 15-bit color for the given palette number (0-7) and color number (0-3).
+    pub fn color(&self, palette_num: u8, color_num: u8) -> u16 {
+        let i = (palette_num as usize * 4 + color_num as usize) * 2;
+        u16::from_le_bytes([self.ram[i], self.ram[i + 1]]) & 0x7FFF
+    }
+}
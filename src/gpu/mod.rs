@@ -1,21 +1,26 @@
 use enumflags2::BitFlags;
-use std::collections::BinaryHeap;
 use crate::interrupts::Interrupt;
 
+mod color_palette;
 mod sprite;
 
+#[cfg(test)]
+mod test;
+
 const HORIZONTAL_BLANK_CYCLES: usize = 204; // Horizontal blank phase takes 201-207 cycles.
 const OAM_READ_CYCLES: usize = 80; // OAM read phase takes 77-83 cycles.
 const VRAM_READ_CYCLES: usize = 172; // VRAM read phase takes 169-175 cycles.
 const SCAN_LINE_CYCLES: usize = 456; // One scan line takes 456 cycles.
 const VERTICAL_BLANK_START_LINE: u8 = 144; // The scan line at which we enter the vertical blank phase
 const VERTICAL_BLANK_END_LINE: u8 = 154; // The scan line at which the vertical blank phase ends
-const VIDEO_RAM_SIZE: usize = 8 * 1024; // 8 KB
-const TOTAL_TILES: usize = 384; // Total number of tiles in video ram
+const VIDEO_RAM_BANK_SIZE: usize = 8 * 1024; // 8 KB
+const VIDEO_RAM_BANKS: usize = 2; // The CGB has 2 banks of video ram. The DMG only uses bank 0
+const TILES_PER_BANK: usize = 384; // Number of tiles in each bank of video ram
 const TILE_MAP_0_START: usize = 0x1800; // The starting address of tile map 0
 const SPRITE_RAM_SIZE: usize = 160; // For the address range 0xFE00-0xFE9F (inclusive).
 const TOTAL_SPRITES: usize = 40; // The number of sprites in sprite ram
 const BYTES_PER_SPRITE: usize = 4;
+const MAX_SPRITES_PER_LINE: usize = 10; // The OAM scan selects at most 10 sprites per scan line
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 
@@ -75,6 +80,20 @@ impl std::convert::From<u8> for ObjSize {
     }
 }
 
+/// Which layer a pixel on the screen came from, so the frontend can color each layer with its own
+/// palette.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    /// The background or the window.
+    Background,
+
+    /// A sprite using OBP0.
+    Obj0,
+
+    /// A sprite using OBP1.
+    Obj1,
+}
+
 type Tile = [[u8; 8]; 8];
 
 fn init_tile() -> Tile {
@@ -86,16 +105,30 @@ pub struct Gpu {
     /// Current screen
     pub screen_buffer: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
 
-    /// The current background
-    background: Box<[[u8; 256]; 256]>,
+    /// The layer each pixel of `screen_buffer` came from.
+    pub layer_buffer: Box<[[Layer; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
 
-    /// The current window
-    window: Box<[[u8; 256]; 256]>,
+    /// Current screen in CGB mode, as 15-bit colors. Bits 0-4 are red, 5-9 green and 10-14 blue.
+    pub color_screen_buffer: Box<[[u16; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
 
-    /// Video RAM internal to the Game Boy.
+    /// True if the CGB-only features are enabled.
+    cgb_mode: bool,
+
+    /// The background and window color numbers of the current scan line, before the background
+    /// palette is applied. Sprite-to-background priority is decided using these.
+    bg_color_line: [u8; SCREEN_WIDTH],
+
+    /// The CGB background-to-OAM priority attribute of each background and window pixel on the
+    /// current scan line.
+    bg_priority_line: [bool; SCREEN_WIDTH],
+
+    /// Video RAM internal to the Game Boy. Bank 1 is only used in CGB mode.
     video_ram: Box<[u8]>,
 
-    /// The current tiles in video ram.
+    /// The currently selected video ram bank. (VBK at 0xFF4F, CGB only)
+    video_ram_bank: usize,
+
+    /// The current tiles in video ram. Tiles from bank 1 come after the tiles from bank 0.
     tile_set: Box<[Tile]>,
 
     /// Sprite RAM internal to the Game Boy, also known as OAM.
@@ -170,18 +203,36 @@ pub struct Gpu {
 
     /// Second sprite palette register
     obj_palette_1: u8,
+
+    /// Background color palettes. (BCPS/BCPD at 0xFF68-0xFF69, CGB only)
+    bg_color_palettes: color_palette::ColorPaletteRam,
+
+    /// Sprite color palettes. (OCPS/OCPD at 0xFF6A-0xFF6B, CGB only)
+    obj_color_palettes: color_palette::ColorPaletteRam,
+
+    /// True if overlapping sprites are prioritized by X coordinate like on the DMG, rather than by
+    /// OAM position. (Bit 0 of OPRI at 0xFF6C, CGB only)
+    obj_priority_by_x: bool,
+
+    /// Set when the LCD enters H-Blank on a visible line, which is when H-Blank DMA transfers
+    /// happen.
+    entered_hblank: bool,
 }
 
 impl Gpu {
-    pub fn new() -> Gpu {
+    pub fn new(cgb_mode: bool) -> Gpu {
         let mut gpu = Gpu {
             // TODO(solson): Figure out a clean way to allocate 2D arrays like these directly on
             // the heap (without giving up the `arr[i][j]` multidimensional indexing).
             screen_buffer: Box::new([[0u8; SCREEN_WIDTH]; SCREEN_HEIGHT]),
-            background: Box::new([[0u8; 256]; 256]),
-            window: Box::new([[0u8; 256]; 256]),
-            video_ram: vec![0; VIDEO_RAM_SIZE].into_boxed_slice(),
-            tile_set: vec![init_tile(); TOTAL_TILES].into_boxed_slice(),
+            layer_buffer: Box::new([[Layer::Background; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            color_screen_buffer: Box::new([[0u16; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            cgb_mode,
+            bg_color_line: [0; SCREEN_WIDTH],
+            bg_priority_line: [false; SCREEN_WIDTH],
+            video_ram: vec![0; VIDEO_RAM_BANK_SIZE * VIDEO_RAM_BANKS].into_boxed_slice(),
+            video_ram_bank: 0,
+            tile_set: vec![init_tile(); TILES_PER_BANK * VIDEO_RAM_BANKS].into_boxed_slice(),
             sprite_ram: vec![0; SPRITE_RAM_SIZE].into_boxed_slice(),
             sprites: vec![sprite::Sprite::new(); TOTAL_SPRITES].into_boxed_slice(),
             cycles: 0,
@@ -207,6 +258,10 @@ impl Gpu {
             background_palette: 0,
             obj_palette_0: 0,
             obj_palette_1: 1,
+            bg_color_palettes: color_palette::ColorPaletteRam::new(),
+            obj_color_palettes: color_palette::ColorPaletteRam::new(),
+            obj_priority_by_x: !cgb_mode,
+            entered_hblank: false,
         };
         for i in 0..TOTAL_SPRITES {
             gpu.sprites[i].index = i;
@@ -214,6 +269,41 @@ impl Gpu {
         gpu
     }
 
+    /// Returns true if the CGB-only features are enabled.
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// Turn off the CGB-only features, as the CGB boot ROM does before starting a game without
+    /// CGB support.
+    pub fn enter_dmg_compat_mode(&mut self) {
+        self.cgb_mode = false;
+        self.obj_priority_by_x = true;
+    }
+
+    /// Returns true once each time the LCD enters H-Blank on a visible line.
+    pub fn take_entered_hblank(&mut self) -> bool {
+        std::mem::replace(&mut self.entered_hblank, false)
+    }
+
+    /// Returns true if the CPU can currently access VRAM. VRAM is locked while the LCD is reading
+    /// from it in mode 3.
+    pub fn vram_accessible(&self) -> bool {
+        match self.mode {
+            Mode::VRamRead => !self.lcd_enabled,
+            _ => true,
+        }
+    }
+
+    /// Returns true if the CPU can currently access OAM. OAM is locked while the LCD is reading
+    /// from it in modes 2 and 3.
+    pub fn oam_accessible(&self) -> bool {
+        match self.mode {
+            Mode::OamRead | Mode::VRamRead => !self.lcd_enabled,
+            _ => true,
+        }
+    }
+
     pub fn read_sprite_ram(&self, addr: usize) -> u8 {
         self.sprite_ram[addr]
     }
@@ -233,26 +323,28 @@ impl Gpu {
 
     }
 
+    /// Read video ram from the currently selected bank.
     pub fn read_vram(&self, addr: usize) -> u8 {
-        self.video_ram[addr]
+        self.video_ram[self.video_ram_bank * VIDEO_RAM_BANK_SIZE + addr]
     }
 
-    /// Write video ram
+    /// Write video ram in the currently selected bank.
     ///
     /// This function also keeps the current tile set up to date
     pub fn write_vram(&mut self, addr: usize, val: u8) {
-        self.video_ram[addr] = val;
+        let bank_start = self.video_ram_bank * VIDEO_RAM_BANK_SIZE;
+        self.video_ram[bank_start + addr] = val;
         if addr >= TILE_MAP_0_START {
             return
         }
 
         // Each row in the tile (8x8 pixels) is 2 bytes
-        let row_start = addr & 0xFFFE;
+        let row_start = bank_start + (addr & 0xFFFE);
         let byte1 = self.video_ram[row_start];
         let byte2 = self.video_ram[row_start + 1];
 
         // Each tile is 16 byte total
-        let tile_index = addr / 16;
+        let tile_index = self.video_ram_bank * TILES_PER_BANK + addr / 16;
         // Each row in a tile is 2 bytes
         let tile_row_index = (addr % 16) / 2;
 
@@ -328,6 +420,7 @@ impl Gpu {
                 if self.cycles > VRAM_READ_CYCLES {
                     self.cycles %= VRAM_READ_CYCLES;
                     self.mode = Mode::HorizontalBlank;
+                    self.entered_hblank = true;
                     self.render_scan_line();
 
                     if self.horizontal_blank_interrupt {
@@ -341,11 +434,22 @@ impl Gpu {
     }
 
     fn render_scan_line(&mut self) {
-        if self.background_enabled {
+        // In CGB mode, clearing LCDC bit 0 doesn't hide the background and window. It only takes
+        // away their priority over sprites.
+        if self.background_enabled || self.cgb_mode {
             self.render_background_line();
-        }
-        if self.window_enabled {
-            self.render_window_line();
+            if self.window_enabled {
+                self.render_window_line();
+            }
+        } else {
+            // On the DMG, clearing LCDC bit 0 blanks both the background and the window to white.
+            let line = self.scan_line as usize;
+            for x in 0..SCREEN_WIDTH {
+                self.screen_buffer[line][x] = 0;
+                self.layer_buffer[line][x] = Layer::Background;
+                self.bg_color_line[x] = 0;
+                self.bg_priority_line[x] = false;
+            }
         }
         if self.obj_display_enabled {
             self.render_sprite_line();
@@ -353,117 +457,159 @@ impl Gpu {
     }
 
     fn render_background_line(&mut self) {
-        let background_map = match self.background_tile_map {
-            TileMapLocation::X9800 => &self.video_ram[0x1800..0x1C00],
-            TileMapLocation::X9C00 => &self.video_ram[0x1C00..0x2000],
-        };
-
-        for i in 0..0x400 {
-            let curr_tile_index: u8 = background_map[i];
-            let curr_tile = match self.background_and_window_location {
-                BackgroundAndWindowLocation::X8000 => self.tile_set[curr_tile_index as usize],
-                BackgroundAndWindowLocation::X8800 => self.tile_set[(256 + ((curr_tile_index as i8) as i16)) as usize]
-            };
-
-            let curr_tile_row = i / 32;
-            let curr_tile_col = i % 32;
-
-            for r in 0..8 {
-                for c in 0..8 {
-                    self.background[curr_tile_row * 8 + r][curr_tile_col * 8 + c] = curr_tile[r][c];
-                }
-            }
+        let y = self.scan_line.wrapping_add(self.scan_y);
+        for screen_x in 0..SCREEN_WIDTH {
+            let x = self.scan_x.wrapping_add(screen_x as u8);
+            let (color_num, attributes) = self.tile_map_pixel(self.background_tile_map, x, y);
+            self.draw_background_pixel(screen_x, color_num, attributes);
         }
+    }
 
-        for i in 0..SCREEN_WIDTH {
-            let pixel_x = (self.scan_x as usize + i) % 256;
-            let pixel_y = (self.scan_line as usize + self.scan_y as usize) % 256;
-            let color = get_palette_color(self.background[pixel_y][pixel_x], self.background_palette);
-            self.screen_buffer[self.scan_line as usize][i] = color;
+    fn render_window_line(&mut self) {
+        let (y, overflow) = self.scan_line.overflowing_sub(self.window_y);
+        if overflow {
+            return;
+        }
+        for screen_x in (self.window_x as usize)..SCREEN_WIDTH {
+            let x = (screen_x - self.window_x as usize) as u8;
+            let (color_num, attributes) = self.tile_map_pixel(self.window_tile_map, x, y);
+            self.draw_background_pixel(screen_x, color_num, attributes);
         }
     }
 
-    fn render_window_line(&mut self) {
-        let window_map = match self.window_tile_map {
-            TileMapLocation::X9800 => &self.video_ram[0x1800..0x1C00],
-            TileMapLocation::X9C00 => &self.video_ram[0x1C00..0x2000],
+    /// Look up a pixel in the 256x256 pixel background or window tile map. Returns the color
+    /// number of the pixel and the CGB attributes of its tile, which are always 0 on the DMG.
+    fn tile_map_pixel(&self, tile_map: TileMapLocation, x: u8, y: u8) -> (u8, u8) {
+        let map_start = match tile_map {
+            TileMapLocation::X9800 => 0x1800,
+            TileMapLocation::X9C00 => 0x1C00,
+        };
+        let map_index = map_start + (y as usize / 8) * 32 + (x as usize / 8);
+        let tile_index = self.video_ram[map_index];
+
+        // In CGB mode, each tile's attributes are at the same position in VRAM bank 1.
+        let attributes = if self.cgb_mode {
+            self.video_ram[VIDEO_RAM_BANK_SIZE + map_index]
+        } else {
+            0
         };
 
-        for i in 0..0x400 {
-            let curr_tile_index: u8 = window_map[i];
-            let curr_tile = match self.background_and_window_location {
-                BackgroundAndWindowLocation::X8000 => self.tile_set[curr_tile_index as usize],
-                BackgroundAndWindowLocation::X8800 => self.tile_set[(256 + ((curr_tile_index as i8) as i16)) as usize]
-            };
-
-            let curr_tile_row = i / 32;
-            let curr_tile_col = i % 32;
+        let mut tile_num = match self.background_and_window_location {
+            BackgroundAndWindowLocation::X8000 => tile_index as usize,
+            BackgroundAndWindowLocation::X8800 => (256 + ((tile_index as i8) as i16)) as usize,
+        };
+        if (attributes >> 3) & 1 == 1 {
+            tile_num += TILES_PER_BANK;
+        }
 
-            for r in 0..8 {
-                for c in 0..8 {
-                    self.window[curr_tile_row * 8 + r][curr_tile_col * 8 + c] = curr_tile[r][c];
-                }
-            }
+        let mut row = y as usize % 8;
+        let mut col = x as usize % 8;
+        if (attributes >> 6) & 1 == 1 {
+            row = 7 - row;
+        }
+        if (attributes >> 5) & 1 == 1 {
+            col = 7 - col;
         }
 
-        for screen_x in (self.window_x as usize)..SCREEN_WIDTH {
-            let (y, overflow) = self.scan_line.overflowing_sub(self.window_y);
-            if !overflow && (y as usize) < SCREEN_HEIGHT {
-                let color = get_palette_color(self.window[y as usize][screen_x - (self.window_x as usize)], self.background_palette);
-                self.screen_buffer[self.scan_line as usize][screen_x] = color;
-            }
+        (self.tile_set[tile_num][row][col], attributes)
+    }
+
+    /// Draw a background or window pixel on the current scan line.
+    fn draw_background_pixel(&mut self, screen_x: usize, color_num: u8, attributes: u8) {
+        let line = self.scan_line as usize;
+        self.bg_color_line[screen_x] = color_num;
+        self.bg_priority_line[screen_x] = (attributes >> 7) & 1 == 1;
+        if self.cgb_mode {
+            self.color_screen_buffer[line][screen_x] =
+                self.bg_color_palettes.color(attributes & 0b111, color_num);
+        } else {
+            self.screen_buffer[line][screen_x] =
+                get_palette_color(color_num, self.background_palette);
+            self.layer_buffer[line][screen_x] = Layer::Background;
         }
     }
 
+    /// Draw the sprites on the current scan line over the background and window.
+    ///
+    /// The OAM scan picks the first 10 sprites in OAM order which overlap the line. Where those
+    /// sprites overlap each other, on the DMG the one with the smaller X coordinate wins, with
+    /// ties going to the one earlier in OAM. In CGB mode the one earlier in OAM always wins.
     fn render_sprite_line(&mut self) {
         let height = match self.obj_size {
             ObjSize::EightBySixteen => 16,
             ObjSize::EightByEight => 8,
         };
 
-        let mut heap = BinaryHeap::new();
-        for i in 0..TOTAL_SPRITES {
-            let s = self.sprites[i];
-            // Sprites at y = 0 or y >= 160 are off screen
-            if self.scan_line.wrapping_sub(s.y) < height {
-                heap.push(s);
-            }
+        // Sprites outside the screen horizontally still count towards the 10 sprite limit.
+        let mut sprites_on_line: Vec<sprite::Sprite> = self.sprites
+            .iter()
+            .filter(|s| self.scan_line.wrapping_sub(s.y) < height)
+            .take(MAX_SPRITES_PER_LINE)
+            .cloned()
+            .collect();
+        if self.obj_priority_by_x {
+            sprites_on_line.sort_by_key(|s| (s.oam_x(), s.index));
         }
-        let mut sprites_to_render = heap.into_sorted_vec();
-        sprites_to_render.truncate(10);
-        for s in sprites_to_render.iter().rev() {
-            let mut tile_num = s.tile_num as usize;
-            let mut line = if s.flip_y {
-                height - self.scan_line.wrapping_sub(s.y) - 1
-            } else {
-                self.scan_line.wrapping_sub(s.y)
-            };
 
-            if line >= 8 {
-                tile_num += 1;
-                line -= 8;
+        // Tracks which pixels already have an opaque pixel from a higher priority sprite. This is
+        // set even if that sprite ends up hidden behind the background, since lower priority
+        // sprites don't show through it.
+        let mut pixel_taken = [false; SCREEN_WIDTH];
+
+        for s in &sprites_on_line {
+            let mut line = self.scan_line.wrapping_sub(s.y);
+            if s.flip_y {
+                line = height - line - 1;
             }
 
-            let tile =  self.tile_set[tile_num];
+            // For 8x16 sprites, bit 0 of the tile number is ignored. The top half uses the even
+            // tile and the bottom half uses the odd tile.
+            let mut tile_num = match self.obj_size {
+                ObjSize::EightByEight => s.tile_num as usize,
+                ObjSize::EightBySixteen => (s.tile_num & 0xFE) as usize + (line / 8) as usize,
+            };
+            if self.cgb_mode {
+                tile_num += s.vram_bank as usize * TILES_PER_BANK;
+            }
+            let tile = self.tile_set[tile_num];
 
-            for x in (0..8).rev() {
-                let tile_x = if s.flip_x {
-                    7 - x
+            for x in 0..8 {
+                let target_x = s.x.wrapping_add(x) as usize;
+                if target_x >= SCREEN_WIDTH || pixel_taken[target_x] {
+                    continue;
+                }
+
+                let tile_x = if s.flip_x { 7 - x } else { x } as usize;
+                let color_num = tile[(line % 8) as usize][tile_x];
+                // Color 0 is transparent for sprites.
+                if color_num == 0 {
+                    continue;
+                }
+                pixel_taken[target_x] = true;
+
+                // Sprites behind the background are only visible over background color 0. This
+                // checks the color number, not the color after the palette is applied. In CGB
+                // mode, background tiles can also claim priority, and clearing LCDC bit 0 puts
+                // sprites above everything.
+                let background_wins = self.background_enabled
+                    && self.bg_color_line[target_x] != 0
+                    && (!s.above_background || self.bg_priority_line[target_x]);
+                if background_wins {
+                    continue;
+                }
+
+                let line = self.scan_line as usize;
+                if self.cgb_mode {
+                    self.color_screen_buffer[line][target_x] =
+                        self.obj_color_palettes.color(s.cgb_palette_num, color_num);
                 } else {
-                    x
-                } as usize;
-                let target_x = s.x.wrapping_add(x);
-                if target_x < SCREEN_WIDTH as u8 {
-                    if tile[line as usize][tile_x] > 0
-                        && (s.above_background || (!s.above_background && self.screen_buffer[self.scan_line as usize][target_x as usize] == 0)) {
-                        let palette = if s.palette_num == 0 {
-                            self.obj_palette_0
-                        } else {
-                            self.obj_palette_1
-                        };
-                        let color = get_palette_color(tile[line as usize][tile_x], palette);
-                        self.screen_buffer[self.scan_line as usize][target_x as usize] = color;
-                    }
+                    let (palette, layer) = if s.palette_num == 0 {
+                        (self.obj_palette_0, Layer::Obj0)
+                    } else {
+                        (self.obj_palette_1, Layer::Obj1)
+                    };
+                    self.screen_buffer[line][target_x] = get_palette_color(color_num, palette);
+                    self.layer_buffer[line][target_x] = layer;
                 }
             }
         }
@@ -483,6 +629,13 @@ impl Gpu {
             0x49 => self.obj_palette_1,
             0x4A => self.window_y,
             0x4B => self.window_x.wrapping_add(7),
+            // The unused bits of the CGB registers read as 1.
+            0x4F => 0b1111_1110 | self.video_ram_bank as u8,
+            0x68 => self.bg_color_palettes.read_index(),
+            0x69 => self.bg_color_palettes.read_data(),
+            0x6A => self.obj_color_palettes.read_index(),
+            0x6B => self.obj_color_palettes.read_data(),
+            0x6C => 0b1111_1110 | self.obj_priority_by_x as u8,
             _ => panic!("Invalid read address for GPU"),
         }
     }
@@ -501,6 +654,12 @@ impl Gpu {
             0x49 => self.obj_palette_1 = val,
             0x4A => self.window_y = val,
             0x4B => self.window_x = val.wrapping_sub(7),
+            0x4F => self.video_ram_bank = (val & 1) as usize,
+            0x68 => self.bg_color_palettes.write_index(val),
+            0x69 => self.bg_color_palettes.write_data(val),
+            0x6A => self.obj_color_palettes.write_index(val),
+            0x6B => self.obj_color_palettes.write_data(val),
+            0x6C => self.obj_priority_by_x = val & 1 == 1,
             _ => panic!("Invalid write address for GPU"),
         }
     }
@@ -519,7 +678,15 @@ impl Gpu {
     }
 
     fn write_lcd_control(&mut self, val: u8) {
-        self.lcd_enabled = (val >> 7) == 1;
+        let lcd_enabled = (val >> 7) == 1;
+        if lcd_enabled != self.lcd_enabled {
+            // The LCD reports H-Blank on line 0 while it is off, and starts a new frame from the
+            // OAM read phase of line 0 when it is turned back on.
+            self.scan_line = 0;
+            self.cycles = 0;
+            self.mode = if lcd_enabled { Mode::OamRead } else { Mode::HorizontalBlank };
+        }
+        self.lcd_enabled = lcd_enabled;
         self.window_tile_map = TileMapLocation::from((val >> 6) & 1);
         self.window_enabled = ((val >> 5) & 1) == 1;
         self.background_and_window_location =
@@ -1,5 +1,3 @@
-use std::cmp::Ordering;
-
 #[derive(Clone, Copy)]
 pub struct Sprite {
     /// Sprite x and y positions
@@ -19,6 +17,12 @@ pub struct Sprite {
     /// Palette number of the sprite
     pub palette_num: u8,
 
+    /// Color palette number of the sprite (CGB only)
+    pub cgb_palette_num: u8,
+
+    /// Video ram bank the sprite's tile is in (CGB only)
+    pub vram_bank: u8,
+
     /// The index of sprite in memory. Used to determine sprite priority
     pub index: usize,
 }
@@ -33,35 +37,25 @@ impl Sprite {
             flip_x: false,
             flip_y: false,
             palette_num: 0,
+            cgb_palette_num: 0,
+            vram_bank: 0,
             index: 0,
         }
     }
 
+    /// The X coordinate as stored in OAM, which is offset by 8 from the screen position. Sprite
+    /// priority compares these so sprites partially off the left edge sort correctly.
+    pub fn oam_x(&self) -> u8 {
+        self.x.wrapping_add(8)
+    }
+
     /// Write sprite byte 3. bits 0-3 are for CGB only
     pub fn write_attributes(&mut self, val: u8) {
         self.above_background = ((val >> 7) & 1) == 0;
         self.flip_y = ((val >> 6) & 1) == 1;
         self.flip_x = ((val >> 5) & 1) == 1;
         self.palette_num = (val >> 4) & 1;
+        self.vram_bank = (val >> 3) & 1;
+        self.cgb_palette_num = val & 0b111;
     }
 }
-
-impl Eq for Sprite {}
-
-impl Ord for Sprite {
-    fn cmp(&self, other: &Sprite) -> Ordering {
-        (self.x, self.index).cmp(&(other.x, other.index))
-    }
-}
-
-impl PartialOrd for Sprite {
-    fn partial_cmp(&self, other: &Sprite) -> Option<Ordering> {
-        Some((self.x, self.index).cmp(&(other.x, other.index)))
-    }
-}
-
-impl PartialEq for Sprite {
-    fn eq(&self, other: &Sprite) -> bool {
-        (self.x, self.index) == (other.x, other.index)
-    }
-}
\ No newline at end of file
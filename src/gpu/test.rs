@@ -0,0 +1,223 @@
+use super::*;
+
+/// LCD on, 0x8000 tile data, sprites and background enabled.
+const LCDC_8X8: u8 = 0b1001_0011;
+
+/// Same as `LCDC_8X8` but with 8x16 sprites.
+const LCDC_8X16: u8 = 0b1001_0111;
+
+/// A palette which maps each color number to the shade with the same number.
+const IDENTITY_PALETTE: u8 = 0b11_10_01_00;
+
+fn setup(lcdc: u8) -> Gpu {
+    let mut gpu = Gpu::new(false);
+    gpu.write_reg(0x40, lcdc);
+    gpu.write_reg(0x47, IDENTITY_PALETTE);
+    gpu.write_reg(0x48, IDENTITY_PALETTE);
+    gpu.write_reg(0x49, IDENTITY_PALETTE);
+    gpu
+}
+
+/// Fill every pixel of the given tile with a single color number.
+fn fill_tile(gpu: &mut Gpu, tile_num: usize, color_num: u8) {
+    let low = if color_num & 1 != 0 { 0xFF } else { 0 };
+    let high = if color_num & 2 != 0 { 0xFF } else { 0 };
+    for row in 0..8 {
+        gpu.write_vram(tile_num * 16 + row * 2, low);
+        gpu.write_vram(tile_num * 16 + row * 2 + 1, high);
+    }
+}
+
+/// Place a sprite in OAM using screen coordinates.
+fn place_sprite(gpu: &mut Gpu, index: usize, x: u8, y: u8, tile_num: u8, attributes: u8) {
+    gpu.write_sprite_ram(index * 4, y.wrapping_add(16));
+    gpu.write_sprite_ram(index * 4 + 1, x.wrapping_add(8));
+    gpu.write_sprite_ram(index * 4 + 2, tile_num);
+    gpu.write_sprite_ram(index * 4 + 3, attributes);
+}
+
+/// Render the given scan line and return its shades.
+fn render_line(gpu: &mut Gpu, line: u8) -> [u8; SCREEN_WIDTH] {
+    gpu.scan_line = line;
+    gpu.render_scan_line();
+    gpu.screen_buffer[line as usize]
+}
+
+#[test]
+fn test_sprite_limit_uses_oam_order() {
+    let mut gpu = setup(LCDC_8X8);
+    fill_tile(&mut gpu, 1, 3);
+
+    // 11 sprites on the same line, each further left than the one before it in OAM. Only the
+    // first 10 in OAM order are drawn, so the leftmost one is dropped.
+    for i in 0..11 {
+        place_sprite(&mut gpu, i, 8 * (10 - i as u8), 0, 1, 0);
+    }
+
+    let line = render_line(&mut gpu, 0);
+    assert_eq!(line[0..8], [0; 8]);
+    assert_eq!(line[8..88], [3; 80][..]);
+}
+
+#[test]
+fn test_sprite_overlap_priority() {
+    let mut gpu = setup(LCDC_8X8);
+    fill_tile(&mut gpu, 1, 1);
+    fill_tile(&mut gpu, 2, 2);
+
+    // The sprite with the smaller X coordinate wins, even though it comes later in OAM.
+    place_sprite(&mut gpu, 0, 4, 0, 1, 0);
+    place_sprite(&mut gpu, 1, 0, 0, 2, 0);
+    // With equal X coordinates, the sprite earlier in OAM wins.
+    place_sprite(&mut gpu, 2, 40, 0, 1, 0);
+    place_sprite(&mut gpu, 3, 40, 0, 2, 0);
+
+    let line = render_line(&mut gpu, 0);
+    assert_eq!(line[0..8], [2; 8]);
+    assert_eq!(line[8..12], [1; 4]);
+    assert_eq!(line[40..48], [1; 8]);
+}
+
+#[test]
+fn test_sprite_8x16_ignores_tile_bit_0() {
+    let mut gpu = setup(LCDC_8X16);
+    fill_tile(&mut gpu, 2, 1);
+    fill_tile(&mut gpu, 3, 2);
+    place_sprite(&mut gpu, 0, 0, 0, 3, 0);
+
+    assert_eq!(render_line(&mut gpu, 0)[0..8], [1; 8]);
+    assert_eq!(render_line(&mut gpu, 8)[0..8], [2; 8]);
+}
+
+#[test]
+fn test_sprite_background_priority_uses_color_number() {
+    let mut gpu = setup(LCDC_8X8);
+    // Background color 1 is drawn with shade 0 but still hides sprites behind the background.
+    gpu.write_reg(0x47, 0b11_10_00_00);
+    fill_tile(&mut gpu, 0, 1);
+    fill_tile(&mut gpu, 1, 3);
+    place_sprite(&mut gpu, 0, 0, 0, 1, 0x80);
+
+    assert_eq!(render_line(&mut gpu, 0)[0..8], [0; 8]);
+
+    // Over background color 0 the sprite is visible.
+    fill_tile(&mut gpu, 0, 0);
+    assert_eq!(render_line(&mut gpu, 0)[0..8], [3; 8]);
+}
+
+#[test]
+fn test_hidden_sprite_masks_lower_priority_sprites() {
+    let mut gpu = setup(LCDC_8X8);
+    fill_tile(&mut gpu, 0, 1);
+    fill_tile(&mut gpu, 1, 2);
+    fill_tile(&mut gpu, 2, 3);
+
+    // The first sprite wins the pixels but is hidden behind the background, and the second
+    // sprite doesn't show through it.
+    place_sprite(&mut gpu, 0, 0, 0, 1, 0x80);
+    place_sprite(&mut gpu, 1, 0, 0, 2, 0);
+
+    assert_eq!(render_line(&mut gpu, 0)[0..8], [1; 8]);
+}
+
+#[test]
+fn test_cgb_background_attributes() {
+    let mut gpu = Gpu::new(true);
+    gpu.write_reg(0x40, LCDC_8X8);
+
+    // Background palette 2, color 1 is pure red.
+    gpu.write_reg(0x68, 0x80 | (2 * 4 + 1) * 2);
+    gpu.write_reg(0x69, 0x1F);
+    gpu.write_reg(0x69, 0x00);
+
+    // Tile 0 in bank 1 is filled with color 1, while tile 0 in bank 0 stays color 0.
+    gpu.write_reg(0x4F, 1);
+    fill_tile(&mut gpu, 0, 1);
+
+    // The first tile map entry uses palette 2 and takes its tile data from bank 1.
+    gpu.write_vram(0x1800, 0b0000_1010);
+    gpu.write_reg(0x4F, 0);
+
+    render_line(&mut gpu, 0);
+    assert_eq!(gpu.color_screen_buffer[0][0..8], [0x001F; 8]);
+    assert_eq!(gpu.color_screen_buffer[0][8..16], [0x7FFF; 8]);
+}
+
+#[test]
+fn test_vram_and_oam_locks() {
+    let mut gpu = setup(LCDC_8X8);
+    gpu.write_reg(0x40, 0x00);
+    gpu.write_reg(0x40, LCDC_8X8);
+
+    // Mode 2 locks OAM only.
+    assert_eq!(gpu.read_reg(0x41) & 0b11, 2);
+    assert!(gpu.vram_accessible());
+    assert!(!gpu.oam_accessible());
+
+    // Mode 3 locks both.
+    gpu.step(OAM_READ_CYCLES + 1);
+    assert_eq!(gpu.read_reg(0x41) & 0b11, 3);
+    assert!(!gpu.vram_accessible());
+    assert!(!gpu.oam_accessible());
+
+    // H-Blank unlocks both.
+    gpu.step(VRAM_READ_CYCLES + 1);
+    assert_eq!(gpu.read_reg(0x41) & 0b11, 0);
+    assert!(gpu.vram_accessible());
+    assert!(gpu.oam_accessible());
+
+    // With the LCD off, nothing is locked whatever the mode was.
+    gpu.step(HORIZONTAL_BLANK_CYCLES);
+    gpu.step(OAM_READ_CYCLES + 1);
+    assert_eq!(gpu.read_reg(0x41) & 0b11, 3);
+    gpu.lcd_enabled = false;
+    assert!(gpu.vram_accessible());
+    assert!(gpu.oam_accessible());
+}
+
+#[test]
+fn test_lcd_off_resets_mode() {
+    let mut gpu = setup(LCDC_8X8);
+    gpu.step(OAM_READ_CYCLES + 1);
+    gpu.step(VRAM_READ_CYCLES + 1);
+    gpu.step(HORIZONTAL_BLANK_CYCLES);
+    assert_eq!(gpu.read_reg(0x44), 1);
+
+    // Turning the LCD off reports H-Blank on line 0.
+    gpu.write_reg(0x40, LCDC_8X8 & 0x7F);
+    assert_eq!((gpu.read_reg(0x41) & 0b11, gpu.read_reg(0x44)), (0, 0));
+    gpu.step(1000);
+    assert_eq!((gpu.read_reg(0x41) & 0b11, gpu.read_reg(0x44)), (0, 0));
+
+    // Turning it back on starts from the OAM read phase of line 0.
+    gpu.write_reg(0x40, LCDC_8X8);
+    assert_eq!((gpu.read_reg(0x41) & 0b11, gpu.read_reg(0x44)), (2, 0));
+    assert_eq!(gpu.cycles, 0);
+}
+
+#[test]
+fn test_cgb_sprite_priority_uses_oam_order() {
+    let mut gpu = Gpu::new(true);
+    gpu.write_reg(0x40, LCDC_8X8);
+    fill_tile(&mut gpu, 1, 1);
+    fill_tile(&mut gpu, 2, 2);
+
+    // Sprite palette 0: color 1 is red and color 2 is green.
+    gpu.write_reg(0x6A, 0x80 | 1 * 2);
+    for &byte in &[0x1F, 0x00, 0xE0, 0x03] {
+        gpu.write_reg(0x6B, byte);
+    }
+
+    // The sprite earlier in OAM wins, even with the larger X coordinate.
+    place_sprite(&mut gpu, 0, 4, 0, 1, 0);
+    place_sprite(&mut gpu, 1, 0, 0, 2, 0);
+    render_line(&mut gpu, 0);
+    assert_eq!(gpu.color_screen_buffer[0][0..4], [0x03E0; 4]);
+    assert_eq!(gpu.color_screen_buffer[0][4..12], [0x001F; 8]);
+
+    // Setting OPRI bit 0 switches to the DMG's X coordinate priority.
+    gpu.write_reg(0x6C, 1);
+    render_line(&mut gpu, 0);
+    assert_eq!(gpu.color_screen_buffer[0][0..8], [0x03E0; 8]);
+    assert_eq!(gpu.color_screen_buffer[0][8..12], [0x001F; 4]);
+}
@@ -0,0 +1,34 @@
+use std::fmt::Debug;
+
+/// The other end of a cartridge's infrared port, such as another Game Boy. HuC1 and HuC3
+/// cartridges have an IR LED and sensor which games use to trade with each other.
+pub trait IrPort: Debug {
+    /// Called when the cartridge turns its LED on or off.
+    fn set_led(&mut self, on: bool);
+
+    /// True if the cartridge's sensor currently sees light.
+    fn light_detected(&self) -> bool;
+}
+
+/// An IR port which reflects the cartridge's own LED back at its sensor, as if it were pointed
+/// at a mirror. Useful for testing.
+#[derive(Clone, Debug)]
+pub struct IrLoopback {
+    led: bool,
+}
+
+impl IrLoopback {
+    pub fn new() -> Self {
+        IrLoopback { led: false }
+    }
+}
+
+impl IrPort for IrLoopback {
+    fn set_led(&mut self, on: bool) {
+        self.led = on;
+    }
+
+    fn light_detected(&self) -> bool {
+        self.led
+    }
+}
@@ -1,6 +1,12 @@
 use enumflags2::BitFlags;
 use crate::interrupts::Interrupt;
 
+/// The size in bytes of an SGB command packet.
+pub const SGB_PACKET_SIZE: usize = 16;
+
+/// The most controllers the SGB multiplayer adapter supports.
+pub const MAX_PLAYERS: usize = 4;
+
 #[derive(BitFlags, Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum ButtonKey {
@@ -27,14 +33,32 @@ pub struct Joypad {
     /// Whether the joypad register should reflect which direction keys are pressed.
     select_dir_keys: bool,
 
-    /// Bit flags of which button keys are currently held down.
-    button_keys_pressed: BitFlags<ButtonKey>,
+    /// Bit flags of which button keys are currently held down, for each player.
+    button_keys_pressed: [BitFlags<ButtonKey>; MAX_PLAYERS],
 
-    /// Bit flags of which direction keys are currently held down.
-    dir_keys_pressed: BitFlags<DirKey>,
+    /// Bit flags of which direction keys are currently held down, for each player.
+    dir_keys_pressed: [BitFlags<DirKey>; MAX_PLAYERS],
 
     /// Whether to request a Joypad interrupt on the next CPU step.
     should_interrupt: bool,
+
+    /// Whether writes to the joypad register should be decoded as SGB command packets.
+    sgb_enabled: bool,
+
+    /// The number of bits of the SGB packet received so far, or None if no packet is being sent.
+    sgb_packet_bits: Option<usize>,
+
+    /// The SGB packet currently being received.
+    sgb_packet: [u8; SGB_PACKET_SIZE],
+
+    /// The last fully received SGB packet which hasn't been handled yet.
+    sgb_received_packet: Option<[u8; SGB_PACKET_SIZE]>,
+
+    /// The number of players enabled by the SGB `MLT_REQ` command. (1, 2 or 4)
+    player_count: usize,
+
+    /// The player whose keys are currently visible in the joypad register.
+    current_player: usize,
 }
 
 impl Joypad {
@@ -42,34 +66,72 @@ impl Joypad {
         Joypad {
             select_button_keys: true,
             select_dir_keys: true,
-            button_keys_pressed: BitFlags::empty(),
-            dir_keys_pressed: BitFlags::empty(),
+            button_keys_pressed: [BitFlags::empty(); MAX_PLAYERS],
+            dir_keys_pressed: [BitFlags::empty(); MAX_PLAYERS],
             should_interrupt: false,
+            sgb_enabled: false,
+            sgb_packet_bits: None,
+            sgb_packet: [0; SGB_PACKET_SIZE],
+            sgb_received_packet: None,
+            player_count: 1,
+            current_player: 0,
         }
     }
 
+    /// Enable receiving SGB command packets through the joypad register.
+    pub fn enable_sgb(&mut self) {
+        self.sgb_enabled = true;
+    }
+
+    /// Set the number of players, as requested by the SGB `MLT_REQ` command.
+    pub fn set_player_count(&mut self, player_count: usize) {
+        self.player_count = player_count;
+        self.current_player = 0;
+    }
+
+    /// Take the last fully received SGB command packet, if any.
+    pub fn take_sgb_packet(&mut self) -> Option<[u8; SGB_PACKET_SIZE]> {
+        self.sgb_received_packet.take()
+    }
+
     pub fn button_key_down(&mut self, button: ButtonKey) {
+        self.player_button_key_down(0, button);
+    }
+
+    pub fn button_key_up(&mut self, button: ButtonKey) {
+        self.player_button_key_up(0, button);
+    }
+
+    pub fn dir_key_down(&mut self, dir: DirKey) {
+        self.player_dir_key_down(0, dir);
+    }
+
+    pub fn dir_key_up(&mut self, dir: DirKey) {
+        self.player_dir_key_up(0, dir);
+    }
+
+    pub fn player_button_key_down(&mut self, player: usize, button: ButtonKey) {
         let before = self.read_reg();
-        self.button_keys_pressed.insert(button);
+        self.button_keys_pressed[player].insert(button);
         let after = self.read_reg();
         // Request an interrupt if a 1 bit in `before` became a 0 bit in `after`.
         self.should_interrupt = before & !after != 0;
     }
 
-    pub fn button_key_up(&mut self, button: ButtonKey) {
-        self.button_keys_pressed.remove(button);
+    pub fn player_button_key_up(&mut self, player: usize, button: ButtonKey) {
+        self.button_keys_pressed[player].remove(button);
     }
 
-    pub fn dir_key_down(&mut self, dir: DirKey) {
+    pub fn player_dir_key_down(&mut self, player: usize, dir: DirKey) {
         let before = self.read_reg();
-        self.dir_keys_pressed.insert(dir);
+        self.dir_keys_pressed[player].insert(dir);
         let after = self.read_reg();
         // Request an interrupt if a 1 bit in `before` became a 0 bit in `after`.
         self.should_interrupt = before & !after != 0;
     }
 
-    pub fn dir_key_up(&mut self, dir: DirKey) {
-        self.dir_keys_pressed.remove(dir);
+    pub fn player_dir_key_up(&mut self, player: usize, dir: DirKey) {
+        self.dir_keys_pressed[player].remove(dir);
     }
 
     pub fn read_reg(&self) -> u8 {
@@ -83,24 +145,81 @@ impl Joypad {
         bits |= (self.select_button_keys as u8) << 5;
         bits |= (self.select_dir_keys as u8) << 4;
         if self.select_button_keys {
-            bits |= self.button_keys_pressed.bits();
+            bits |= self.button_keys_pressed[self.current_player].bits();
         }
         if self.select_dir_keys {
-            bits |= self.dir_keys_pressed.bits();
+            bits |= self.dir_keys_pressed[self.current_player].bits();
+        }
+        if self.player_count > 1 && !self.select_button_keys && !self.select_dir_keys {
+            // With SGB multiplayer enabled, the low bits hold the current player when no keys
+            // are selected. (0xF for player 1, 0xE for player 2, etc.)
+            bits |= self.current_player as u8;
         }
         !bits
     }
 
     pub fn write_reg(&mut self, bits: u8) {
+        let was_selecting_buttons = self.select_button_keys;
+        let was_selecting_any = self.select_button_keys || self.select_dir_keys;
+
         // Bits 0-3 are read-only and bits 6-7 are unused and unwritable according to Mooneye.
         // Also, the meaning of these bits is negated (0 means `true`).
         self.select_button_keys = bits >> 5 & 1 == 0;
         self.select_dir_keys = bits >> 4 & 1 == 0;
+
+        if self.sgb_enabled {
+            self.receive_sgb_bit(was_selecting_buttons, was_selecting_any);
+        }
+
         // TODO(solson): Enabling these bits can trigger the Joypad interrupt if some keys were
         // already being held, so we should handle interrupts here, too. (Or, more likely, in a way
         // that lets us do the check in a single place.)
     }
 
+    /// Decode SGB packets sent through the select bits. A packet starts with a pulse of both
+    /// select bits, then each bit is sent as a pulse of one select bit (P14 for 0 and P15 for 1)
+    /// with both bits released in between. The 128 data bits are followed by a 0 stop bit.
+    fn receive_sgb_bit(&mut self, was_selecting_buttons: bool, was_selecting_any: bool) {
+        match (self.select_button_keys, self.select_dir_keys, self.sgb_packet_bits) {
+            // Reset pulse, which starts a new packet.
+            (true, true, _) => {
+                self.sgb_packet_bits = Some(0);
+                self.sgb_packet = [0; SGB_PACKET_SIZE];
+            }
+
+            // The stop bit, which completes the packet.
+            (false, true, Some(bits)) if bits == SGB_PACKET_SIZE * 8 && !was_selecting_any => {
+                self.sgb_received_packet = Some(self.sgb_packet);
+                self.sgb_packet_bits = None;
+            }
+
+            // A 1 bit after the packet is full means the transfer was garbled, so drop it.
+            (true, false, Some(bits)) if bits == SGB_PACKET_SIZE * 8 && !was_selecting_any => {
+                self.sgb_packet_bits = None;
+            }
+
+            // A data bit, sent least significant bit first. Only the start of a pulse counts.
+            (button, dir, Some(bits)) if button != dir && !was_selecting_any => {
+                let bit = button as u8;
+                self.sgb_packet[bits / 8] |= bit << (bits % 8);
+                self.sgb_packet_bits = Some(bits + 1);
+            }
+
+            // Both select bits released between bits of a packet, or a pulse being held.
+            (_, _, Some(_)) => {}
+
+            // Releasing the button select bit outside of a packet switches to the next player.
+            (false, false, None) => {
+                if was_selecting_buttons && self.player_count > 1 {
+                    self.current_player = (self.current_player + 1) % self.player_count;
+                }
+            }
+
+            // Selecting keys to read them outside of a packet.
+            (_, _, None) => {}
+        }
+    }
+
     /// Called by the CPU when executing an instruction. Returns whether to request a Joypad
     /// interrupt.
     pub fn step(&mut self) -> BitFlags<Interrupt> {
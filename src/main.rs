@@ -3,25 +3,45 @@ extern crate sdl2;
 use crate::cart::{Cart, CartConfig};
 use crate::cart_header::{CartHardware, CartHeader};
 use crate::cpu::Cpu;
-use crate::frontend::{start_frontend, start_frontend_debug};
+use crate::audio::CLOCK_RATE;
+use crate::filter::{Blend, Filter};
+use crate::frontend::{start_frontend, start_frontend_debug, FrontendConfig};
+use crate::gbs::Gbs;
+use crate::ir::IrLoopback;
+use crate::model::{BootRomSizeError, Model};
+use crate::palette::Palette;
 use crate::wla_symbols::WlaSymbols;
 use failure::ResultExt;
 use log::info;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{BufReader, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use structopt::StructOpt;
 
 mod audio;
+mod audio_view;
 mod cart;
 mod cart_header;
 mod cpu;
 mod debug;
+mod eeprom;
+mod filter;
+mod flash;
 mod frontend;
+mod gbs;
 mod gpu;
 mod interrupts;
+mod ir;
 mod joypad;
+mod model;
+mod palette;
+mod rtc;
+mod sgb;
 mod timer;
+mod wav;
 mod wla_symbols;
 
 #[derive(Debug, StructOpt)]
@@ -35,6 +55,9 @@ enum Opts {
 
     #[structopt(name = "info", about = "Prints information about the given Game Boy ROMs")]
     Info(InfoOpts),
+
+    #[structopt(name = "gbs", about = "Plays the given GBS music file")]
+    Gbs(GbsOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -50,6 +73,35 @@ struct RunOpts {
     /// Load symbol file for debugging (in the WLA DX assembler's format
     #[structopt(short = "S", long = "symbol-file", name = "SYMBOLS", parse(from_os_str))]
     symbols_path: Option<PathBuf>,
+
+    /// Allow VRAM and OAM access during every LCD mode (useful for debugging homebrew)
+    #[structopt(long = "unlocked-vram")]
+    unlocked_vram: bool,
+
+    /// The Game Boy model to emulate: dmg0, dmg, mgb, sgb or cgb (defaults to the best model
+    /// the game supports)
+    #[structopt(short = "m", long = "model", name = "MODEL")]
+    model: Option<Model>,
+
+    /// Run this boot ROM before the game, instead of starting with the state it leaves behind
+    #[structopt(short = "b", long = "boot-rom", name = "BOOT_ROM", parse(from_os_str))]
+    boot_rom_path: Option<PathBuf>,
+
+    /// Whether an MBC1 cartridge is a multicart (MBC1M): true or false (defaults to detecting it
+    /// from the ROM)
+    #[structopt(long = "multicart", name = "MULTICART")]
+    multicart: Option<bool>,
+
+    /// Point the IR port of HuC1 and HuC3 cartridges at a mirror, so the cartridge sees its own
+    /// LED (useful for testing a game's IR code alone)
+    #[structopt(long = "ir-loopback")]
+    ir_loopback: bool,
+
+    #[structopt(flatten)]
+    display: DisplayOpts,
+
+    #[structopt(flatten)]
+    audio: AudioOpts,
 }
 
 #[derive(Debug, StructOpt)]
@@ -61,8 +113,100 @@ struct DebugOpts {
     /// Load symbol file for debugging (in the WLA DX assembler's format
     #[structopt(short = "S", long = "symbol-file", name = "SYMBOLS", parse(from_os_str))]
     symbols_path: Option<PathBuf>,
+
+    /// Allow VRAM and OAM access during every LCD mode (useful for debugging homebrew)
+    #[structopt(long = "unlocked-vram")]
+    unlocked_vram: bool,
+
+    /// The Game Boy model to emulate: dmg0, dmg, mgb, sgb or cgb (defaults to the best model
+    /// the game supports)
+    #[structopt(short = "m", long = "model", name = "MODEL")]
+    model: Option<Model>,
+
+    /// Run this boot ROM before the game, instead of starting with the state it leaves behind
+    #[structopt(short = "b", long = "boot-rom", name = "BOOT_ROM", parse(from_os_str))]
+    boot_rom_path: Option<PathBuf>,
+
+    /// Whether an MBC1 cartridge is a multicart (MBC1M): true or false (defaults to detecting it
+    /// from the ROM)
+    #[structopt(long = "multicart", name = "MULTICART")]
+    multicart: Option<bool>,
+
+    /// Point the IR port of HuC1 and HuC3 cartridges at a mirror, so the cartridge sees its own
+    /// LED (useful for testing a game's IR code alone)
+    #[structopt(long = "ir-loopback")]
+    ir_loopback: bool,
+
+    #[structopt(flatten)]
+    display: DisplayOpts,
+
+    #[structopt(flatten)]
+    audio: AudioOpts,
 }
 
+#[derive(Debug, StructOpt)]
+struct DisplayOpts {
+    /// The palette for DMG games: dmg-green, pocket, light or cgb-left (F9 cycles through them)
+    #[structopt(short = "p", long = "palette", name = "PALETTE", conflicts_with = "PALETTE_FILE")]
+    palette: Option<String>,
+
+    /// Load a user-defined palette for DMG games from this file
+    #[structopt(long = "palette-file", name = "PALETTE_FILE", parse(from_os_str))]
+    palette_path: Option<PathBuf>,
+
+    /// The initial window size, as a multiple of the screen size
+    #[structopt(long = "scale", name = "SCALE", default_value = "5")]
+    scale: usize,
+
+    /// Scale the screen to fill the window instead of by whole multiples (F11 toggles
+    /// fullscreen)
+    #[structopt(long = "fit")]
+    fit_to_window: bool,
+
+    /// Blend frames to imitate the LCD's slow response: none, average or ghosting
+    #[structopt(long = "blend", name = "BLEND", default_value = "none")]
+    blend: Blend,
+
+    /// Draw a grid between pixels like the DMG's dot-matrix LCD
+    #[structopt(long = "dot-matrix")]
+    dot_matrix: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct AudioOpts {
+    /// The audio sample rate in Hz
+    #[structopt(long = "sample-rate", name = "SAMPLE_RATE", default_value = "48000")]
+    sample_rate: u32,
+
+    /// How much audio to keep queued in milliseconds. Lower values reduce delay but may crackle
+    #[structopt(long = "audio-latency", name = "LATENCY", default_value = "60")]
+    audio_latency: u32,
+}
+
+#[derive(Debug, StructOpt)]
+struct GbsOpts {
+    /// The GBS file path
+    #[structopt(name = "GBS", parse(from_os_str))]
+    gbs_path: PathBuf,
+
+    /// The track to play, counting from 1 (defaults to the file's first track)
+    #[structopt(short = "t", long = "track", name = "TRACK")]
+    track: Option<u8>,
+
+    /// Render the track to this WAV file instead of playing it
+    #[structopt(long = "render-wav", name = "WAV", parse(from_os_str))]
+    wav_path: Option<PathBuf>,
+
+    /// How many seconds of the track to render
+    #[structopt(long = "length", name = "SECONDS", default_value = "120")]
+    length: f64,
+
+    #[structopt(flatten)]
+    display: DisplayOpts,
+
+    #[structopt(flatten)]
+    audio: AudioOpts,
+}
 
 #[derive(Debug, StructOpt)]
 struct InfoOpts {
@@ -85,6 +229,7 @@ fn main() -> Result<(), failure::Error> {
         Opts::Run(run_opts) => run(run_opts),
         Opts::Debug(debug_opts) => debug(debug_opts),
         Opts::Info(info_opts) => info(info_opts),
+        Opts::Gbs(gbs_opts) => gbs(gbs_opts),
     }
 }
 
@@ -93,7 +238,8 @@ fn run(opts: &RunOpts) -> Result<(), failure::Error> {
         .context("Failed to read ROM file")?
         .into_boxed_slice();
     let cart_header = CartHeader::from_rom(&rom).context("Failed to parse cartridge header")?;
-    let cart_config = CartConfig::from_cart_header(&cart_header)?;
+    let mut cart_config = CartConfig::from_cart_header(&cart_header)?;
+    cart_config.multicart = opts.multicart;
 
     // TODO(solson): Include some kind of game-identifying information in the save file to
     // prevent loading a save file with the wrong game.
@@ -105,8 +251,18 @@ fn run(opts: &RunOpts) -> Result<(), failure::Error> {
         info!("Initialized cartridge RAM from file");
     }
 
-    let cart = Cart::new(rom, ram, &cart_config).context("Failed to initialize cartridge")?;
-    let mut cpu = Cpu::new(cart);
+    let model = opts.model.unwrap_or_else(|| Model::from_cart_header(&cart_header));
+    let boot_rom = match &opts.boot_rom_path {
+        Some(path) => Some(read_boot_rom(path, model)?),
+        None => None,
+    };
+
+    let mut cart = Cart::new(rom, ram, &cart_config).context("Failed to initialize cartridge")?;
+    if opts.ir_loopback {
+        cart.set_ir_port(Rc::new(RefCell::new(IrLoopback::new())));
+    }
+    let mut cpu = Cpu::new(cart, model, boot_rom);
+    cpu.unlocked_vram = opts.unlocked_vram;
 
     if let Some(path) = &opts.symbols_path {
         let file = File::open(path).context("Failed to open symbol file")?;
@@ -114,20 +270,77 @@ fn run(opts: &RunOpts) -> Result<(), failure::Error> {
             .context("Failed to parse WLA DX symbol file")?);
     }
 
-    start_frontend(&mut cpu);
+    start_frontend(&mut cpu, &mut frontend_config(&opts.display, &opts.audio, cart_title(&cart_header))?);
+
+    if let Some(path) = &opts.save_path {
+        if cart_header.hardware.contains(CartHardware::Battery) {
+            std::fs::write(path, cpu.cart.save_data()).context("Failed to write save file")?;
+            info!("Saved cartridge RAM to file");
+        }
+    }
 
     Ok(())
 }
 
+fn frontend_config(opts: &DisplayOpts, audio_opts: &AudioOpts, title: String) -> Result<FrontendConfig, failure::Error> {
+    let mut palettes = Palette::presets();
+    let palette_index = if let Some(path) = &opts.palette_path {
+        let text = std::fs::read_to_string(path).context("Failed to read palette file")?;
+        let default_name = path.file_stem().map_or("custom".into(), |s| s.to_string_lossy());
+        palettes.push(Palette::parse(&text, &default_name).context("Failed to parse palette file")?);
+        palettes.len() - 1
+    } else if let Some(name) = &opts.palette {
+        let palette = Palette::preset(name)?;
+        palettes.iter().position(|p| *p == palette).unwrap()
+    } else {
+        0
+    };
+
+    Ok(FrontendConfig {
+        palettes,
+        palette_index,
+        scale: opts.scale.max(1),
+        fit_to_window: opts.fit_to_window,
+        title,
+        filter: Filter::new(opts.blend, opts.dot_matrix),
+        sample_rate: audio_opts.sample_rate,
+        audio_latency: audio_opts.audio_latency.max(1),
+    })
+}
+
+fn cart_title(cart_header: &CartHeader) -> String {
+    String::from_utf8_lossy(&cart_header.title).trim().to_string()
+}
+
+fn read_boot_rom(path: &Path, model: Model) -> Result<Box<[u8]>, failure::Error> {
+    let boot_rom = std::fs::read(path).context("Failed to read boot ROM file")?;
+    if boot_rom.len() != model.boot_rom_size() {
+        let expected = model.boot_rom_size();
+        return Err(BootRomSizeError { model, expected, actual: boot_rom.len() }.into());
+    }
+    Ok(boot_rom.into_boxed_slice())
+}
+
 fn debug(opts: &DebugOpts) -> Result<(), failure::Error> {
     let rom = std::fs::read(&opts.rom_path)
         .context("Failed to read ROM file")?
         .into_boxed_slice();
     let cart_header = CartHeader::from_rom(&rom).context("Failed to parse cartridge header")?;
-    let cart_config = CartConfig::from_cart_header(&cart_header)?;
-
-    let cart = Cart::new(rom, None, &cart_config).context("Failed to initialize cartridge")?;
-    let mut cpu = Cpu::new(cart);
+    let mut cart_config = CartConfig::from_cart_header(&cart_header)?;
+    cart_config.multicart = opts.multicart;
+
+    let model = opts.model.unwrap_or_else(|| Model::from_cart_header(&cart_header));
+    let boot_rom = match &opts.boot_rom_path {
+        Some(path) => Some(read_boot_rom(path, model)?),
+        None => None,
+    };
+
+    let mut cart = Cart::new(rom, None, &cart_config).context("Failed to initialize cartridge")?;
+    if opts.ir_loopback {
+        cart.set_ir_port(Rc::new(RefCell::new(IrLoopback::new())));
+    }
+    let mut cpu = Cpu::new(cart, model, boot_rom);
+    cpu.unlocked_vram = opts.unlocked_vram;
 
     if let Some(path) = &opts.symbols_path {
         let file = File::open(path).context("Failed to open symbol file")?;
@@ -135,7 +348,45 @@ fn debug(opts: &DebugOpts) -> Result<(), failure::Error> {
             .context("Failed to parse WLA DX symbol file")?);
     }
 
-    start_frontend_debug(&mut cpu);
+    start_frontend_debug(&mut cpu, &mut frontend_config(&opts.display, &opts.audio, cart_title(&cart_header))?);
+
+    Ok(())
+}
+
+fn gbs(opts: &GbsOpts) -> Result<(), failure::Error> {
+    let file = std::fs::read(&opts.gbs_path).context("Failed to read GBS file")?;
+    let gbs = Gbs::parse(&file).context("Failed to parse GBS file")?;
+    let track = opts.track.unwrap_or(gbs.first_song);
+    let rom = gbs.rom(track)?;
+    let cart_config = gbs.cart_config(rom.len());
+    let cart = Cart::new(rom, None, &cart_config).context("Failed to initialize cartridge")?;
+    let mut cpu = Cpu::new(cart, gbs.model(), None);
+    info!("Playing track {} of {}", track, gbs.song_count);
+    if !gbs.copyright.is_empty() {
+        info!("Copyright {}", gbs.copyright);
+    }
+
+    let title = format!("{} - {} (track {}/{})", gbs.title, gbs.author, track, gbs.song_count);
+    match &opts.wav_path {
+        Some(path) => {
+            // Render a frame's worth of cycles at a time.
+            const CYCLES_PER_STEP: usize = 70224;
+            let steps = (opts.length * CLOCK_RATE) as usize / CYCLES_PER_STEP;
+            let sample_rate = opts.audio.sample_rate;
+            cpu.audio.set_sample_rate(sample_rate as f64);
+
+            let mut samples: Vec<i16> = Vec::new();
+            for _ in 0..steps {
+                cpu.step_cycles(CYCLES_PER_STEP, &HashSet::new());
+                cpu.audio.drain_samples(&mut samples);
+            }
+
+            let file = File::create(path).context("Failed to create WAV file")?;
+            wav::write_wav(BufWriter::new(file), sample_rate, &samples).context("Failed to write WAV file")?;
+            info!("Rendered {} to {}", title, path.display());
+        }
+        None => start_frontend(&mut cpu, &mut frontend_config(&opts.display, &opts.audio, title)?),
+    }
 
     Ok(())
 }
@@ -0,0 +1,71 @@
+use crate::cart_header::{CartHeader, GbcFlag, SgbFlag};
+use failure_derive::Fail;
+
+/// The Game Boy hardware model being emulated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    /// The earliest revision of the original Game Boy, only released in Japan.
+    Dmg0,
+
+    /// The original Game Boy.
+    Dmg,
+
+    /// The Game Boy Pocket.
+    Mgb,
+
+    /// The Super Game Boy, which runs DMG games on a SNES.
+    Sgb,
+
+    /// The Game Boy Color.
+    Cgb,
+}
+
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "unknown model '{}' (expected dmg0, dmg, mgb, sgb or cgb)", _0)]
+pub struct UnknownModelError(String);
+
+#[derive(Clone, Debug, Fail)]
+#[fail(display = "boot ROM is {} bytes but the {:?} boot ROM is {} bytes", actual, model, expected)]
+pub struct BootRomSizeError {
+    pub model: Model,
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl Model {
+    /// Pick the model best suited to the given cartridge: the Game Boy Color for games with GBC
+    /// support, the Super Game Boy for other games with SGB support, and the original Game Boy
+    /// otherwise.
+    pub fn from_cart_header(cart_header: &CartHeader) -> Self {
+        match (cart_header.gbc_flag, cart_header.sgb_flag) {
+            (GbcFlag::Supported, _) | (GbcFlag::Required, _) => Model::Cgb,
+            (GbcFlag::Unsupported, SgbFlag::Supported) => Model::Sgb,
+            (GbcFlag::Unsupported, SgbFlag::Unsupported) => Model::Dmg,
+        }
+    }
+
+    /// The size in bytes of this model's boot ROM.
+    pub fn boot_rom_size(self) -> usize {
+        match self {
+            Model::Dmg0 | Model::Dmg | Model::Mgb | Model::Sgb => 0x100,
+            // The CGB boot ROM is mapped at 0x0000-0x00FF and 0x0200-0x08FF, with the cartridge
+            // header showing through in between.
+            Model::Cgb => 0x900,
+        }
+    }
+}
+
+impl std::str::FromStr for Model {
+    type Err = UnknownModelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(UnknownModelError(s.to_string())),
+        }
+    }
+}
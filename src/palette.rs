@@ -0,0 +1,141 @@
+use crate::gpu::Layer;
+use failure_derive::Fail;
+
+#[cfg(test)]
+mod test;
+
+/// A color as red, green and blue components.
+pub type Rgb = (u8, u8, u8);
+
+/// The colors used to show the four DMG shades, from lightest to darkest. Like the CGB's built-in
+/// colorization of DMG games, the background and each sprite palette can have their own colors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+
+    /// Colors for the background and window.
+    pub bg: [Rgb; 4],
+
+    /// Colors for sprites using OBP0.
+    pub obj0: [Rgb; 4],
+
+    /// Colors for sprites using OBP1.
+    pub obj1: [Rgb; 4],
+}
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum PaletteError {
+    #[fail(display = "unknown palette '{}' (expected one of: {})", name, known)]
+    UnknownPreset { name: String, known: String },
+
+    #[fail(display = "line {}: expected 'name', 'bg', 'obj0' or 'obj1', got '{}'", line, text)]
+    InvalidLine { line: usize, text: String },
+
+    #[fail(display = "line {}: expected 4 colors like #9BBC0F, got '{}'", line, text)]
+    InvalidColors { line: usize, text: String },
+
+    #[fail(display = "palette file has no 'bg' line")]
+    MissingBackground,
+}
+
+impl Palette {
+    /// A palette which uses the same colors for every layer.
+    fn uniform(name: &str, colors: [Rgb; 4]) -> Self {
+        Palette { name: name.to_string(), bg: colors, obj0: colors, obj1: colors }
+    }
+
+    /// The built-in palettes. The first one is the default.
+    pub fn presets() -> Vec<Palette> {
+        vec![
+            Palette::uniform("dmg-green", [(155, 188, 15), (139, 172, 15), (48, 98, 48), (15, 56, 15)]),
+            Palette::uniform("pocket", [(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)]),
+            Palette::uniform("light", [(0, 181, 129), (0, 154, 112), (0, 105, 74), (0, 81, 56)]),
+            // The CGB's colorization when Left is held during boot.
+            Palette {
+                name: "cgb-left".to_string(),
+                bg: [(255, 255, 255), (99, 165, 255), (0, 0, 255), (0, 0, 0)],
+                obj0: [(255, 255, 255), (255, 132, 132), (148, 58, 58), (0, 0, 0)],
+                obj1: [(255, 255, 255), (255, 132, 132), (148, 58, 58), (0, 0, 0)],
+            },
+        ]
+    }
+
+    /// Find a built-in palette by name.
+    pub fn preset(name: &str) -> Result<Palette, PaletteError> {
+        let presets = Palette::presets();
+        let known = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ");
+        presets.iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_else(|| PaletteError::UnknownPreset { name: name.to_string(), known })
+    }
+
+    /// Parse a user-defined palette. Each line sets the name or the colors of one layer, for
+    /// example:
+    ///
+    /// ```text
+    /// # Blue background, red sprites.
+    /// name = blue
+    /// bg = #FFFFFF #63A5FF #0000FF #000000
+    /// obj0 = #FFFFFF #FF8484 #943A3A #000000
+    /// ```
+    ///
+    /// The `bg` line is required. `obj0` defaults to the `bg` colors and `obj1` to the `obj0`
+    /// colors.
+    pub fn parse(text: &str, default_name: &str) -> Result<Palette, PaletteError> {
+        let mut name = default_name.to_string();
+        let (mut bg, mut obj0, mut obj1) = (None, None, None);
+
+        for (i, line) in text.lines().enumerate() {
+            let line_num = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid_line = || PaletteError::InvalidLine { line: line_num, text: line.to_string() };
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().ok_or_else(invalid_line)?.trim();
+            let value = parts.next().ok_or_else(invalid_line)?.trim();
+            match key {
+                "name" => name = value.to_string(),
+                "bg" => bg = Some(parse_colors(value, line_num)?),
+                "obj0" => obj0 = Some(parse_colors(value, line_num)?),
+                "obj1" => obj1 = Some(parse_colors(value, line_num)?),
+                _ => return Err(invalid_line()),
+            }
+        }
+
+        let bg = bg.ok_or(PaletteError::MissingBackground)?;
+        let obj0 = obj0.unwrap_or(bg);
+        let obj1 = obj1.unwrap_or(obj0);
+        Ok(Palette { name, bg, obj0, obj1 })
+    }
+
+    /// Get the color for a shade (0-3) on the given layer.
+    pub fn color(&self, layer: Layer, shade: u8) -> Rgb {
+        let colors = match layer {
+            Layer::Background => &self.bg,
+            Layer::Obj0 => &self.obj0,
+            Layer::Obj1 => &self.obj1,
+        };
+        colors[shade as usize]
+    }
+}
+
+/// Parse 4 space-separated colors in the `#RRGGBB` format.
+fn parse_colors(text: &str, line: usize) -> Result<[Rgb; 4], PaletteError> {
+    let invalid = || PaletteError::InvalidColors { line, text: text.to_string() };
+    let mut colors = [(0, 0, 0); 4];
+    let mut words = text.split_whitespace();
+    for color in &mut colors {
+        let word = words.next().ok_or_else(invalid)?;
+        let hex = word.strip_prefix('#').filter(|h| h.len() == 6).ok_or_else(invalid)?;
+        let rgb = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+        *color = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+    }
+    if words.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(colors)
+}
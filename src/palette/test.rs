@@ -0,0 +1,67 @@
+use super::*;
+
+const BLUE_TEXT: &str = "
+# Blue background, red sprites.
+name = blue
+bg = #FFFFFF #63A5FF #0000FF #000000
+obj0 = #FFFFFF #FF8484 #943A3A #000000
+";
+
+#[test]
+fn test_parse() {
+    let palette = Palette::parse(BLUE_TEXT, "custom").unwrap();
+    assert_eq!(palette.name, "blue");
+    assert_eq!(palette.bg, [(0xFF, 0xFF, 0xFF), (0x63, 0xA5, 0xFF), (0x00, 0x00, 0xFF), (0x00, 0x00, 0x00)]);
+    assert_eq!(palette.obj0[2], (0x94, 0x3A, 0x3A));
+    // obj1 defaults to the obj0 colors.
+    assert_eq!(palette.obj1, palette.obj0);
+    assert_eq!(palette.color(Layer::Obj1, 1), (0xFF, 0x84, 0x84));
+}
+
+#[test]
+fn test_parse_defaults() {
+    let palette = Palette::parse("bg = #000000 #111111 #222222 #333333", "from-file").unwrap();
+    assert_eq!(palette.name, "from-file");
+    assert_eq!(palette.obj0, palette.bg);
+    assert_eq!(palette.obj1, palette.bg);
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(
+        Palette::parse("name = empty", "custom"),
+        Err(PaletteError::MissingBackground),
+    );
+    assert_eq!(
+        Palette::parse("\nbackground = #000000 #111111 #222222 #333333", "custom"),
+        Err(PaletteError::InvalidLine { line: 2, text: "background = #000000 #111111 #222222 #333333".to_string() }),
+    );
+    assert_eq!(
+        Palette::parse("bg", "custom"),
+        Err(PaletteError::InvalidLine { line: 1, text: "bg".to_string() }),
+    );
+    for colors in &[
+        "#000000 #111111 #222222",
+        "#000000 #111111 #222222 #333333 #444444",
+        "#000000 #111111 #222222 333333",
+        "#000000 #111111 #222222 #33333",
+        "#000000 #111111 #222222 #33333G",
+    ] {
+        assert_eq!(
+            Palette::parse(&format!("bg = {}", colors), "custom"),
+            Err(PaletteError::InvalidColors { line: 1, text: colors.to_string() }),
+        );
+    }
+}
+
+#[test]
+fn test_preset() {
+    assert_eq!(Palette::preset("pocket").unwrap().bg[0], (255, 255, 255));
+    match Palette::preset("sepia") {
+        Err(PaletteError::UnknownPreset { name, known }) => {
+            assert_eq!(name, "sepia");
+            assert_eq!(known, "dmg-green, pocket, light, cgb-left");
+        }
+        other => panic!("expected an unknown preset error, got {:?}", other),
+    }
+}
@@ -0,0 +1,356 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+mod test;
+
+/// The RTC runs from its own 32.768kHz crystal, so it counts seconds of real time no matter the
+/// CPU speed. This is the number of (normal speed) hardware cycles in a second.
+const CYCLES_PER_SECOND: usize = 4_194_304;
+
+/// Size of the RTC footer appended to save files by most emulators: the current and latched
+/// registers as ten 32-bit values, then a 64-bit UNIX timestamp.
+pub const RTC_SAVE_SIZE: usize = 48;
+
+/// An older variant of the footer with a 32-bit timestamp.
+pub const RTC_SAVE_SIZE_SHORT: usize = 44;
+
+/// Size of the HuC3 clock state appended to save files, in SameBoy's format: a 64-bit UNIX
+/// timestamp, then the minutes, days, alarm minutes and alarm days as 16-bit values, then the
+/// alarm enable byte.
+pub const HUC3_RTC_SAVE_SIZE: usize = 17;
+
+/// The HuC3 clock counts minutes in a day, then days.
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Index of each register in `regs`, counting from RTC register 0x08.
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAYS_LOW: usize = 3;
+const DAYS_HIGH: usize = 4;
+
+/// The bits of each register which exist. The rest read as 0.
+const REG_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+/// Bit 0 of the days high register holds bit 8 of the day counter.
+const DAY_BIT_8: u8 = 1 << 0;
+
+/// Bit 6 of the days high register stops the clock.
+const HALT: u8 = 1 << 6;
+
+/// Bit 7 of the days high register is set when the day counter overflows, until cleared.
+const DAY_CARRY: u8 = 1 << 7;
+
+/// The real-time clock in MBC3 cartridges.
+#[derive(Clone, Debug)]
+pub struct Rtc {
+    /// Seconds, minutes, hours, the lower 8 bits of the day counter, and the days high register.
+    regs: [u8; 5],
+
+    /// Copy of `regs` taken when the clock is latched, which is what the game reads.
+    latched: [u8; 5],
+
+    /// The last value written to the latch register. Writing 0 and then 1 latches the clock.
+    latch_reg: u8,
+
+    /// Cycles since the seconds last increased.
+    cycles: usize,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Rtc { regs: [0; 5], latched: [0; 5], latch_reg: 0xFF, cycles: 0 }
+    }
+
+    /// Restore the clock from a save file footer, and advance it by the time since it was saved.
+    pub fn from_save(footer: &[u8]) -> Self {
+        let word = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+        let mut rtc = Rtc::new();
+        for i in 0..5 {
+            rtc.regs[i] = word(i * 4) as u8 & REG_MASKS[i];
+            rtc.latched[i] = word(20 + i * 4) as u8 & REG_MASKS[i];
+        }
+
+        let saved_at = if footer.len() >= RTC_SAVE_SIZE {
+            u64::from_le_bytes([
+                footer[40], footer[41], footer[42], footer[43],
+                footer[44], footer[45], footer[46], footer[47],
+            ])
+        } else {
+            word(40) as u64
+        };
+        rtc.advance_seconds(unix_time().saturating_sub(saved_at));
+        rtc
+    }
+
+    /// The footer to append to the save file, timestamped with the current time.
+    pub fn save(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_SAVE_SIZE);
+        for &reg in self.regs.iter().chain(&self.latched) {
+            footer.extend_from_slice(&(reg as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    /// Read a latched register. `reg` is the RAM bank number it's mapped to (0x08-0x0C).
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[(reg - 0x08) as usize]
+    }
+
+    /// Write a register. `reg` is the RAM bank number it's mapped to (0x08-0x0C).
+    pub fn write(&mut self, reg: u8, val: u8) {
+        let i = (reg - 0x08) as usize;
+        self.regs[i] = val & REG_MASKS[i];
+        // Writing the seconds resets the divider counting towards the next second.
+        if i == SECONDS {
+            self.cycles = 0;
+        }
+    }
+
+    /// Write the latch register. Writing 0 and then 1 copies the clock to the latched registers.
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_reg == 0 && val == 1 {
+            self.latched = self.regs;
+        }
+        self.latch_reg = val;
+    }
+
+    /// Advance the clock by some hardware cycles of emulated time.
+    pub fn step(&mut self, cycles: usize) {
+        if self.regs[DAYS_HIGH] & HALT != 0 {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    /// Advance the clock by some seconds of real time which passed while the emulator wasn't
+    /// running.
+    fn advance_seconds(&mut self, mut seconds: u64) {
+        if self.regs[DAYS_HIGH] & HALT != 0 {
+            return;
+        }
+
+        // Out of range values wrap without carrying, so count through them a second at a time.
+        // That takes at most a few hours of clock time.
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = seconds
+            + self.regs[SECONDS] as u64
+            + self.regs[MINUTES] as u64 * 60
+            + self.regs[HOURS] as u64 * 60 * 60;
+        self.regs[SECONDS] = (total % 60) as u8;
+        self.regs[MINUTES] = (total / 60 % 60) as u8;
+        self.regs[HOURS] = (total / (60 * 60) % 24) as u8;
+
+        let days = (self.regs[DAYS_HIGH] as u64 & DAY_BIT_8 as u64) << 8 | self.regs[DAYS_LOW] as u64;
+        let days = days + total / (24 * 60 * 60);
+        if days > 0x1FF {
+            self.regs[DAYS_HIGH] |= DAY_CARRY;
+        }
+        self.regs[DAYS_LOW] = days as u8;
+        self.regs[DAYS_HIGH] = self.regs[DAYS_HIGH] & !DAY_BIT_8 | (days >> 8) as u8 & DAY_BIT_8;
+    }
+
+    /// True if the seconds, minutes and hours are all in their normal ranges.
+    fn in_range(&self) -> bool {
+        self.regs[SECONDS] < 60 && self.regs[MINUTES] < 60 && self.regs[HOURS] < 24
+    }
+
+    /// Count one second. Out of range values count up to the register's limit and wrap to 0
+    /// without carrying into the next register, like the hardware.
+    fn tick(&mut self) {
+        if !self.increment(SECONDS, 60) || !self.increment(MINUTES, 60) || !self.increment(HOURS, 24) {
+            return;
+        }
+
+        let days = (self.regs[DAYS_HIGH] as u16 & DAY_BIT_8 as u16) << 8 | self.regs[DAYS_LOW] as u16;
+        let days = (days + 1) & 0x1FF;
+        if days == 0 {
+            self.regs[DAYS_HIGH] |= DAY_CARRY;
+        }
+        self.regs[DAYS_LOW] = days as u8;
+        self.regs[DAYS_HIGH] = self.regs[DAYS_HIGH] & !DAY_BIT_8 | (days >> 8) as u8;
+    }
+
+    /// Increment a register which wraps to 0 at `limit`. Returns true if it carries into the next
+    /// register.
+    fn increment(&mut self, i: usize, limit: u8) -> bool {
+        if self.regs[i] == limit - 1 {
+            self.regs[i] = 0;
+            true
+        } else {
+            self.regs[i] = (self.regs[i] + 1) & REG_MASKS[i];
+            false
+        }
+    }
+}
+
+/// The clock in HuC3 cartridges, which counts minutes and days. The game talks to it through a
+/// command register: each command is a 4-bit opcode and a 4-bit argument, and reads and writes
+/// go through a small memory where the time is at addresses 0-6 and the alarm at 0x58-0x5F.
+#[derive(Clone, Debug)]
+pub struct HuC3Rtc {
+    /// Minutes since midnight (0-1439).
+    minutes: u16,
+    days: u16,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+
+    /// The memory address the next read or write command accesses.
+    access_index: u8,
+
+    /// The argument of the last extended command (opcode 6). 2 is a status request, which makes
+    /// reads return 1, 0 is idle, and the other values play the tone generator.
+    extended: u8,
+
+    /// The nibble returned by the last read command.
+    read_value: u8,
+
+    /// Cycles since the minutes last increased.
+    cycles: usize,
+}
+
+impl HuC3Rtc {
+    pub fn new() -> Self {
+        HuC3Rtc {
+            minutes: 0,
+            days: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            access_index: 0,
+            extended: 0,
+            read_value: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Restore the clock from a save file footer, and advance it by the time since it was saved.
+    pub fn from_save(footer: &[u8]) -> Self {
+        let half = |i: usize| u16::from_le_bytes([footer[i], footer[i + 1]]);
+        let mut saved_at = [0; 8];
+        saved_at.copy_from_slice(&footer[0..8]);
+        let mut rtc = HuC3Rtc::new();
+        rtc.minutes = half(8) % MINUTES_PER_DAY;
+        rtc.days = half(10);
+        rtc.alarm_minutes = half(12);
+        rtc.alarm_days = half(14);
+        rtc.alarm_enabled = footer[16] & 1 != 0;
+
+        let elapsed_minutes = unix_time().saturating_sub(u64::from_le_bytes(saved_at)) / 60;
+        rtc.advance_minutes(elapsed_minutes);
+        rtc
+    }
+
+    /// The footer to append to the save file, timestamped with the current time.
+    pub fn save(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(HUC3_RTC_SAVE_SIZE);
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        for &half in &[self.minutes, self.days, self.alarm_minutes, self.alarm_days] {
+            footer.extend_from_slice(&half.to_le_bytes());
+        }
+        footer.push(self.alarm_enabled as u8);
+        footer
+    }
+
+    /// Read the command register, which holds the result of the last read command.
+    pub fn read(&self) -> u8 {
+        if self.extended == 0x2 { 1 } else { self.read_value }
+    }
+
+    /// Write the command register.
+    pub fn write(&mut self, val: u8) {
+        let arg = val & 0x0F;
+        match val >> 4 {
+            // Read the nibble at the address and move to the next
+            0x1 => {
+                self.read_value = self.read_memory(self.access_index);
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+
+            // Write the nibble at the address, and move to the next for opcode 3
+            0x2 | 0x3 => {
+                self.write_memory(self.access_index, arg);
+                if val >> 4 == 0x3 {
+                    self.access_index = self.access_index.wrapping_add(1);
+                }
+            }
+
+            // Set the low or high nibble of the address
+            0x4 => self.access_index = self.access_index & 0xF0 | arg,
+            0x5 => self.access_index = self.access_index & 0x0F | arg << 4,
+
+            // Extended command
+            0x6 => self.extended = arg,
+
+            _ => {}
+        }
+    }
+
+    /// The tone generator's current setting, if it's playing. The piezo speaker itself isn't
+    /// emulated, so this is passed on to the frontend.
+    pub fn tone(&self) -> Option<u8> {
+        match self.extended {
+            0x0 | 0x2 => None,
+            tone => Some(tone),
+        }
+    }
+
+    /// Advance the clock by some hardware cycles of emulated time.
+    pub fn step(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        let cycles_per_minute = CYCLES_PER_SECOND * 60;
+        if self.cycles >= cycles_per_minute {
+            self.advance_minutes((self.cycles / cycles_per_minute) as u64);
+            self.cycles %= cycles_per_minute;
+        }
+    }
+
+    fn advance_minutes(&mut self, minutes: u64) {
+        let total = self.minutes as u64 + minutes;
+        self.minutes = (total % MINUTES_PER_DAY as u64) as u16;
+        self.days = self.days.wrapping_add((total / MINUTES_PER_DAY as u64) as u16);
+    }
+
+    fn read_memory(&self, addr: u8) -> u8 {
+        let nibble = |value: u16, i: u8| (value >> (i * 4)) as u8 & 0x0F;
+        match addr {
+            0x00..=0x02 => nibble(self.minutes, addr),
+            0x03..=0x06 => nibble(self.days, addr - 0x03),
+            0x58..=0x5A => nibble(self.alarm_minutes, addr - 0x58),
+            0x5B..=0x5E => nibble(self.alarm_days, addr - 0x5B),
+            0x5F => self.alarm_enabled as u8,
+            _ => 0,
+        }
+    }
+
+    fn write_memory(&mut self, addr: u8, arg: u8) {
+        let set_nibble = |value: &mut u16, i: u8| {
+            *value = *value & !(0xF << (i * 4)) | (arg as u16) << (i * 4);
+        };
+        match addr {
+            0x00..=0x02 => set_nibble(&mut self.minutes, addr),
+            0x03..=0x06 => set_nibble(&mut self.days, addr - 0x03),
+            0x58..=0x5A => set_nibble(&mut self.alarm_minutes, addr - 0x58),
+            0x5B..=0x5E => set_nibble(&mut self.alarm_days, addr - 0x5B),
+            0x5F => self.alarm_enabled = arg & 1 != 0,
+            _ => {}
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,214 @@
+use super::*;
+
+/// A 48-byte footer with the given registers, both current and latched, saved at `saved_at`.
+fn footer(regs: [u8; 5], saved_at: u64) -> Vec<u8> {
+    let mut footer = Vec::new();
+    for &reg in regs.iter().chain(&regs) {
+        footer.extend_from_slice(&(reg as u32).to_le_bytes());
+    }
+    footer.extend_from_slice(&saved_at.to_le_bytes());
+    footer
+}
+
+#[test]
+fn test_save_round_trip() {
+    let mut rtc = Rtc::new();
+    for (i, &val) in [12, 34, 5, 0x67, HALT | DAY_BIT_8].iter().enumerate() {
+        rtc.write(0x08 + i as u8, val);
+    }
+    rtc.write_latch(0);
+    rtc.write_latch(1);
+    rtc.write(0x08, 13);
+
+    let saved = rtc.save();
+    assert_eq!(saved.len(), RTC_SAVE_SIZE);
+    let loaded = Rtc::from_save(&saved);
+    assert_eq!(loaded.regs, [13, 34, 5, 0x67, HALT | DAY_BIT_8]);
+    assert_eq!(loaded.latched, [12, 34, 5, 0x67, HALT | DAY_BIT_8]);
+}
+
+#[test]
+fn test_catch_up() {
+    // Saved 1 hour, 1 minute and 1 second ago. A second may pass while the test runs.
+    let rtc = Rtc::from_save(&footer([0, 0, 0, 0, 0], unix_time() - 3661));
+    assert!((1..=2).contains(&rtc.regs[SECONDS]));
+    assert_eq!(rtc.regs[MINUTES..], [1, 1, 0, 0]);
+    // The latched registers only change when the game latches them.
+    assert_eq!(rtc.latched, [0; 5]);
+
+    // The short footer has a 32-bit timestamp.
+    let mut short = footer([0, 30, 23, 0xFF, 0], 0);
+    short.truncate(40);
+    short.extend_from_slice(&(unix_time() as u32 - 2 * 24 * 60 * 60).to_le_bytes());
+    assert_eq!(short.len(), RTC_SAVE_SIZE_SHORT);
+    let rtc = Rtc::from_save(&short);
+    assert_eq!(rtc.regs[MINUTES..], [30, 23, 0x01, DAY_BIT_8]);
+
+    // A zero timestamp is decades of catching up, which overflows the day counter.
+    let rtc = Rtc::from_save(&footer([0; 5], 0));
+    assert_ne!(rtc.regs[DAYS_HIGH] & DAY_CARRY, 0);
+
+    // A halted clock doesn't move.
+    let rtc = Rtc::from_save(&footer([59, 59, 23, 0, HALT], unix_time() - 3661));
+    assert_eq!(rtc.regs, [59, 59, 23, 0, HALT]);
+}
+
+#[test]
+fn test_catch_up_out_of_range() {
+    // Invalid values wrap to 0 without carrying, and the clock counts normally from there.
+    let mut rtc = Rtc::new();
+    rtc.regs = [62, 59, 0, 0, 0];
+    rtc.advance_seconds(2 + 60 + 5);
+    assert_eq!(rtc.regs, [5, 0, 1, 0, 0]);
+}
+
+#[test]
+fn test_latch() {
+    let mut rtc = Rtc::new();
+    rtc.write(0x09, 42);
+    assert_eq!(rtc.read(0x09), 0);
+
+    // Writing 1 without a 0 first doesn't latch.
+    rtc.write_latch(1);
+    assert_eq!(rtc.read(0x09), 0);
+    rtc.write_latch(0);
+    rtc.write_latch(1);
+    assert_eq!(rtc.read(0x09), 42);
+
+    // The latched value stays while the clock runs.
+    rtc.write(0x09, 43);
+    assert_eq!(rtc.read(0x09), 42);
+}
+
+#[test]
+fn test_halt() {
+    let mut rtc = Rtc::new();
+    rtc.write(0x0C, HALT);
+    rtc.step(10 * CYCLES_PER_SECOND);
+    assert_eq!(rtc.regs[SECONDS], 0);
+
+    rtc.write(0x0C, 0);
+    rtc.step(10 * CYCLES_PER_SECOND);
+    assert_eq!(rtc.regs[SECONDS], 10);
+}
+
+#[test]
+fn test_day_carry() {
+    let mut rtc = Rtc::new();
+    for (i, &val) in [59, 59, 23, 0xFF, DAY_BIT_8].iter().enumerate() {
+        rtc.write(0x08 + i as u8, val);
+    }
+    rtc.step(CYCLES_PER_SECOND);
+    assert_eq!(rtc.regs, [0, 0, 0, 0, DAY_CARRY]);
+
+    // The carry stays set until the game clears it.
+    rtc.step(24 * 60 * 60 * CYCLES_PER_SECOND);
+    assert_eq!(rtc.regs, [0, 0, 0, 1, DAY_CARRY]);
+    rtc.write(0x0C, 0);
+    assert_eq!(rtc.regs[DAYS_HIGH], 0);
+
+    // Catching up sets it too.
+    rtc.regs = [0, 0, 0, 0xFF, DAY_BIT_8];
+    rtc.advance_seconds(2 * 24 * 60 * 60);
+    assert_eq!(rtc.regs, [0, 0, 0, 1, DAY_CARRY]);
+}
+
+/// Point a HuC3 clock's memory address at `addr`.
+fn huc3_seek(rtc: &mut HuC3Rtc, addr: u8) {
+    rtc.write(0x40 | addr & 0x0F);
+    rtc.write(0x50 | addr >> 4);
+}
+
+/// Read `count` nibbles from a HuC3 clock's memory, starting at `addr`.
+fn huc3_read(rtc: &mut HuC3Rtc, addr: u8, count: usize) -> Vec<u8> {
+    huc3_seek(rtc, addr);
+    (0..count).map(|_| {
+        rtc.write(0x10);
+        rtc.read()
+    }).collect()
+}
+
+#[test]
+fn test_huc3_commands() {
+    let mut rtc = HuC3Rtc::new();
+
+    // Opcode 3 writes a nibble and moves to the next address, least significant nibble first.
+    huc3_seek(&mut rtc, 0x00);
+    for &nibble in &[0x3, 0x2, 0x1, 0xC, 0xB, 0xA, 0x0] {
+        rtc.write(0x30 | nibble);
+    }
+    assert_eq!((rtc.minutes, rtc.days), (0x123, 0x0ABC));
+    assert_eq!(huc3_read(&mut rtc, 0x00, 7), [0x3, 0x2, 0x1, 0xC, 0xB, 0xA, 0x0]);
+
+    // Opcode 2 writes without moving.
+    huc3_seek(&mut rtc, 0x58);
+    rtc.write(0x25);
+    rtc.write(0x24);
+    assert_eq!(huc3_read(&mut rtc, 0x58, 2), [0x4, 0x0]);
+
+    // The alarm enable is at 0x5F, and unused addresses read as 0.
+    huc3_seek(&mut rtc, 0x5F);
+    rtc.write(0x21);
+    assert!(rtc.alarm_enabled);
+    assert_eq!(huc3_read(&mut rtc, 0x5F, 2), [0x1, 0x0]);
+
+    // The status request makes reads return 1 until another extended command.
+    rtc.write(0x62);
+    assert_eq!(rtc.read(), 1);
+    rtc.write(0x60);
+    assert_eq!(rtc.read(), 0x0);
+}
+
+#[test]
+fn test_huc3_tone() {
+    let mut rtc = HuC3Rtc::new();
+    assert_eq!(rtc.tone(), None);
+    rtc.write(0x6E);
+    assert_eq!(rtc.tone(), Some(0xE));
+    // Other commands leave it playing.
+    rtc.write(0x10);
+    assert_eq!(rtc.tone(), Some(0xE));
+    rtc.write(0x61);
+    assert_eq!(rtc.tone(), Some(0x1));
+    // The status request and extended command 0 stop it.
+    rtc.write(0x62);
+    assert_eq!(rtc.tone(), None);
+    rtc.write(0x63);
+    rtc.write(0x60);
+    assert_eq!(rtc.tone(), None);
+}
+
+#[test]
+fn test_huc3_step() {
+    let mut rtc = HuC3Rtc::new();
+    rtc.minutes = MINUTES_PER_DAY - 1;
+    rtc.step(59 * CYCLES_PER_SECOND);
+    assert_eq!((rtc.minutes, rtc.days), (MINUTES_PER_DAY - 1, 0));
+    rtc.step(CYCLES_PER_SECOND);
+    assert_eq!((rtc.minutes, rtc.days), (0, 1));
+}
+
+#[test]
+fn test_huc3_save() {
+    let mut rtc = HuC3Rtc::new();
+    rtc.minutes = 1430;
+    rtc.days = 0x0123;
+    rtc.alarm_minutes = 0x0456;
+    rtc.alarm_days = 0x0789;
+    rtc.alarm_enabled = true;
+
+    let saved = rtc.save();
+    assert_eq!(saved.len(), HUC3_RTC_SAVE_SIZE);
+    assert_eq!(&saved[8..], &[0x96, 0x05, 0x23, 0x01, 0x56, 0x04, 0x89, 0x07, 0x01]);
+    let loaded = HuC3Rtc::from_save(&saved);
+    assert_eq!((loaded.minutes, loaded.days), (1430, 0x0123));
+    assert_eq!((loaded.alarm_minutes, loaded.alarm_days, loaded.alarm_enabled), (0x0456, 0x0789, true));
+
+    // Loading catches up on the time since the save: 2 days and 90 minutes later, it's 80 minutes
+    // into the third day after.
+    let mut old = saved.clone();
+    old[..8].copy_from_slice(&(unix_time() - (2 * 24 * 60 + 90) * 60).to_le_bytes());
+    let loaded = HuC3Rtc::from_save(&old);
+    assert_eq!((loaded.minutes, loaded.days), (80, 0x0126));
+    assert_eq!(loaded.alarm_days, 0x0789);
+}
@@ -0,0 +1,496 @@
+use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::joypad::{Joypad, SGB_PACKET_SIZE};
+use log::{debug, info, warn};
+
+#[cfg(test)]
+mod test;
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+/// The position of the Game Boy screen within the SGB screen.
+const GAME_SCREEN_X: usize = (SGB_SCREEN_WIDTH - SCREEN_WIDTH) / 2;
+const GAME_SCREEN_Y: usize = (SGB_SCREEN_HEIGHT - SCREEN_HEIGHT) / 2;
+
+const SCREEN_TILES_WIDTH: usize = SCREEN_WIDTH / 8; // 20 tiles
+const SCREEN_TILES_HEIGHT: usize = SCREEN_HEIGHT / 8; // 18 tiles
+
+const VRAM_TRANSFER_SIZE: usize = 4 * 1024; // 4 KB
+const SYSTEM_PALETTES: usize = 512;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = SCREEN_TILES_WIDTH * SCREEN_TILES_HEIGHT / 4; // 2 bits per tile
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32; // 8x8 pixels at 4 bits per pixel
+const BORDER_MAP_WIDTH: usize = SGB_SCREEN_WIDTH / 8;
+const BORDER_MAP_HEIGHT: usize = SGB_SCREEN_HEIGHT / 8;
+const BORDER_PALETTES_OFFSET: usize = 0x800; // Where the border palettes start in PCT_TRN data.
+
+/// The number of frames to wait after a VRAM transfer command before capturing the screen, so
+/// the game has time to finish displaying the data.
+const VRAM_TRANSFER_DELAY: u8 = 2;
+
+/// The colors of palette 0 after the SGB BIOS finishes booting.
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// Data the SGB can copy out of the Game Boy screen.
+#[derive(Clone, Copy, Debug)]
+enum VramTransfer {
+    /// PAL_TRN: The 512 system palettes.
+    SystemPalettes,
+
+    /// CHR_TRN: Half of the 256 border tiles.
+    BorderTiles { upper_half: bool },
+
+    /// PCT_TRN: The border tile map and border palettes.
+    BorderMap,
+
+    /// ATTR_TRN: The 45 attribute files.
+    AttributeFiles,
+}
+
+/// How the Game Boy screen is masked, set by MASK_EN.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mask {
+    /// Show the Game Boy screen normally.
+    Cancel,
+
+    /// Keep showing the last frame.
+    Freeze,
+
+    /// Show a black screen.
+    Black,
+
+    /// Show a screen filled with color 0.
+    Color0,
+}
+
+/// The Super Game Boy, which colorizes the Game Boy screen and surrounds it with a border based on
+/// commands the game sends through the joypad register.
+#[derive(Clone)]
+pub struct Sgb {
+    /// The finished SGB picture, including the border, as 15-bit BGR colors.
+    pub screen_buffer: Box<[[u16; SGB_SCREEN_WIDTH]; SGB_SCREEN_HEIGHT]>,
+
+    /// The last colorized Game Boy screen.
+    game_screen: Box<[[u16; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
+
+    /// The packets received so far of a command which spans multiple packets.
+    command: Vec<u8>,
+
+    /// The four palettes used to colorize the Game Boy screen. Color 0 is shared by all of them.
+    palettes: [[u16; 4]; 4],
+
+    /// Which palette is used for each tile of the Game Boy screen.
+    attribute_map: [[u8; SCREEN_TILES_WIDTH]; SCREEN_TILES_HEIGHT],
+
+    /// The palettes PAL_SET can pick from, loaded by PAL_TRN.
+    system_palettes: Box<[[u16; 4]; SYSTEM_PALETTES]>,
+
+    /// The attribute maps ATTR_SET can pick from, loaded by ATTR_TRN. Each holds 2 bits per tile,
+    /// with the first tile in the most significant bits.
+    attribute_files: Box<[[u8; ATTRIBUTE_FILE_SIZE]; ATTRIBUTE_FILES]>,
+
+    /// Border tile data in the SNES 4 bits per pixel format.
+    border_tiles: Box<[u8; BORDER_TILES * BORDER_TILE_SIZE]>,
+
+    /// The border tile map. Bits 0-7 hold the tile number, bits 10-12 the palette, bit 14 is X
+    /// flip and bit 15 is Y flip.
+    border_map: Box<[u16; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT]>,
+
+    /// The border palettes, which the border tile map refers to as palettes 4-7.
+    border_palettes: [[u16; 16]; 4],
+
+    /// How the Game Boy screen is masked.
+    mask: Mask,
+
+    /// A VRAM transfer waiting to be captured, with the number of frames left to wait.
+    pending_transfer: Option<(VramTransfer, u8)>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            screen_buffer: Box::new([[DEFAULT_PALETTE[0]; SGB_SCREEN_WIDTH]; SGB_SCREEN_HEIGHT]),
+            game_screen: Box::new([[DEFAULT_PALETTE[0]; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            attribute_map: [[0; SCREEN_TILES_WIDTH]; SCREEN_TILES_HEIGHT],
+            system_palettes: Box::new([[0; 4]; SYSTEM_PALETTES]),
+            attribute_files: Box::new([[0; ATTRIBUTE_FILE_SIZE]; ATTRIBUTE_FILES]),
+            border_tiles: Box::new([0; BORDER_TILES * BORDER_TILE_SIZE]),
+            border_map: Box::new([0; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT]),
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::Cancel,
+            pending_transfer: None,
+        }
+    }
+
+    /// Handle a packet received through the joypad register. The low 3 bits of a command's first
+    /// byte hold the number of packets in the command.
+    pub fn receive_packet(&mut self, packet: [u8; SGB_PACKET_SIZE], joypad: &mut Joypad) {
+        self.command.extend_from_slice(&packet);
+        let packets = ((self.command[0] & 0b111) as usize).max(1);
+        if self.command.len() >= packets * SGB_PACKET_SIZE {
+            let command = std::mem::replace(&mut self.command, Vec::new());
+            self.execute(&command, joypad);
+        }
+    }
+
+    fn execute(&mut self, command: &[u8], joypad: &mut Joypad) {
+        match command[0] >> 3 {
+            // PAL01, PAL23, PAL03, PAL12: Set the colors of two palettes.
+            0x00 => self.set_palette_pair(0, 1, command),
+            0x01 => self.set_palette_pair(2, 3, command),
+            0x02 => self.set_palette_pair(0, 3, command),
+            0x03 => self.set_palette_pair(1, 2, command),
+
+            // ATTR_BLK, ATTR_LIN, ATTR_DIV, ATTR_CHR: Assign palettes to parts of the screen.
+            0x04 => self.attr_blk(command),
+            0x05 => self.attr_lin(command),
+            0x06 => self.attr_div(command),
+            0x07 => self.attr_chr(command),
+
+            // PAL_SET: Pick palettes from the system palettes.
+            0x0A => self.pal_set(command),
+
+            // PAL_TRN: Transfer the system palettes.
+            0x0B => self.start_vram_transfer(VramTransfer::SystemPalettes),
+
+            // MLT_REQ: Enable multiplayer with 1, 2 or 4 players.
+            0x11 => {
+                let player_count = [1, 2, 1, 4][(command[1] & 0b11) as usize];
+                info!("SGB multiplayer with {} players", player_count);
+                joypad.set_player_count(player_count);
+            }
+
+            // CHR_TRN: Transfer half of the border tiles.
+            0x13 => {
+                let upper_half = command[1] & 1 == 1;
+                self.start_vram_transfer(VramTransfer::BorderTiles { upper_half });
+            }
+
+            // PCT_TRN: Transfer the border tile map and palettes.
+            0x14 => self.start_vram_transfer(VramTransfer::BorderMap),
+
+            // ATTR_TRN: Transfer the attribute files.
+            0x15 => self.start_vram_transfer(VramTransfer::AttributeFiles),
+
+            // ATTR_SET: Apply an attribute file.
+            0x16 => {
+                self.apply_attribute_file(command[1] & 0b0011_1111);
+                if command[1] & 0b0100_0000 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+
+            // MASK_EN: Mask the Game Boy screen.
+            0x17 => {
+                self.mask = match command[1] & 0b11 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+            }
+
+            cmd => debug!("Ignoring unsupported SGB command 0x{:02X}", cmd),
+        }
+    }
+
+    /// Set the colors of palettes `a` and `b`, and color 0 of every palette.
+    fn set_palette_pair(&mut self, a: usize, b: usize, command: &[u8]) {
+        let color_0 = read_color(command, 1);
+        for palette in &mut self.palettes {
+            palette[0] = color_0;
+        }
+        for i in 0..3 {
+            self.palettes[a][i + 1] = read_color(command, 3 + i * 2);
+            self.palettes[b][i + 1] = read_color(command, 9 + i * 2);
+        }
+    }
+
+    /// Assign palettes to the inside, border and outside of rectangles of tiles.
+    fn attr_blk(&mut self, command: &[u8]) {
+        let data_sets = (command[1] & 0b1_1111) as usize;
+        for data_set in command[2..].chunks_exact(6).take(data_sets) {
+            let control = data_set[0] & 0b111;
+            let inside_palette = data_set[1] & 0b11;
+            let mut border_palette = (data_set[1] >> 2) & 0b11;
+            let outside_palette = (data_set[1] >> 4) & 0b11;
+            let (x1, y1) = ((data_set[2] & 0b1_1111) as usize, (data_set[3] & 0b1_1111) as usize);
+            let (x2, y2) = ((data_set[4] & 0b1_1111) as usize, (data_set[5] & 0b1_1111) as usize);
+
+            // If only the inside or only the outside is changed, the border changes with it.
+            let (change_inside, mut change_border, change_outside) =
+                (control & 1 != 0, control & 2 != 0, control & 4 != 0);
+            if control == 0b001 {
+                change_border = true;
+                border_palette = inside_palette;
+            } else if control == 0b100 {
+                change_border = true;
+                border_palette = outside_palette;
+            }
+
+            for y in 0..SCREEN_TILES_HEIGHT {
+                for x in 0..SCREEN_TILES_WIDTH {
+                    let in_block = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = in_block && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let attribute = &mut self.attribute_map[y][x];
+                    if on_border {
+                        if change_border { *attribute = border_palette; }
+                    } else if in_block {
+                        if change_inside { *attribute = inside_palette; }
+                    } else if change_outside {
+                        *attribute = outside_palette;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Assign palettes to whole rows or columns of tiles.
+    fn attr_lin(&mut self, command: &[u8]) {
+        let data_sets = command[1] as usize;
+        for &data in command[2..].iter().take(data_sets) {
+            let line = (data & 0b1_1111) as usize;
+            let palette = (data >> 5) & 0b11;
+            if data >> 7 == 1 {
+                // Horizontal line
+                if line < SCREEN_TILES_HEIGHT {
+                    self.attribute_map[line] = [palette; SCREEN_TILES_WIDTH];
+                }
+            } else if line < SCREEN_TILES_WIDTH {
+                // Vertical line
+                for row in &mut self.attribute_map {
+                    row[line] = palette;
+                }
+            }
+        }
+    }
+
+    /// Divide the screen in two with a line of tiles, and assign palettes to each part.
+    fn attr_div(&mut self, command: &[u8]) {
+        let after_palette = command[1] & 0b11;
+        let before_palette = (command[1] >> 2) & 0b11;
+        let line_palette = (command[1] >> 4) & 0b11;
+        let horizontal = command[1] & 0b0100_0000 != 0;
+        let line = (command[2] & 0b1_1111) as usize;
+
+        for y in 0..SCREEN_TILES_HEIGHT {
+            for x in 0..SCREEN_TILES_WIDTH {
+                let pos = if horizontal { y } else { x };
+                self.attribute_map[y][x] = if pos < line {
+                    before_palette
+                } else if pos == line {
+                    line_palette
+                } else {
+                    after_palette
+                };
+            }
+        }
+    }
+
+    /// Assign palettes to a run of individual tiles.
+    fn attr_chr(&mut self, command: &[u8]) {
+        let mut x = (command[1] & 0b1_1111) as usize;
+        let mut y = (command[2] & 0b1_1111) as usize;
+        let count = u16::from_le_bytes([command[3], command[4]]) as usize;
+        let vertical = command[5] & 1 == 1;
+
+        for i in 0..count {
+            let byte_i = 6 + i / 4;
+            if byte_i >= command.len() || x >= SCREEN_TILES_WIDTH || y >= SCREEN_TILES_HEIGHT {
+                break;
+            }
+            self.attribute_map[y][x] = (command[byte_i] >> (6 - (i % 4) * 2)) & 0b11;
+
+            if vertical {
+                y += 1;
+                if y == SCREEN_TILES_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == SCREEN_TILES_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// Load all four palettes from the system palettes, and optionally apply an attribute file.
+    fn pal_set(&mut self, command: &[u8]) {
+        for i in 0..4 {
+            let palette_num = u16::from_le_bytes([command[1 + i * 2], command[2 + i * 2]]) & 0x1FF;
+            self.palettes[i] = self.system_palettes[palette_num as usize];
+        }
+        // Color 0 of the first palette is shared by all of them.
+        for i in 1..4 {
+            self.palettes[i][0] = self.palettes[0][0];
+        }
+
+        let flags = command[9];
+        if flags & 0b1000_0000 != 0 {
+            self.apply_attribute_file(flags & 0b0011_1111);
+        }
+        if flags & 0b0100_0000 != 0 {
+            self.mask = Mask::Cancel;
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file_num: u8) {
+        let file = match self.attribute_files.get(file_num as usize) {
+            Some(file) => file,
+            None => {
+                warn!("SGB attribute file {} out of range", file_num);
+                return;
+            }
+        };
+
+        for y in 0..SCREEN_TILES_HEIGHT {
+            for x in 0..SCREEN_TILES_WIDTH {
+                let i = y * SCREEN_TILES_WIDTH + x;
+                self.attribute_map[y][x] = (file[i / 4] >> (6 - (i % 4) * 2)) & 0b11;
+            }
+        }
+    }
+
+    fn start_vram_transfer(&mut self, transfer: VramTransfer) {
+        self.pending_transfer = Some((transfer, VRAM_TRANSFER_DELAY));
+    }
+
+    /// Called by the CPU when the Game Boy finishes drawing a frame, with its shades. Finishes any
+    /// pending VRAM transfer and updates `screen_buffer`.
+    pub fn frame_finished(&mut self, screen: &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]) {
+        match self.pending_transfer {
+            Some((transfer, 0)) => {
+                self.pending_transfer = None;
+                self.finish_vram_transfer(transfer, &capture_vram_transfer(screen));
+            }
+            Some((transfer, frames)) => self.pending_transfer = Some((transfer, frames - 1)),
+            None => {}
+        }
+
+        match self.mask {
+            Mask::Cancel => {
+                for y in 0..SCREEN_HEIGHT {
+                    for x in 0..SCREEN_WIDTH {
+                        let palette = self.attribute_map[y / 8][x / 8] as usize;
+                        self.game_screen[y][x] = self.palettes[palette][screen[y][x] as usize];
+                    }
+                }
+            }
+            Mask::Freeze => {}
+            Mask::Black => *self.game_screen = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            Mask::Color0 => *self.game_screen = [[self.palettes[0][0]; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        }
+
+        self.render();
+    }
+
+    fn finish_vram_transfer(&mut self, transfer: VramTransfer, data: &[u8; VRAM_TRANSFER_SIZE]) {
+        debug!("Finished SGB VRAM transfer {:?}", transfer);
+        match transfer {
+            VramTransfer::SystemPalettes => {
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = read_color(data, i * 8 + j * 2);
+                    }
+                }
+            }
+
+            VramTransfer::BorderTiles { upper_half } => {
+                let start = if upper_half { VRAM_TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + VRAM_TRANSFER_SIZE].copy_from_slice(data);
+            }
+
+            VramTransfer::BorderMap => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    for (j, color) in palette.iter_mut().enumerate() {
+                        *color = read_color(data, BORDER_PALETTES_OFFSET + i * 32 + j * 2);
+                    }
+                }
+            }
+
+            VramTransfer::AttributeFiles => {
+                for (i, file) in self.attribute_files.iter_mut().enumerate() {
+                    let start = i * ATTRIBUTE_FILE_SIZE;
+                    file.copy_from_slice(&data[start..start + ATTRIBUTE_FILE_SIZE]);
+                }
+            }
+        }
+    }
+
+    /// Draw the game screen and the border into `screen_buffer`. The game screen sits in front of
+    /// the backdrop color, and opaque border pixels are drawn over both.
+    fn render(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                let in_game_screen = (GAME_SCREEN_X..GAME_SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (GAME_SCREEN_Y..GAME_SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                let color = match self.border_pixel(x, y) {
+                    Some(color) => color,
+                    None if in_game_screen => self.game_screen[y - GAME_SCREEN_Y][x - GAME_SCREEN_X],
+                    None => backdrop,
+                };
+                self.screen_buffer[y][x] = color;
+            }
+        }
+    }
+
+    /// Get the color of the border at the given position, or None if it's transparent.
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * BORDER_MAP_WIDTH + x / 8];
+        let tile_num = (entry & 0xFF) as usize;
+        // Only palettes 4-7 are meant for the border.
+        let palette = ((entry >> 10) & 0b11) as usize;
+        let x_flip = (entry >> 14) & 1 == 1;
+        let y_flip = (entry >> 15) & 1 == 1;
+
+        let row = if y_flip { 7 - y % 8 } else { y % 8 };
+        let bit = if x_flip { x % 8 } else { 7 - x % 8 };
+
+        // Each row has bitplanes 0 and 1 interleaved in the first 16 bytes of the tile, and
+        // bitplanes 2 and 3 in the last 16 bytes.
+        let tile = &self.border_tiles[tile_num * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let planes = [tile[row * 2], tile[row * 2 + 1], tile[16 + row * 2], tile[16 + row * 2 + 1]];
+        let color_num = planes.iter()
+            .enumerate()
+            .fold(0, |acc, (i, plane)| acc | ((plane >> bit) & 1) << i);
+
+        if color_num == 0 {
+            None
+        } else {
+            Some(self.border_palettes[palette][color_num as usize])
+        }
+    }
+}
+
+/// Read a little-endian 15-bit color.
+fn read_color(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([bytes[i], bytes[i + 1]]) & 0x7FFF
+}
+
+/// Re-encode the first 256 tiles of the screen, from left to right and top to bottom, as Game Boy
+/// tile data. This is how the SGB receives bulk data from the Game Boy.
+fn capture_vram_transfer(screen: &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]) -> [u8; VRAM_TRANSFER_SIZE] {
+    let mut data = [0; VRAM_TRANSFER_SIZE];
+    for (tile_num, tile) in data.chunks_exact_mut(16).enumerate() {
+        let tile_x = (tile_num % SCREEN_TILES_WIDTH) * 8;
+        let tile_y = (tile_num / SCREEN_TILES_WIDTH) * 8;
+        for row in 0..8 {
+            for col in 0..8 {
+                let shade = screen[tile_y + row][tile_x + col];
+                tile[row * 2] |= (shade & 1) << (7 - col);
+                tile[row * 2 + 1] |= ((shade >> 1) & 1) << (7 - col);
+            }
+        }
+    }
+    data
+}
@@ -0,0 +1,150 @@
+use super::*;
+
+/// Build a single-packet command with the given command number and data.
+fn packet(command: u8, data: &[u8]) -> [u8; SGB_PACKET_SIZE] {
+    let mut packet = [0; SGB_PACKET_SIZE];
+    packet[0] = command << 3 | 1;
+    packet[1..=data.len()].copy_from_slice(data);
+    packet
+}
+
+fn sgb_joypad() -> Joypad {
+    let mut joypad = Joypad::new();
+    joypad.enable_sgb();
+    joypad
+}
+
+/// Bit-bang a packet through the joypad register the way the SGB BIOS protocol expects.
+fn send_packet(joypad: &mut Joypad, packet: &[u8; SGB_PACKET_SIZE]) {
+    // Reset pulse: both select bits low.
+    joypad.write_reg(0x00);
+    joypad.write_reg(0x30);
+    for i in 0..SGB_PACKET_SIZE * 8 {
+        let bit = (packet[i / 8] >> (i % 8)) & 1;
+        // P15 low sends a 1, P14 low sends a 0.
+        joypad.write_reg(if bit == 1 { 0x10 } else { 0x20 });
+        joypad.write_reg(0x30);
+    }
+    // Stop bit.
+    joypad.write_reg(0x20);
+    joypad.write_reg(0x30);
+}
+
+#[test]
+fn test_receive_sgb_bit() {
+    let mut joypad = sgb_joypad();
+    let sent: [u8; SGB_PACKET_SIZE] = [
+        0x01, 0x80, 0xFF, 0x00, 0x5A, 0xA5, 0x12, 0x34,
+        0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0, 0x0F, 0x7E,
+    ];
+    send_packet(&mut joypad, &sent);
+    assert_eq!(joypad.take_sgb_packet(), Some(sent));
+    assert_eq!(joypad.take_sgb_packet(), None);
+
+    // Without the stop bit the packet isn't finished.
+    joypad.write_reg(0x00);
+    joypad.write_reg(0x30);
+    for _ in 0..SGB_PACKET_SIZE * 8 {
+        joypad.write_reg(0x20);
+        joypad.write_reg(0x30);
+    }
+    assert_eq!(joypad.take_sgb_packet(), None);
+
+    // A 1 in place of the stop bit drops the packet.
+    joypad.write_reg(0x10);
+    joypad.write_reg(0x30);
+    joypad.write_reg(0x20);
+    joypad.write_reg(0x30);
+    assert_eq!(joypad.take_sgb_packet(), None);
+
+    // Packets are ignored without SGB support.
+    let mut joypad = Joypad::new();
+    send_packet(&mut joypad, &sent);
+    assert_eq!(joypad.take_sgb_packet(), None);
+}
+
+#[test]
+fn test_pal01() {
+    let mut sgb = Sgb::new();
+    let mut joypad = sgb_joypad();
+    let data = [
+        0x11, 0x01, // Color 0
+        0x22, 0x02, 0x33, 0x03, 0x44, 0x04, // Palette 0, colors 1-3
+        0x55, 0x05, 0x66, 0x06, 0xFF, 0xFF, // Palette 1, colors 1-3
+    ];
+    send_packet(&mut joypad, &packet(0x00, &data));
+    sgb.receive_packet(joypad.take_sgb_packet().unwrap(), &mut joypad);
+
+    assert_eq!(sgb.palettes[0], [0x0111, 0x0222, 0x0333, 0x0444]);
+    // The top bit of each color is ignored.
+    assert_eq!(sgb.palettes[1], [0x0111, 0x0555, 0x0666, 0x7FFF]);
+    // Color 0 is shared by every palette, but the other colors are untouched.
+    assert_eq!(sgb.palettes[2], [0x0111, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+    assert_eq!(sgb.palettes[3], [0x0111, DEFAULT_PALETTE[1], DEFAULT_PALETTE[2], DEFAULT_PALETTE[3]]);
+}
+
+#[test]
+fn test_attr_blk() {
+    let mut sgb = Sgb::new();
+    let mut joypad = sgb_joypad();
+
+    // Inside palette 1, border palette 2, outside palette 3, for tiles (2, 3) to (6, 8).
+    sgb.receive_packet(packet(0x04, &[1, 0b111, 0b11_10_01, 2, 3, 6, 8]), &mut joypad);
+    for y in 0..SCREEN_TILES_HEIGHT {
+        for x in 0..SCREEN_TILES_WIDTH {
+            let in_block = (2..=6).contains(&x) && (3..=8).contains(&y);
+            let on_border = in_block && (x == 2 || x == 6 || y == 3 || y == 8);
+            let expected = if on_border { 2 } else if in_block { 1 } else { 3 };
+            assert_eq!(sgb.attribute_map[y][x], expected, "tile ({}, {})", x, y);
+        }
+    }
+
+    // Changing only the inside also changes the border, and leaves the outside alone.
+    sgb.receive_packet(packet(0x04, &[1, 0b001, 0b00_00_00, 2, 3, 6, 8]), &mut joypad);
+    assert_eq!(sgb.attribute_map[3][2], 0);
+    assert_eq!(sgb.attribute_map[5][4], 0);
+    assert_eq!(sgb.attribute_map[0][0], 3);
+
+    // Changing only the outside also changes the border, and leaves the inside alone.
+    sgb.receive_packet(packet(0x04, &[1, 0b100, 0b01_00_10, 3, 4, 5, 7]), &mut joypad);
+    assert_eq!(sgb.attribute_map[4][3], 1);
+    assert_eq!(sgb.attribute_map[5][4], 0);
+    assert_eq!(sgb.attribute_map[0][0], 1);
+}
+
+#[test]
+fn test_mlt_req() {
+    let mut sgb = Sgb::new();
+    let mut joypad = sgb_joypad();
+
+    // Select and release the button keys, then read the player ID in the low bits.
+    let next_id = |joypad: &mut Joypad| {
+        joypad.write_reg(0x10);
+        joypad.write_reg(0x30);
+        joypad.read_reg() & 0xF
+    };
+
+    // With one player the low bits read as no keys pressed.
+    assert_eq!(next_id(&mut joypad), 0xF);
+
+    send_packet(&mut joypad, &packet(0x11, &[1]));
+    sgb.receive_packet(joypad.take_sgb_packet().unwrap(), &mut joypad);
+    assert_eq!(joypad.read_reg() & 0xF, 0xF);
+    let ids: Vec<u8> = (0..4).map(|_| next_id(&mut joypad)).collect();
+    assert_eq!(ids, [0xE, 0xF, 0xE, 0xF]);
+
+    send_packet(&mut joypad, &packet(0x11, &[3]));
+    sgb.receive_packet(joypad.take_sgb_packet().unwrap(), &mut joypad);
+    let ids: Vec<u8> = (0..5).map(|_| next_id(&mut joypad)).collect();
+    assert_eq!(ids, [0xE, 0xD, 0xC, 0xF, 0xE]);
+
+    // Selecting the direction keys doesn't switch players.
+    joypad.write_reg(0x20);
+    joypad.write_reg(0x30);
+    assert_eq!(joypad.read_reg() & 0xF, 0xE);
+
+    send_packet(&mut joypad, &packet(0x11, &[0]));
+    sgb.receive_packet(joypad.take_sgb_packet().unwrap(), &mut joypad);
+    assert_eq!(next_id(&mut joypad), 0xF);
+    assert_eq!(next_id(&mut joypad), 0xF);
+}
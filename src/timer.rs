@@ -1,7 +1,16 @@
 use crate::interrupts::Interrupt;
+use crate::model::Model;
 use enumflags2::BitFlags;
 
-const CYCLES_PER_DIVIDE_INC: usize = 256; // The number of cycles between increments of the divide register
+#[cfg(test)]
+mod test;
+
+const CYCLES_PER_TICK: usize = 4; // The internal divider counts up by 4 every machine cycle.
+
+/// The bit of the internal divider which clocks the APU frame sequencer when it falls, giving
+/// 512Hz. In double speed mode the divider counts twice as fast, so the next bit up is used.
+const FRAME_SEQUENCER_BIT: u16 = 12;
+const FRAME_SEQUENCER_BIT_DOUBLE_SPEED: u16 = 13;
 
 #[derive(Clone, Copy)]
 enum CounterSpeed {
@@ -11,13 +20,14 @@ enum CounterSpeed {
     S16384 = 3,
 }
 
-impl std::convert::From<CounterSpeed> for usize {
-    fn from(value: CounterSpeed) -> usize {
-        match value {
-            CounterSpeed::S4096 => 1024,
-            CounterSpeed::S262144 => 16,
-            CounterSpeed::S65536 => 64,
-            CounterSpeed::S16384 => 256,
+impl CounterSpeed {
+    /// The bit of the internal divider which increments the counter when it falls from 1 to 0.
+    fn divider_bit(self) -> u16 {
+        match self {
+            CounterSpeed::S4096 => 9,
+            CounterSpeed::S262144 => 3,
+            CounterSpeed::S65536 => 5,
+            CounterSpeed::S16384 => 7,
         }
     }
 }
@@ -36,18 +46,13 @@ impl std::convert::From<u8> for CounterSpeed {
 
 #[derive(Clone)]
 pub struct Timer {
-    /// The divider `DIV` register 0xFF04
-    divider: u8,
-
-    /// The divider register cycle counter
-    div_cycle_counter: usize,
+    /// The 16-bit internal divider, which counts cycles. The divider `DIV` register 0xFF04 is its
+    /// upper 8 bits.
+    divider: u16,
 
     /// The timer counter `TIMA` register 0xFF05
     counter: u8,
 
-    ///  The timer counter register cycle counter
-    counter_cycle_counter: usize,
-
     /// The timer modulo `TMA` register 0xFF06
     modulo: u8,
 
@@ -56,55 +61,95 @@ pub struct Timer {
 
     /// The timer control `TAC` register 0xFF07 bits 0-1
     counter_speed: CounterSpeed,
+
+    /// True if the CGB is in double speed mode.
+    double_speed: bool,
+
+    /// Number of times the frame sequencer bit has fallen since the last call to
+    /// `take_frame_sequencer_clocks`.
+    frame_sequencer_clocks: usize,
 }
 
 impl Timer {
+    /// The timer at power on, before the boot ROM runs.
     pub fn new() -> Timer {
+        Timer::with_divider(0)
+    }
+
+    /// The timer when the given model's boot ROM jumps to the cartridge. The divider has been
+    /// counting the whole time the boot ROM ran.
+    pub fn post_boot(model: Model) -> Timer {
+        Timer::with_divider(match model {
+            Model::Dmg0 => 0x182C,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            // The SGB boot ROM's run time depends on how long sending the header packets takes,
+            // and the CGB's on the cartridge header, so these are typical values.
+            Model::Sgb => 0xD85C,
+            Model::Cgb => 0x1EA0,
+        })
+    }
+
+    fn with_divider(divider: u16) -> Timer {
         Timer {
-            divider: 0xAC, // Arbitrary value which passes the Mooneye on-boot values test.
-            div_cycle_counter: 0,
+            divider,
             counter: 0,
-            counter_cycle_counter: 0,
             modulo: 0,
             counter_running: false,
             counter_speed: CounterSpeed::S4096,
+            double_speed: false,
+            frame_sequencer_clocks: 0,
         }
     }
 
     pub fn step(&mut self, cycles: usize) -> BitFlags<Interrupt> {
-        self.update_divider(cycles);
-        self.update_counter(cycles)
+        let mut interrupts = BitFlags::empty();
+        for _ in 0..cycles / CYCLES_PER_TICK {
+            let new_divider = self.divider.wrapping_add(CYCLES_PER_TICK as u16);
+            interrupts |= self.set_divider(new_divider);
+        }
+        interrupts
     }
 
-    fn update_divider(&mut self, cycles: usize) {
-        self.div_cycle_counter += cycles;
-        if self.div_cycle_counter >= CYCLES_PER_DIVIDE_INC {
-            self.div_cycle_counter %= CYCLES_PER_DIVIDE_INC;
-            self.divider = self.divider.wrapping_add(1);
-        }
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
     }
 
-    fn update_counter(&mut self, cycles: usize) -> BitFlags<Interrupt> {
-        if !self.counter_running {
-            return BitFlags::empty();
+    /// Returns the number of times the APU frame sequencer should be clocked since the last call.
+    pub fn take_frame_sequencer_clocks(&mut self) -> usize {
+        std::mem::replace(&mut self.frame_sequencer_clocks, 0)
+    }
+
+    /// Update the internal divider, incrementing the counter if its selected divider bit falls.
+    fn set_divider(&mut self, new_divider: u16) -> BitFlags<Interrupt> {
+        let falling_edge = |bit: u16| (self.divider >> bit) & 1 == 1 && (new_divider >> bit) & 1 == 0;
+        let counter_edge = falling_edge(self.counter_speed.divider_bit());
+        let frame_sequencer_bit =
+            if self.double_speed { FRAME_SEQUENCER_BIT_DOUBLE_SPEED } else { FRAME_SEQUENCER_BIT };
+        if falling_edge(frame_sequencer_bit) {
+            self.frame_sequencer_clocks += 1;
         }
-        self.counter_cycle_counter += cycles;
-        if self.counter_cycle_counter >= usize::from(self.counter_speed) {
-            self.counter_cycle_counter %= usize::from(self.counter_speed);
-            let (new_counter, overflow) = self.counter.overflowing_add(1);
-            if overflow {
-                self.counter = self.modulo;
-                return BitFlags::from(Interrupt::Timer);
-            } else {
-                self.counter = new_counter;
-            }
+        self.divider = new_divider;
+        if self.counter_running && counter_edge {
+            self.increment_counter()
+        } else {
+            BitFlags::empty()
+        }
+    }
+
+    fn increment_counter(&mut self) -> BitFlags<Interrupt> {
+        let (new_counter, overflow) = self.counter.overflowing_add(1);
+        if overflow {
+            self.counter = self.modulo;
+            BitFlags::from(Interrupt::Timer)
+        } else {
+            self.counter = new_counter;
+            BitFlags::empty()
         }
-        BitFlags::empty()
     }
 
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x04 => self.divider,
+            0x04 => (self.divider >> 8) as u8,
             0x05 => self.counter,
             0x06 => self.modulo,
             // The upper 5 bits are unused and always 1.
@@ -113,12 +158,11 @@ impl Timer {
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    /// Write a timer register. Returns any interrupts caused by the write, since resetting the
+    /// divider can increment the counter.
+    pub fn write_reg(&mut self, addr: u8, val: u8) -> BitFlags<Interrupt> {
         match addr {
-            0x04 => {
-                self.div_cycle_counter = 0;
-                self.divider = 0
-            },
+            0x04 => return self.set_divider(0),
             0x05 => self.counter = val,
             0x06 => self.modulo = val,
             0x07 => {
@@ -127,5 +171,6 @@ impl Timer {
             },
             _ => panic!("Invalid write address for timer")
         }
+        BitFlags::empty()
     }
 }
\ No newline at end of file
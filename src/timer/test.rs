@@ -0,0 +1,94 @@
+use super::*;
+
+/// A timer counting at 262144Hz, which increments when bit 3 of the divider falls.
+fn fast_timer() -> Timer {
+    let mut timer = Timer::new();
+    timer.write_reg(0x07, 0b101);
+    timer
+}
+
+#[test]
+fn test_counter_speed() {
+    let mut timer = fast_timer();
+    timer.step(12);
+    assert_eq!(timer.read_reg(0x05), 0);
+    timer.step(4);
+    assert_eq!(timer.read_reg(0x05), 1);
+    timer.step(16 * 10);
+    assert_eq!(timer.read_reg(0x05), 11);
+
+    // DIV is the upper byte of the divider.
+    assert_eq!(timer.read_reg(0x04), 0);
+    timer.step(256 - 176);
+    assert_eq!(timer.read_reg(0x04), 1);
+
+    // A stopped counter doesn't increment.
+    timer.write_reg(0x07, 0b001);
+    timer.step(64);
+    assert_eq!(timer.read_reg(0x05), 16);
+}
+
+#[test]
+fn test_counter_overflow() {
+    let mut timer = fast_timer();
+    timer.write_reg(0x05, 0xFF);
+    timer.write_reg(0x06, 0xAB);
+    assert!(timer.step(12).is_empty());
+    assert_eq!(timer.step(4), BitFlags::from(Interrupt::Timer));
+    // The counter is reloaded from the modulo.
+    assert_eq!(timer.read_reg(0x05), 0xAB);
+}
+
+#[test]
+fn test_divider_reset_glitch() {
+    // Resetting the divider while the selected bit is 1 makes it fall, incrementing the counter.
+    let mut timer = fast_timer();
+    timer.step(8);
+    assert_eq!(timer.read_reg(0x05), 0);
+    assert!(timer.write_reg(0x04, 0).is_empty());
+    assert_eq!(timer.read_reg(0x05), 1);
+
+    // The counter then takes a full period to increment again.
+    timer.step(12);
+    assert_eq!(timer.read_reg(0x05), 1);
+    timer.step(4);
+    assert_eq!(timer.read_reg(0x05), 2);
+
+    // While the bit is 0 the reset doesn't increment it.
+    timer.step(4);
+    timer.write_reg(0x04, 0);
+    assert_eq!(timer.read_reg(0x05), 2);
+
+    // The glitch can overflow the counter too.
+    timer.write_reg(0x05, 0xFF);
+    timer.step(8);
+    assert_eq!(timer.write_reg(0x04, 0), BitFlags::from(Interrupt::Timer));
+    assert_eq!(timer.read_reg(0x05), 0);
+
+    // The glitch doesn't happen with the counter stopped.
+    timer.write_reg(0x07, 0b001);
+    timer.step(8);
+    timer.write_reg(0x04, 0);
+    assert_eq!(timer.read_reg(0x05), 0);
+}
+
+#[test]
+fn test_divider_reset_slow_speed() {
+    // At 4096Hz the counter follows bit 9, so it only glitches in the second half of its period.
+    let mut timer = Timer::new();
+    timer.write_reg(0x07, 0b100);
+    timer.step(0x1FC);
+    timer.write_reg(0x04, 0);
+    assert_eq!(timer.read_reg(0x05), 0);
+    timer.step(0x200);
+    timer.write_reg(0x04, 0);
+    assert_eq!(timer.read_reg(0x05), 1);
+}
+
+#[test]
+fn test_post_boot_divider() {
+    assert_eq!(Timer::post_boot(Model::Dmg).read_reg(0x04), 0xAB);
+    assert_eq!(Timer::post_boot(Model::Mgb).read_reg(0x04), 0xAB);
+    assert_eq!(Timer::post_boot(Model::Dmg0).read_reg(0x04), 0x18);
+    assert_eq!(Timer::post_boot(Model::Cgb).read_reg(0x04), 0x1E);
+}
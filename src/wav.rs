@@ -0,0 +1,32 @@
+use std::io::{self, Write};
+
+#[cfg(test)]
+mod test;
+
+/// Write 16-bit stereo samples, interleaved left and right, as a WAV file.
+pub fn write_wav<W: Write>(mut out: W, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let data_size = (samples.len() * BYTES_PER_SAMPLE as usize) as u32;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?; // Format chunk size
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?; // Bytes per second
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?; // Bits per sample
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    out.flush()
+}
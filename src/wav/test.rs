@@ -0,0 +1,20 @@
+use super::*;
+
+#[test]
+fn test_write_wav() {
+    let mut out = Vec::new();
+    write_wav(&mut out, 48000, &[1, -1, 0x1234, -0x8000]).unwrap();
+
+    assert_eq!(out.len(), 44 + 8);
+    assert_eq!(&out[0..4], b"RIFF");
+    assert_eq!(&out[4..8], &44u32.to_le_bytes());
+    assert_eq!(&out[8..16], b"WAVEfmt ");
+    assert_eq!(&out[16..20], &16u32.to_le_bytes());
+    assert_eq!(&out[20..24], &[1, 0, 2, 0]); // PCM, stereo
+    assert_eq!(&out[24..28], &48000u32.to_le_bytes());
+    assert_eq!(&out[28..32], &(48000u32 * 4).to_le_bytes());
+    assert_eq!(&out[32..36], &[4, 0, 16, 0]); // Block align, bits per sample
+    assert_eq!(&out[36..40], b"data");
+    assert_eq!(&out[40..44], &8u32.to_le_bytes());
+    assert_eq!(&out[44..], &[0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12, 0x00, 0x80]);
+}